@@ -0,0 +1,1045 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+#![allow(unused_variables)]
+use crate::audio::{AudioSink, Resampler, CPU_CLOCK_NTSC, DEFAULT_SAMPLE_RATE};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+//: Overview Comment {{{
+/* The APU is the sound half of the 2A03. It has five channels:
+ * two pulse (square) waves, a triangle wave, a noise generator and the DMC which plays
+ * back 1-bit delta encoded samples read straight out of cpu memory.
+ *
+ * Each channel is built from a few small units: a timer that divides the cpu clock down to
+ * the channel's frequency, a sequencer that steps through the wave shape, and some
+ * combination of length counter, envelope, sweep and linear counter to shape the sound.
+ *
+ * The frame counter (sequencer) clocks the envelopes/linear counter (quarter frames) and
+ * length counters/sweeps (half frames) at roughly 240Hz, and can raise an IRQ in 4-step mode.
+ *
+ * All five channel outputs go through a non-linear mixer to produce the final sample.
+ * */
+//: }}}
+
+//: Tables {{{
+// Length counter load values, indexed by the top 5 bits of the length register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Pulse wave shapes: 12.5%, 25%, 50%, 25% negated
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Triangle wave shape
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15,
+];
+
+// Noise timer periods (NTSC, in cpu cycles)
+const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// DMC timer periods (NTSC, in cpu cycles)
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// Frame counter step timings (in cpu cycles)
+const FRAME_STEP_ONE: u32 = 7457;
+const FRAME_STEP_TWO: u32 = 14913;
+const FRAME_STEP_THREE: u32 = 22371;
+const FRAME_STEP_FOUR: u32 = 29829;
+const FRAME_STEP_FIVE: u32 = 37281;
+//: }}}
+
+//: Envelope {{{
+// Volume control shared by the pulse and noise channels
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    loop_flag: bool, // Also the length counter halt flag
+    constant_volume: bool,
+    volume: u8, // Constant volume, or the divider period
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.loop_flag = value & 0x20 != 0;
+        self.constant_volume = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    // Quarter frame clock
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.loop_flag {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+//: }}}
+
+//: LengthCounter {{{
+// Silences a channel after a set number of half frames
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool, // From $4015
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    // Half frame clock
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.value > 0
+    }
+}
+//: }}}
+
+//: Pulse {{{
+#[derive(Default)]
+struct Pulse {
+    // Pulse one negates with ones' complement, pulse two with two's complement
+    channel_one: bool,
+
+    duty: u8,
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+
+    envelope: Envelope,
+    length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(channel_one: bool) -> Self {
+        Self {
+            channel_one,
+            ..Default::default()
+        }
+    }
+
+    // $4000 / $4004
+    fn write_control(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length.halt = value & 0x20 != 0;
+        self.envelope.write(value);
+    }
+
+    // $4001 / $4005
+    fn write_sweep(&mut self, value: u8) {
+        self.sweep_enabled = value & 0x80 != 0;
+        self.sweep_period = (value >> 4) & 0x07;
+        self.sweep_negate = value & 0x08 != 0;
+        self.sweep_shift = value & 0x07;
+        self.sweep_reload = true;
+    }
+
+    // $4002 / $4006
+    fn write_timer_low(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    // $4003 / $4007
+    fn write_timer_high(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00FF) | (((value & 0x07) as u16) << 8);
+        self.length.load(value >> 3);
+        self.sequence = 0;
+        self.envelope.start = true;
+    }
+
+    // Period the sweep unit is moving towards
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let ones_complement = if self.channel_one { 1 } else { 0 };
+            self.timer_period.saturating_sub(change + ones_complement)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // The sweep unit mutes the channel if the period is out of range, even when disabled
+    fn sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // Clocked every apu cycle (every other cpu cycle)
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Half frame clock
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.sweep_muted()
+        {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0
+            || !self.length.active()
+            || self.sweep_muted()
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+//: }}}
+
+//: Triangle {{{
+#[derive(Default)]
+struct Triangle {
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+
+    length: LengthCounter,
+
+    linear_control: bool, // Also the length counter halt flag
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    // $4008
+    fn write_linear(&mut self, value: u8) {
+        self.linear_control = value & 0x80 != 0;
+        self.length.halt = self.linear_control;
+        self.linear_reload_value = value & 0x7F;
+    }
+
+    // $400A
+    fn write_timer_low(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    // $400B
+    fn write_timer_high(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00FF) | (((value & 0x07) as u16) << 8);
+        self.length.load(value >> 3);
+        self.linear_reload = true;
+    }
+
+    // Clocked every cpu cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.sequence = (self.sequence + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Quarter frame clock
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.linear_control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence as usize]
+    }
+}
+//: }}}
+
+//: Noise {{{
+struct Noise {
+    mode: bool, // Short (93 step) mode
+    timer_period: u16,
+    timer: u16,
+    shift: u16, // 15 bit linear feedback shift register
+
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            mode: false,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    // $400C
+    fn write_control(&mut self, value: u8) {
+        self.length.halt = value & 0x20 != 0;
+        self.envelope.write(value);
+    }
+
+    // $400E
+    fn write_period(&mut self, value: u8) {
+        self.mode = value & 0x80 != 0;
+        self.timer_period = NOISE_PERIOD_TABLE[(value & 0x0F) as usize];
+    }
+
+    // $400F
+    fn write_length(&mut self, value: u8) {
+        self.length.load(value >> 3);
+        self.envelope.start = true;
+    }
+
+    // Clocked every cpu cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            // Feedback from bit 6 in short mode, bit 1 otherwise
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift & 0x01) ^ ((self.shift >> tap) & 0x01);
+            self.shift >>= 1;
+            self.shift |= feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.shift & 0x01 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+//: }}}
+
+//: Dmc {{{
+struct Dmc {
+    irq_enable: bool,
+    irq_pending: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer: u16,
+
+    output_level: u8, // 7 bit
+
+    // Sample location as set by $4012/$4013
+    sample_addr: u16,
+    sample_length: u16,
+    // Playback position
+    current_addr: u16,
+    bytes_remaining: u16,
+
+    // Memory reader fills this, output unit empties it
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Self {
+            irq_enable: false,
+            irq_pending: false,
+            loop_flag: false,
+            timer_period: DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    // $4010
+    fn write_control(&mut self, value: u8) {
+        self.irq_enable = value & 0x80 != 0;
+        if !self.irq_enable {
+            self.irq_pending = false;
+        }
+        self.loop_flag = value & 0x40 != 0;
+        self.timer_period = DMC_RATE_TABLE[(value & 0x0F) as usize];
+    }
+
+    // $4011
+    fn write_output(&mut self, value: u8) {
+        self.output_level = value & 0x7F;
+    }
+
+    // $4012
+    fn write_addr(&mut self, value: u8) {
+        self.sample_addr = 0xC000 | ((value as u16) << 6);
+    }
+
+    // $4013
+    fn write_length(&mut self, value: u8) {
+        self.sample_length = ((value as u16) << 4) | 1;
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Address the memory reader wants to fetch, if the buffer needs filling
+    fn fetch_addr(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    // Memory reader gets its byte
+    fn fill_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+
+        // Address wraps around to $8000
+        if self.current_addr == 0xFFFF {
+            self.current_addr = 0x8000;
+        } else {
+            self.current_addr += 1;
+        }
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enable {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    // Clocked every cpu cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            // Start a new output cycle
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+//: }}}
+
+//: Apu {{{
+pub struct Apu {
+    pulse_one: Pulse,
+    pulse_two: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    // Frame counter
+    // false: 4-step, true: 5-step
+    frame_five_step: bool,
+    frame_irq_inhibit: bool,
+    frame_irq_pending: bool,
+    // Cpu cycles into the current frame counter sequence
+    frame_cycle: u32,
+    // Writes to $4017 take effect after a 3 or 4 cycle delay
+    frame_reset_delay: u8,
+
+    // Total cpu cycles, used for even/odd timings
+    cycle: u64,
+
+    // Non-linear mixer lookup tables
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+
+    // Converts mixer output to the host's sample rate
+    resampler: Resampler,
+}
+//: }}}
+
+//: Apu Functions {{{
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        // Approximations of the mixer's resistor network from the nesdev wiki
+        let mut pulse_table = [0.0; 31];
+        for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        Self {
+            pulse_one: Pulse::new(true),
+            pulse_two: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+
+            frame_five_step: false,
+            frame_irq_inhibit: false,
+            frame_irq_pending: false,
+            frame_cycle: 0,
+            frame_reset_delay: 0,
+
+            cycle: 0,
+
+            pulse_table,
+            tnd_table,
+
+            resampler: Resampler::new(CPU_CLOCK_NTSC, DEFAULT_SAMPLE_RATE),
+        }
+    }
+
+    // Interface Functions
+    // Register writes ($4000-$4013, $4015, $4017)
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000 => self.pulse_one.write_control(value),
+            0x4001 => self.pulse_one.write_sweep(value),
+            0x4002 => self.pulse_one.write_timer_low(value),
+            0x4003 => self.pulse_one.write_timer_high(value),
+            0x4004 => self.pulse_two.write_control(value),
+            0x4005 => self.pulse_two.write_sweep(value),
+            0x4006 => self.pulse_two.write_timer_low(value),
+            0x4007 => self.pulse_two.write_timer_high(value),
+            0x4008 => self.triangle.write_linear(value),
+            0x400A => self.triangle.write_timer_low(value),
+            0x400B => self.triangle.write_timer_high(value),
+            0x400C => self.noise.write_control(value),
+            0x400E => self.noise.write_period(value),
+            0x400F => self.noise.write_length(value),
+            0x4010 => self.dmc.write_control(value),
+            0x4011 => self.dmc.write_output(value),
+            0x4012 => self.dmc.write_addr(value),
+            0x4013 => self.dmc.write_length(value),
+            0x4015 => self.write_status(value),
+            0x4017 => self.write_frame_counter(value),
+            _ => {} // $4009, $400D unused
+        }
+    }
+
+    // Status read ($4015)
+    // debug: read without clearing the frame interrupt flag
+    pub fn read_status(&mut self, debug: bool) -> u8 {
+        let mut status = 0;
+        if self.pulse_one.length.active() {
+            status |= 0x01;
+        }
+        if self.pulse_two.length.active() {
+            status |= 0x02;
+        }
+        if self.triangle.length.active() {
+            status |= 0x04;
+        }
+        if self.noise.length.active() {
+            status |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq_pending {
+            status |= 0x40;
+        }
+        if self.dmc.irq_pending {
+            status |= 0x80;
+        }
+
+        if !debug {
+            self.frame_irq_pending = false;
+        }
+        status
+    }
+
+    // Clock once per cpu cycle
+    pub fn clock(&mut self) {
+        // Triangle, noise and DMC run at cpu speed, the pulses at half that
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle % 2 == 1 {
+            self.pulse_one.clock_timer();
+            self.pulse_two.clock_timer();
+        }
+
+        self.clock_frame_counter();
+
+        self.resampler.push(self.output());
+
+        self.cycle += 1;
+    }
+
+    // Output sample rate for drain_samples, any buffered audio is dropped
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(CPU_CLOCK_NTSC, sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.output_rate()
+    }
+
+    // Take the resampled audio produced since the last call
+    pub fn drain_samples(&mut self, out: &mut Vec<f32>) {
+        self.resampler.drain_into(out);
+    }
+
+    // Send the resampled audio produced since the last call to a sink
+    pub fn drain_samples_to(&mut self, sink: &mut dyn AudioSink) -> std::io::Result<()> {
+        self.resampler.drain_to(sink)
+    }
+
+    // Address the DMC wants read, the bus should fetch it and pass it to dmc_fill_buffer
+    pub fn dmc_fetch_addr(&self) -> Option<u16> {
+        self.dmc.fetch_addr()
+    }
+
+    pub fn dmc_fill_buffer(&mut self, value: u8) {
+        self.dmc.fill_buffer(value);
+    }
+
+    // Frame counter or DMC interrupt request
+    pub fn irq(&self) -> bool {
+        self.frame_irq_pending || self.dmc.irq_pending
+    }
+
+    // Current mixed output, 0.0 to ~1.0
+    pub fn output(&self) -> f32 {
+        let pulse = self.pulse_one.output() + self.pulse_two.output();
+        let tnd = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+
+    // Internal Functions
+    fn write_status(&mut self, value: u8) {
+        self.pulse_one.length.set_enabled(value & 0x01 != 0);
+        self.pulse_two.length.set_enabled(value & 0x02 != 0);
+        self.triangle.length.set_enabled(value & 0x04 != 0);
+        self.noise.length.set_enabled(value & 0x08 != 0);
+
+        // Enabling the DMC restarts the sample only if it had finished
+        if value & 0x10 == 0 {
+            self.dmc.bytes_remaining = 0;
+        } else if self.dmc.bytes_remaining == 0 {
+            self.dmc.restart();
+        }
+        self.dmc.irq_pending = false;
+    }
+
+    fn write_frame_counter(&mut self, value: u8) {
+        self.frame_five_step = value & 0x80 != 0;
+        self.frame_irq_inhibit = value & 0x40 != 0;
+        if self.frame_irq_inhibit {
+            self.frame_irq_pending = false;
+        }
+
+        // Sequencer reset happens 3 cycles later on an even cycle, 4 on an odd one
+        self.frame_reset_delay = if self.cycle & 1 == 0 { 3 } else { 4 };
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                // 5-step mode clocks everything right away
+                if self.frame_five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+
+        match self.frame_cycle {
+            FRAME_STEP_ONE | FRAME_STEP_THREE => self.clock_quarter_frame(),
+            FRAME_STEP_TWO => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            _ => {}
+        }
+
+        if !self.frame_five_step {
+            // 4-step mode, interrupt flag is set over three cycles around the last step
+            if self.frame_cycle == FRAME_STEP_FOUR {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            if (FRAME_STEP_FOUR - 1..=FRAME_STEP_FOUR + 1).contains(&self.frame_cycle)
+                && !self.frame_irq_inhibit
+            {
+                self.frame_irq_pending = true;
+            }
+            if self.frame_cycle == FRAME_STEP_FOUR + 1 {
+                self.frame_cycle = 0;
+            }
+        } else {
+            if self.frame_cycle == FRAME_STEP_FIVE {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            if self.frame_cycle == FRAME_STEP_FIVE + 1 {
+                self.frame_cycle = 0;
+            }
+        }
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_one.envelope.clock();
+        self.pulse_two.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    // Length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse_one.length.clock();
+        self.pulse_two.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse_one.clock_sweep();
+        self.pulse_two.clock_sweep();
+    }
+}
+//: }}}
+
+//: Apu SaveState {{{
+impl SaveState for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.loop_flag);
+        w.write_bool(self.constant_volume);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.constant_volume = r.read_bool()?;
+        self.volume = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.value = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.sequence);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_bool(self.sweep_reload);
+        w.write_u8(self.sweep_divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.duty = r.read_u8()? & 0x03;
+        self.sequence = r.read_u8()? & 0x07;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()? & 0x07;
+        self.sweep_reload = r.read_bool()?;
+        self.sweep_divider = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sequence);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        self.length.save_state(w);
+        w.write_bool(self.linear_control);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sequence = r.read_u8()? & 0x1F;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.length.load_state(r)?;
+        self.linear_control = r.read_bool()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.mode);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.mode = r.read_bool()?;
+        self.timer_period = r.read_u16()?.max(1);
+        self.timer = r.read_u16()?;
+        self.shift = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)
+    }
+}
+
+impl SaveState for Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enable);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.loop_flag);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.output_level);
+        w.write_u16(self.sample_addr);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_addr);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enable = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.timer_period = r.read_u16()?.max(1);
+        self.timer = r.read_u16()?;
+        self.output_level = r.read_u8()? & 0x7F;
+        self.sample_addr = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_addr = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let has_sample = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?.max(1);
+        self.silence = r.read_bool()?;
+        Ok(())
+    }
+}
+
+// The resampler belongs to the host side and is not saved
+impl SaveState for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse_one.save_state(w);
+        self.pulse_two.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_bool(self.frame_five_step);
+        w.write_bool(self.frame_irq_inhibit);
+        w.write_bool(self.frame_irq_pending);
+        w.write_u32(self.frame_cycle);
+        w.write_u8(self.frame_reset_delay);
+        w.write_u64(self.cycle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_one.load_state(r)?;
+        self.pulse_two.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.frame_five_step = r.read_bool()?;
+        self.frame_irq_inhibit = r.read_bool()?;
+        self.frame_irq_pending = r.read_bool()?;
+        self.frame_cycle = r.read_u32()?;
+        self.frame_reset_delay = r.read_u8()?;
+        self.cycle = r.read_u64()?;
+        Ok(())
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cpu cycles between shifts of the noise register for a $400E period index
+    fn noise_step(index: u8) -> usize {
+        let mut apu = Apu::new();
+        apu.write(0x400E, index);
+        let mut last = apu.noise.shift;
+        let mut changes = Vec::new();
+        for cycle in 0..20000 {
+            apu.clock();
+            if apu.noise.shift != last {
+                last = apu.noise.shift;
+                changes.push(cycle);
+            }
+        }
+        changes[2] - changes[1]
+    }
+
+    #[test]
+    fn noise_period_in_cpu_cycles() {
+        for index in 0..16 {
+            assert_eq!(noise_step(index), NOISE_PERIOD_TABLE[index as usize] as usize);
+        }
+    }
+}
+//: }}}
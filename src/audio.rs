@@ -0,0 +1,488 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+#[cfg(feature = "live-audio")]
+use std::sync::{Arc, Mutex};
+
+// NTSC cpu clock, the APU produces one sample per cpu cycle
+pub const CPU_CLOCK_NTSC: u32 = 1_789_773;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+//: AudioSink {{{
+/// Somewhere for resampled audio to go (a sound device, a file, ...)
+pub trait AudioSink {
+    /// Sample rate the sink expects, in Hz
+    fn sample_rate(&self) -> u32;
+    /// Mono samples in the range -1.0 to 1.0
+    fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()>;
+}
+//: }}}
+
+//: RingBuffer {{{
+/// Fixed size sample queue between the emulator and the frontend.
+/// If the frontend falls behind the oldest samples are dropped.
+pub struct RingBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        self.samples.pop_front()
+    }
+
+    // Move every queued sample into out
+    pub fn drain_into(&mut self, out: &mut Vec<f32>) {
+        out.extend(self.samples.drain(..));
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+//: }}}
+
+//: Filter {{{
+// First order filter, the NES output stage is roughly two high passes and a low pass
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn high_pass(sample_rate: u32, cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Self {
+            high_pass: true,
+            alpha: rc / (rc + dt),
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn low_pass(sample_rate: u32, cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Self {
+            high_pass: false,
+            alpha: dt / (rc + dt),
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn apply(&mut self, sample: f32) -> f32 {
+        let out = if self.high_pass {
+            self.alpha * (self.prev_out + sample - self.prev_in)
+        } else {
+            self.prev_out + self.alpha * (sample - self.prev_out)
+        };
+        self.prev_in = sample;
+        self.prev_out = out;
+        out
+    }
+}
+//: }}}
+
+//: Resampler {{{
+// Band-limited step kernel, taps per step and sub-sample positions it is tabulated at
+const STEP_WIDTH: usize = 48;
+const STEP_PHASES: usize = 64;
+// Cutoff as a fraction of the output rate, the Blackman window's transition band ends just
+// under the output's Nyquist frequency
+const STEP_CUTOFF: f64 = 0.42;
+
+// Windowed sinc impulses, one row per phase, each row sums to 1 so a step keeps its height
+fn step_kernel() -> Vec<[f32; STEP_WIDTH]> {
+    (0..STEP_PHASES)
+        .map(|phase| {
+            let center = (STEP_WIDTH / 2) as f64 + phase as f64 / STEP_PHASES as f64;
+            let mut taps = [0.0f64; STEP_WIDTH];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f64 - center;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let t = std::f64::consts::PI * 2.0 * STEP_CUTOFF * x;
+                    t.sin() / t
+                };
+                // Blackman window over the kernel width
+                let w = (x + STEP_WIDTH as f64 / 2.0) / STEP_WIDTH as f64;
+                let w = 2.0 * std::f64::consts::PI * w;
+                *tap = sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos());
+            }
+            let sum: f64 = taps.iter().sum();
+            taps.map(|tap| (tap / sum) as f32)
+        })
+        .collect()
+}
+
+/// Converts the APU's per cpu cycle output down to a host sample rate.
+/// The APU output only ever jumps between flat levels, so instead of filtering every input
+/// sample each jump is added to the output as a band-limited step (like blip_buf): a windowed
+/// sinc impulse at the jump's sub-sample position, which the output then sums up. Nothing
+/// above the output's Nyquist frequency is left to alias. The result then goes through the
+/// NES's own output filters.
+/// Only integer phase math is used so the output is deterministic.
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+
+    kernel: Vec<[f32; STEP_WIDTH]>,
+    // Steps still to be summed into upcoming output samples, the front is the next one
+    deltas: VecDeque<f32>,
+    // Running sum of the deltas, the band-limited signal
+    level: f32,
+    // Last input sample, steps are the difference from it
+    last: f32,
+    // Output phase, an output sample is due every time this passes input_rate
+    phase: u32,
+
+    filters: [Filter; 3],
+    buffer: RingBuffer,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            input_rate,
+            output_rate,
+            kernel: step_kernel(),
+            deltas: VecDeque::from(vec![0.0; STEP_WIDTH]),
+            level: 0.0,
+            last: 0.0,
+            phase: 0,
+            filters: [
+                Filter::high_pass(output_rate, 90.0),
+                Filter::high_pass(output_rate, 440.0),
+                Filter::low_pass(output_rate, 14000.0),
+            ],
+            // Half a second of audio
+            buffer: RingBuffer::new(output_rate as usize / 2),
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    // Feed one input sample
+    pub fn push(&mut self, sample: f32) {
+        let delta = sample - self.last;
+        if delta != 0.0 {
+            self.last = sample;
+            // How far between output samples the step falls
+            let phase =
+                (self.phase as u64 * STEP_PHASES as u64 / self.input_rate as u64) as usize;
+            for (out, tap) in self.deltas.iter_mut().zip(self.kernel[phase].iter()) {
+                *out += delta * tap;
+            }
+        }
+
+        self.phase += self.output_rate;
+        if self.phase >= self.input_rate {
+            self.phase -= self.input_rate;
+
+            self.level += self.deltas.pop_front().unwrap();
+            self.deltas.push_back(0.0);
+            let mut out = self.level;
+            for filter in self.filters.iter_mut() {
+                out = filter.apply(out);
+            }
+            self.buffer.push(out.clamp(-1.0, 1.0));
+        }
+    }
+    // Take all the samples ready for the host
+    pub fn drain_into(&mut self, out: &mut Vec<f32>) {
+        self.buffer.drain_into(out);
+    }
+
+    // Send all the samples ready for the host to a sink
+    pub fn drain_to(&mut self, sink: &mut dyn AudioSink) -> std::io::Result<()> {
+        let mut samples = Vec::with_capacity(self.buffer.len());
+        self.buffer.drain_into(&mut samples);
+        sink.write_samples(&samples)
+    }
+}
+//: }}}
+
+//: WavSink {{{
+/// Writes 16-bit mono PCM to a .wav file, used to capture audio without a sound device
+pub struct WavSink {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    data_bytes: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &str, sample_rate: u32) -> std::io::Result<Self> {
+        let mut sink = Self {
+            writer: BufWriter::new(File::create(path)?),
+            sample_rate,
+            data_bytes: 0,
+        };
+        // Sizes are filled in by finish()
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let channels: u16 = 1;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+        let byte_rate = self.sample_rate * block_align as u32;
+
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+        w.write_all(b"fmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&channels.to_le_bytes())?;
+        w.write_all(&self.sample_rate.to_le_bytes())?;
+        w.write_all(&byte_rate.to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&bits_per_sample.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&self.data_bytes.to_le_bytes())?;
+        Ok(())
+    }
+
+    // Patch the header with the final sizes
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.data_bytes += (samples.len() * 2) as u32;
+        Ok(())
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+//: }}}
+
+//: Playhead {{{
+// Reads the ring buffer for the sound device, holding the last sample through underruns
+struct Playhead {
+    last: f32,
+    underrun: bool,
+}
+
+impl Playhead {
+    fn new() -> Self {
+        // Starts out waiting for the buffer to fill
+        Self {
+            last: 0.0,
+            underrun: true,
+        }
+    }
+
+    fn next(&mut self, queue: &mut RingBuffer) -> f32 {
+        if self.underrun && queue.len() >= queue.capacity / 2 {
+            self.underrun = false;
+        }
+        if !self.underrun {
+            match queue.pop() {
+                Some(sample) => self.last = sample,
+                None => self.underrun = true,
+            }
+        }
+        self.last
+    }
+}
+//: }}}
+
+//: LiveSink {{{
+/// Plays audio on the default sound device.
+/// The frontend pushes a frame of samples at a time and the device pulls them from a small
+/// ring buffer on its own thread, if the frontend gets ahead the oldest samples are dropped.
+/// When the buffer runs dry the last sample is held until it is half full again, so a slow
+/// frame costs one gap instead of a crackle.
+#[cfg(feature = "live-audio")]
+pub struct LiveSink {
+    // Playback stops when the stream is dropped
+    _stream: cpal::Stream,
+    queue: Arc<Mutex<RingBuffer>>,
+    sample_rate: u32,
+}
+
+#[cfg(feature = "live-audio")]
+impl LiveSink {
+    pub fn open() -> Result<Self, String> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use cpal::SampleFormat;
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("No sound device found")?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        let sample_rate = config.sample_rate().0;
+        // A tenth of a second, about 6 frames
+        let queue = Arc::new(Mutex::new(RingBuffer::new(sample_rate as usize / 10)));
+
+        let format = config.sample_format();
+        let config = config.into();
+        let stream = match format {
+            SampleFormat::F32 => Self::build_stream::<f32>(&device, &config, &queue),
+            SampleFormat::I16 => Self::build_stream::<i16>(&device, &config, &queue),
+            SampleFormat::U16 => Self::build_stream::<u16>(&device, &config, &queue),
+            format => return Err(format!("Unsupported sample format {format}")),
+        }
+        .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            _stream: stream,
+            queue,
+            sample_rate,
+        })
+    }
+
+    fn build_stream<T>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        queue: &Arc<Mutex<RingBuffer>>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: cpal::SizedSample + cpal::FromSample<f32>,
+    {
+        use cpal::traits::DeviceTrait;
+
+        let queue = Arc::clone(queue);
+        let channels = config.channels as usize;
+        let mut playhead = Playhead::new();
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut queue = queue.lock().unwrap();
+                // Same mono sample on every channel
+                for frame in data.chunks_mut(channels) {
+                    frame.fill(T::from_sample(playhead.next(&mut queue)));
+                }
+            },
+            |e| eprintln!("Audio stream error: {e}"),
+            None,
+        )
+    }
+}
+
+#[cfg(feature = "live-audio")]
+impl AudioSink for LiveSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        for sample in samples {
+            queue.push(*sample);
+        }
+        Ok(())
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RMS of the second half of a quarter second square wave
+    fn square_rms(period: u32) -> f32 {
+        let mut resampler = Resampler::new(CPU_CLOCK_NTSC, 44_100);
+        for i in 0..CPU_CLOCK_NTSC / 4 {
+            resampler.push(if i % period < period / 2 { 0.25 } else { -0.25 });
+        }
+        let mut out = Vec::new();
+        resampler.drain_into(&mut out);
+        let tail = &out[out.len() / 2..];
+        (tail.iter().map(|s| s * s).sum::<f32>() / tail.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn resampler_is_band_limited() {
+        // 1kHz comes through
+        assert!(square_rms(1790) > 0.15);
+        // 29.8kHz is past Nyquist, averaging each output period would fold it back to
+        // 14.3kHz at about -14dB
+        assert!(square_rms(60) < 0.005);
+    }
+
+    #[test]
+    fn playhead_holds_through_underrun() {
+        let mut queue = RingBuffer::new(8);
+        let mut playhead = Playhead::new();
+
+        // Nothing plays until the buffer is half full
+        for sample in [0.1, 0.2, 0.3] {
+            queue.push(sample);
+            assert_eq!(playhead.next(&mut queue), 0.0);
+        }
+        queue.push(0.4);
+        assert_eq!(playhead.next(&mut queue), 0.1);
+        for sample in [0.2, 0.3, 0.4] {
+            assert_eq!(playhead.next(&mut queue), sample);
+        }
+
+        // Ran dry, the last sample is held until it is half full again
+        assert_eq!(playhead.next(&mut queue), 0.4);
+        queue.push(0.5);
+        assert_eq!(playhead.next(&mut queue), 0.4);
+        for sample in [0.6, 0.7, 0.8] {
+            queue.push(sample);
+        }
+        assert_eq!(playhead.next(&mut queue), 0.5);
+
+        // Too far ahead drops the oldest
+        for i in 0..10 {
+            queue.push(i as f32);
+        }
+        assert_eq!(queue.len(), 8);
+        assert_eq!(playhead.next(&mut queue), 2.0);
+    }
+}
+//: }}}
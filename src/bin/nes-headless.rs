@@ -0,0 +1,482 @@
+// Vim folding
+// vim:foldmethod=marker
+// Headless runner for CI and batch jobs, no window or sound device needed
+//
+// Usage: nes-headless <rom> [options]
+//   --frames <n>              Frames to run (default 60, or the length of --movie)
+//   --until-pc <hex>          Stop once the cpu reaches this address
+//   --until-ram <hex>=<hex>   Stop once a cpu ram address holds this value
+//   --input <file>            Scripted input, lines of "<frame> <buttons> [<buttons>...]"
+//                             where buttons is a comma separated list (a,b,select,start,
+//                             up,down,left,right) or "." for none, one column per player
+//                             (up to 4)
+//   --multitap <type>         Plug in a four player adapter, "fourscore" or "famicom"
+//   --zapper <file>           Plug a zapper into port 2, aimed by lines of
+//                             "<frame> <x> <y> [fire]", y of 240 or more is off screen
+//   --movie <file>            Play an fm2 movie from power on instead of --input
+//   --record <file>           Write the input of every frame run as an fm2 movie
+//   --cheats <file>           Load a cheat file, see cheats.rs for the format
+//   --cheat <code>            Add a Game Genie or ram cheat, can be given more than once
+//   --hash-every <n>          Print the frame hash every n frames
+//   --screenshot <file>       Write the last frame as a png
+//   --dump-ram <file>         Write the 2KB of cpu ram at the end
+//   --wav <file>              Capture audio
+//   --info                    Print the decoded rom header
+//   --sav <file>              Battery ram, loaded if the file exists and written at the end
+//
+// The crc32 of the last frame is always printed, and the exit code is 1 if an --until
+// condition was never met. If a KIL opcode jams the cpu it is reported on stderr and the
+// ppu keeps running until the last frame.
+use nes_emulator::audio::{AudioSink, WavSink};
+use nes_emulator::bus::{WINDOW_HEIGHT, WINDOW_WIDTH};
+use nes_emulator::cartridge::{CartError, Timing};
+use nes_emulator::cheats::{Cheat, Cheats};
+use nes_emulator::input::*;
+use nes_emulator::movie::{Movie, MoviePlayer};
+use nes_emulator::utils::{crc32, crc32_update};
+use nes_emulator::Nes;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+//: Options {{{
+// Frame and the buttons held on each port from then on
+type InputScript = Vec<(u32, [u8; PLAYER_COUNT])>;
+// Frame and the zapper aim from then on
+type ZapperScript = Vec<(u32, ZapperInput)>;
+
+struct Options {
+    rom: String,
+    frames: Option<u32>,
+    until_pc: Option<u16>,
+    until_ram: Option<(u16, u8)>,
+    input: InputScript,
+    zapper: Option<ZapperScript>,
+    multitap: Option<DeviceKind>,
+    movie: Option<String>,
+    record: Option<String>,
+    cheats: Cheats,
+    hash_every: Option<u32>,
+    screenshot: Option<String>,
+    dump_ram: Option<String>,
+    wav: Option<String>,
+    info: bool,
+    sav: Option<String>,
+}
+
+fn parse_hex_u16(s: &str) -> Result<u16, Box<dyn Error>> {
+    Ok(u16::from_str_radix(s.trim_start_matches("0x").trim_start_matches('$'), 16)?)
+}
+
+fn parse_buttons(s: &str) -> Result<u8, Box<dyn Error>> {
+    let mut buttons = 0;
+    if s == "." {
+        return Ok(buttons);
+    }
+    for name in s.split(',') {
+        buttons |= match button_from_name(name.trim()) {
+            Some(bit) => bit,
+            None => return Err(format!("Unknown button {name}"))?,
+        };
+    }
+    Ok(buttons)
+}
+
+// Input script, each line sets the buttons held from that frame on
+fn parse_input_script(path: &str) -> Result<InputScript, Box<dyn Error>> {
+    let mut script = Vec::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || format!("{path}:{}: bad input line", number + 1);
+        let mut parts = line.split_whitespace();
+        let frame = parts.next().unwrap_or_default().parse::<u32>().map_err(|_| bad_line())?;
+        let mut buttons = [0; PLAYER_COUNT];
+        for (player, column) in parts.enumerate() {
+            match (buttons.get_mut(player), parse_buttons(column)) {
+                (Some(held), Ok(b)) => *held = b,
+                _ => return Err(bad_line())?,
+            }
+        }
+        script.push((frame, buttons));
+    }
+    script.sort_by_key(|(frame, _)| *frame);
+    Ok(script)
+}
+
+// Zapper script, each line aims the zapper from that frame on
+fn parse_zapper_script(path: &str) -> Result<ZapperScript, Box<dyn Error>> {
+    let mut script = Vec::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match parts.as_slice() {
+            [frame, x, y] | [frame, x, y, "fire"] => (frame.parse(), x.parse(), y.parse()),
+            _ => return Err(format!("{path}:{}: bad zapper line", number + 1))?,
+        };
+        match parsed {
+            (Ok(frame), Ok(x), Ok(y)) => {
+                let trigger = parts.len() == 4;
+                script.push((frame, ZapperInput { x, y, trigger }));
+            }
+            _ => return Err(format!("{path}:{}: bad zapper line", number + 1))?,
+        }
+    }
+    script.sort_by_key(|(frame, _)| *frame);
+    Ok(script)
+}
+
+fn parse_args() -> Result<Options, Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = Options {
+        rom: String::new(),
+        frames: None,
+        until_pc: None,
+        until_ram: None,
+        input: Vec::new(),
+        zapper: None,
+        multitap: None,
+        movie: None,
+        record: None,
+        cheats: Cheats::new(),
+        hash_every: None,
+        screenshot: None,
+        dump_ram: None,
+        wav: None,
+        info: false,
+        sav: None,
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let mut value = || -> Result<String, Box<dyn Error>> {
+            i += 1;
+            match args.get(i) {
+                Some(v) => Ok(v.clone()),
+                None => Err(format!("{arg} needs a value"))?,
+            }
+        };
+        match arg {
+            "--frames" => options.frames = Some(value()?.parse()?),
+            "--until-pc" => options.until_pc = Some(parse_hex_u16(&value()?)?),
+            "--until-ram" => {
+                let v = value()?;
+                let Some((addr, val)) = v.split_once('=') else {
+                    return Err("--until-ram expects <addr>=<value>")?;
+                };
+                options.until_ram = Some((parse_hex_u16(addr)?, parse_hex_u16(val)? as u8));
+            }
+            "--input" => options.input = parse_input_script(&value()?)?,
+            "--zapper" => options.zapper = Some(parse_zapper_script(&value()?)?),
+            "--multitap" => {
+                options.multitap = match value()?.as_str() {
+                    "fourscore" => Some(DeviceKind::FourScore),
+                    "famicom" => Some(DeviceKind::FamicomAdapter),
+                    other => return Err(format!("Unknown multitap {other}"))?,
+                }
+            }
+            "--movie" => options.movie = Some(value()?),
+            "--record" => options.record = Some(value()?),
+            "--cheats" => {
+                let path = value()?;
+                let cheats = Cheats::parse(&fs::read_to_string(&path)?)
+                    .map_err(|(line, e)| format!("{path}:{line}: {e}"))?;
+                options.cheats.list.extend(cheats.list);
+            }
+            "--cheat" => options.cheats.add(Cheat::parse(&value()?, "")?),
+            "--hash-every" => options.hash_every = Some(value()?.parse()?),
+            "--screenshot" => options.screenshot = Some(value()?),
+            "--dump-ram" => options.dump_ram = Some(value()?),
+            "--wav" => options.wav = Some(value()?),
+            "--info" => options.info = true,
+            "--sav" => options.sav = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}"))?,
+            _ => options.rom = arg.to_string(),
+        }
+        i += 1;
+    }
+
+    if options.rom.is_empty() {
+        Err("Usage: nes-headless <rom> [options]")?;
+    }
+    if options.movie.is_some() && (!options.input.is_empty() || options.zapper.is_some()) {
+        Err("--movie can't be used with --input or --zapper")?;
+    }
+    Ok(options)
+}
+//: }}}
+
+//: ScriptInput {{{
+// Plays the input scripts, input stays held until the next line changes it
+struct ScriptInput {
+    script: InputScript,
+    zapper: ZapperScript,
+    next: usize,
+    next_zapper: usize,
+    frame: u32,
+    held: FrameInput,
+}
+
+impl InputSource for ScriptInput {
+    fn next_frame(&mut self) -> Option<FrameInput> {
+        while let Some((_, buttons)) = self.script.get(self.next).filter(|(f, _)| *f <= self.frame) {
+            self.held.buttons = *buttons;
+            self.next += 1;
+        }
+        while let Some((_, aim)) = self.zapper.get(self.next_zapper).filter(|(f, _)| *f <= self.frame) {
+            self.held.zapper = *aim;
+            self.next_zapper += 1;
+        }
+        self.frame += 1;
+        Some(self.held)
+    }
+}
+//: }}}
+
+//: write_png {{{
+// Minimal png writer, the image data goes into uncompressed (stored) deflate blocks
+fn write_png(path: &str, width: u32, height: u32, rgba: &[u8]) -> std::io::Result<()> {
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&crc32_update(crc32(kind), data).to_be_bytes());
+    }
+
+    // Each row starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity(((width * 4 + 1) * height) as usize);
+    for row in rgba.chunks((width * 4) as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream of stored blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in &raw {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8 bit RGBA
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib);
+    chunk(&mut png, b"IEND", &[]);
+    fs::write(path, png)
+}
+//: }}}
+
+//: main {{{
+fn main() {
+    let options = match parse_args() {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(2);
+        }
+    };
+
+    let mut nes = match fs::read(&options.rom)
+        .map_err(CartError::Io)
+        .and_then(|rom| Nes::from_rom_bytes(&rom))
+    {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(2);
+        }
+    };
+
+    if options.info {
+        println!("{:#?}", nes.cart_info());
+    }
+
+    // Only NTSC timing is emulated, other regions run too fast and too high pitched
+    let timing = nes.cart_info().timing;
+    if timing == Timing::Pal || timing == Timing::Dendy {
+        eprintln!("Warning: {timing:?} cart, it runs with NTSC timing");
+    }
+
+    if let Some(path) = &options.sav {
+        if let Ok(data) = fs::read(path) {
+            if let Err(e) = nes.load_save_ram(&data) {
+                eprintln!("{e}");
+                exit(2);
+            }
+        }
+    }
+
+    let mut audio_sink: Option<WavSink> = None;
+    if let Some(path) = &options.wav {
+        match WavSink::create(path, 44_100) {
+            Ok(sink) => audio_sink = Some(sink),
+            Err(e) => {
+                eprintln!("{e}");
+                exit(2);
+            }
+        }
+    }
+
+    if let Some(sink) = &audio_sink {
+        nes.set_sample_rate(sink.sample_rate());
+    }
+
+    if let Some(kind) = options.multitap {
+        nes.plug(0, kind);
+    }
+    if options.zapper.is_some() {
+        nes.plug(1, DeviceKind::Zapper);
+    }
+    nes.set_cheats(options.cheats.clone());
+
+    // Input comes from the movie if there is one, otherwise the script
+    let mut frames = options.frames.unwrap_or(60);
+    let mut source: Box<dyn InputSource> = match &options.movie {
+        Some(path) => {
+            let movie = match fs::read_to_string(path) {
+                Ok(text) => Movie::parse_fm2(&text).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let movie = match movie {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("{path}: {e}");
+                    exit(2);
+                }
+            };
+            if movie.rom_md5().is_some_and(|md5| md5 != nes.rom_md5()) {
+                eprintln!("Warning: {path} was recorded with a different rom");
+            }
+            for (port, kind) in movie.devices().into_iter().enumerate() {
+                nes.plug(port, kind);
+            }
+            frames = options.frames.unwrap_or(movie.len() as u32);
+            Box::new(MoviePlayer::new(movie))
+        }
+        None => Box::new(ScriptInput {
+            script: options.input.clone(),
+            zapper: options.zapper.clone().unwrap_or_default(),
+            next: 0,
+            next_zapper: 0,
+            frame: 0,
+            held: FrameInput::default(),
+        }),
+    };
+
+    let mut recording = options.record.as_ref().map(|_| {
+        let name = Path::new(&options.rom).file_stem().unwrap_or_default();
+        let pal = nes.cart_info().timing == Timing::Pal;
+        let devices = [nes.device_kind(0), nes.device_kind(1)];
+        Movie::new(&name.to_string_lossy(), nes.rom_md5(), pal, devices)
+    });
+
+    let has_condition = options.until_pc.is_some() || options.until_ram.is_some();
+    let mut condition_met = false;
+    let mut frame: u32 = 0;
+
+    while frame < frames {
+        // Nothing held once a movie runs out
+        let input = source.next_frame().unwrap_or_default();
+        nes.apply_input(&input);
+        if let Some(movie) = recording.as_mut() {
+            movie.push(input);
+        }
+
+        if let Some(pc) = options.until_pc {
+            // Go an instruction at a time so the pc can be checked
+            let start = nes.frame_count();
+            while nes.frame_count() == start && nes.cpu().pc != pc {
+                nes.step_instruction();
+            }
+            if nes.cpu().pc == pc {
+                condition_met = true;
+                break;
+            }
+        } else {
+            nes.run_frame();
+        }
+        frame += 1;
+
+        for event in nes.take_cpu_events() {
+            eprintln!("frame {frame}: {event}");
+        }
+
+        if let Some(sink) = audio_sink.as_mut() {
+            if let Err(e) = sink.write_samples(&nes.audio_samples()) {
+                eprintln!("{e}");
+                exit(2);
+            }
+        }
+
+        if options.hash_every.is_some_and(|n| n > 0 && frame.is_multiple_of(n)) {
+            println!("frame {frame} {:08X}", crc32(nes.frame_buffer()));
+        }
+
+        if let Some((addr, value)) = options.until_ram {
+            if nes.peek(addr) == value {
+                condition_met = true;
+                break;
+            }
+        }
+    }
+
+    println!("frames {frame} hash {:08X}", crc32(nes.frame_buffer()));
+
+    if let Some(path) = &options.screenshot {
+        if let Err(e) = write_png(path, WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32, nes.frame_buffer()) {
+            eprintln!("{e}");
+            exit(2);
+        }
+    }
+
+    if let Some(path) = &options.dump_ram {
+        let memory: Vec<u8> = (0..0x800).map(|addr| nes.peek(addr)).collect();
+        if let Err(e) = fs::write(path, memory) {
+            eprintln!("{e}");
+            exit(2);
+        }
+    }
+
+    if let (Some(path), Some(data)) = (&options.sav, nes.save_ram()) {
+        if let Err(e) = fs::write(path, data) {
+            eprintln!("{e}");
+            exit(2);
+        }
+    }
+
+    if let (Some(path), Some(movie)) = (&options.record, &recording) {
+        if let Err(e) = fs::write(path, movie.to_fm2()) {
+            eprintln!("{e}");
+            exit(2);
+        }
+    }
+
+    // exit() skips destructors, finish the wav first
+    drop(audio_sink);
+    if has_condition && !condition_met {
+        eprintln!("Stop condition not met after {frame} frames");
+        exit(1);
+    }
+}
+//: }}}
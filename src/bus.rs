@@ -2,10 +2,13 @@
 // vim:foldmethod=marker
 #![allow(dead_code)]
 #![allow(unused_variables)]
-use crate::cartridge::Cart;
+use crate::apu::Apu;
+use crate::cartridge::{Cart, CartError, CartInfo, Mirroring};
+use crate::cheats::Cheats;
 use crate::input::Input;
 use crate::ppu::PpuData;
 use crate::ram::Ram;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub const WINDOW_WIDTH: u16 = 256;
 pub const WINDOW_HEIGHT: u16 = 240;
@@ -19,34 +22,36 @@ pub const PPU_SCROLL_ADDR: u16 = 0x2005;
 pub const PPU_ADDR_ADDR: u16 = 0x2006;
 pub const PPU_DATA_ADDR: u16 = 0x2007;
 pub const OAM_DMA_ADDR: u16 = 0x4014;
+pub const APU_STATUS_ADDR: u16 = 0x4015;
 pub const JOYPAD_ONE_ADDR: u16 = 0x4016;
+pub const JOYPAD_TWO_ADDR: u16 = 0x4017; // Reads only, writes go to the apu frame counter
+pub const APU_FRAME_COUNTER_ADDR: u16 = 0x4017;
 
 //: Bus {{{
-pub struct Bus<'a> {
-    ram: &'a mut Ram, 
-    cart: &'a Cart,
-    input: &'a mut Input,
+pub struct Bus {
+    ram: Ram,
+    cart: Cart,
+    input: Input,
+    cheats: Cheats,
 
-    pub nmi_signal: bool,  // Non Maskable Interrupt Signal, flag is here 
-                           // because it goes from PPU to CPU 
     pub ppu_data: PpuData, // PPU Data that must be accessed by other 
                            // parts of the code. Defined in ppu.rs
     pub cpu_debug: bool,   // Flag to display assembled instructions as they execute 
-    pub oam_dma_cpu: bool, // Flag for CPU to halt during OAM DMA 
-    pub oam_dma_ppu: bool, // Flag for PPU to perform OAM DMA
-    pub oam_dma_addr: u16, // Address of the OAM DMA
+    pub oam_dma_page: Option<u8>, // Page written to $4014, the cpu's DMA unit picks it up
+    pub apu: Apu,          // Audio Processing Unit, registers live at $4000-$4017
+    pub open_bus: u8,      // Last value on the cpu data bus, undriven bits read back as this
 }
 //}}}
 
 //: Bus Functions {{{
-impl<'a> Bus<'a> {
+impl Bus {
     // Setup Functions
-    pub fn new(ram: &'a mut Ram, cart: &'a Cart, input: &'a mut Input) -> Self {
+    pub fn new(ram: Ram, cart: Cart, input: Input) -> Self {
         Self {
             ram,
             cart,
             input,
-            nmi_signal: false,
+            cheats: Cheats::new(),
             ppu_data: PpuData {
                 nmi_occurred: false,
                 ctrl: 0,
@@ -64,15 +69,28 @@ impl<'a> Bus<'a> {
                 oam: [0; 0x100],
             },
             cpu_debug: false,
-            oam_dma_cpu: false,
-            oam_dma_ppu: false,
-            oam_dma_addr: 0,
+            oam_dma_page: None,
+            apu: Apu::new(),
+            open_bus: 0,
         }
     }
 
     // Interface Functions
     // Read a byte
-    pub fn read(&mut self, mut addr: u16, debug: bool) -> u8 {
+    pub fn read(&mut self, addr: u16, debug: bool) -> u8 {
+        let mut value = self.read_device(addr, debug);
+        // Game Genie sits between the cart and the console
+        if addr >= 0x8000 {
+            value = self.cheats.patch_read(addr, value);
+        }
+        if !debug {
+            self.open_bus = value;
+        }
+        value
+    }
+
+    // Read a byte from whatever is mapped at addr
+    fn read_device(&mut self, mut addr: u16, debug: bool) -> u8 {
         if addr < 0x2000 {
             // Internal RAM
             return self.ram.get_cpu_memory(addr);
@@ -133,16 +151,25 @@ impl<'a> Bus<'a> {
         } else {
             // Cartridge space
             if addr < 0x4020 {
-                // Read joypad one input one bit at a time
-                if addr == JOYPAD_ONE_ADDR {
-                    return self.input.read_and_shift_joypad_one();
+                // Read joypad input one bit at a time, the upper bits are open bus
+                if addr == JOYPAD_ONE_ADDR || addr == JOYPAD_TWO_ADDR {
+                    let port = (addr - JOYPAD_ONE_ADDR) as usize;
+                    let bit = if debug {
+                        self.input.peek(port)
+                    } else {
+                        self.input.read(port)
+                    };
+                    (self.open_bus & 0xE0) | bit
+                } else if addr == APU_STATUS_ADDR {
+                    self.apu.read_status(debug)
                 } else {
-                    return 0;
+                    0
                 }
-            } else if addr < 0x8000 {
-                // Cart RAM, todo
+            } else if addr < 0x6000 {
+                // Expansion area, unused by supported mappers
                 return 0;
             } else {
+                // Prg ram and rom
                 return self.cart.cpu_read(addr);
             }
         }
@@ -168,6 +195,7 @@ impl<'a> Bus<'a> {
     }
 
     pub fn write(&mut self, mut addr: u16, value: u8) {
+        self.open_bus = value;
         if addr < 0x2000 {
             // Internal RAM
             self.ram.set_cpu_memory(addr, value);
@@ -187,7 +215,10 @@ impl<'a> Bus<'a> {
                 // Address for oam read / write
                 OAM_ADDR_ADDR => self.ppu_data.oam_addr = value,
                 // Value to write to oam at address specified
-                OAM_DATA_ADDR => self.ppu_data.oam[self.ppu_data.oam_addr as usize] = value,
+                OAM_DATA_ADDR => {
+                    self.ppu_data.oam[self.ppu_data.oam_addr as usize] = value;
+                    self.ppu_data.oam_addr = self.ppu_data.oam_addr.wrapping_add(1);
+                }
                 // Value of scroll position
                 PPU_SCROLL_ADDR => {
                     if !self.ppu_data.scroll_latch {
@@ -224,41 +255,160 @@ impl<'a> Bus<'a> {
                 }
                 _ => return, // catch all
             }
+        } else if (0x4000..=0x4013).contains(&addr)
+            || addr == APU_STATUS_ADDR
+            || addr == APU_FRAME_COUNTER_ADDR
+        {
+            // Audio registers
+            self.apu.write(addr, value);
         } else if addr == OAM_DMA_ADDR {
-            // Setup OAM DMA
-            self.oam_dma_cpu = true;
-            self.oam_dma_ppu = true;
-            self.oam_dma_addr = (value as u16) << 8;
+            // OAM DMA, done by the cpu's DMA unit
+            self.oam_dma_page = Some(value);
         } else if addr == JOYPAD_ONE_ADDR {
-            // Latch joypad one read
-            if value & 1 != 0 {
-                self.input.set_latch(true);
-            } else {
-                // Do the actual input reading here
-                // On actual hardware it happens continually,
-                // but there is no point as only A could be read
-                self.input.update_input();
-                self.input.set_latch(false);
-            }
+            // Strobe latches every controller port
+            self.input.write_strobe(value);
+        } else if addr >= 0x6000 {
+            // Prg ram and mapper registers
+            self.cart.cpu_write(addr, value);
         }
     }
 
     // Read from PPU Vram
     pub fn ppu_read(&self, addr: u16) -> u8 {
+        let mirroring = self.cart.mirroring();
         if addr < 0x2000 {
-            return self.cart.ppu_read(addr);
+            self.cart.ppu_read(addr)
+        } else if addr < 0x3F00 && mirroring == Mirroring::FourScreen {
+            self.cart.nametable_read(addr)
         } else {
-            return self.ram.get_ppu_memory(addr);
+            self.ram.get_ppu_memory(addr, mirroring)
+        }
+    }
+
+    // Pattern table read made by the ppu while rendering
+    // Mappers such as MMC3 watch these fetches to count scanlines
+    pub fn ppu_fetch_pattern(&mut self, addr: u16) -> u8 {
+        if self.ppu_data.get_rendering_enabled() {
+            self.cart.ppu_fetch(addr);
         }
+        self.ppu_read(addr)
+    }
+
+    // Reset button, the ppu stops rendering and the apu goes quiet
+    // ram, vram and the mapper keep their contents
+    pub fn reset(&mut self) {
+        self.ppu_data.ctrl = 0;
+        self.ppu_data.mask = 0;
+        self.ppu_data.addr_latch = false;
+        self.ppu_data.scroll_latch = false;
+        self.ppu_data.data_buffer = 0;
+        self.oam_dma_page = None;
+        self.apu.write(APU_STATUS_ADDR, 0);
+    }
+
+    // Controller state, set by the frontend
+    pub fn input(&self) -> &Input {
+        &self.input
+    }
+
+    pub fn input_mut(&mut self) -> &mut Input {
+        &mut self.input
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    // Put frozen ram values back, once a frame
+    pub fn apply_ram_cheats(&mut self) {
+        self.cheats.apply_ram(&mut self.ram);
+    }
+
+    // Decoded header of the loaded rom
+    pub fn cart_info(&self) -> &CartInfo {
+        &self.cart.info
+    }
+
+    // Battery backed cart ram, None if the cart has no battery
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        self.cart.save_ram()
+    }
+
+    pub fn load_save_ram(&mut self, data: &[u8]) -> Result<(), CartError> {
+        self.cart.load_save_ram(data)
+    }
+
+    // Crc of the loaded rom
+    pub fn rom_crc(&self) -> u32 {
+        self.cart.rom_crc()
+    }
+
+    pub fn rom_md5(&self) -> [u8; 16] {
+        self.cart.rom_md5()
+    }
+
+    // NMI line, high from the start of vblank until $2002 is read or vblank ends, as long as
+    // NMIs are enabled in $2000. The cpu triggers on it going high
+    pub fn nmi_line(&self) -> bool {
+        self.ppu_data.nmi_occurred && self.ppu_data.get_nmi_enable()
+    }
+
+    // IRQ line, true while any device is requesting an interrupt
+    pub fn irq_signal(&self) -> bool {
+        self.cart.irq() || self.apu.irq()
+    }
+
+    // Clock the APU and the mapper, once per CPU cycle
+    // DMC sample fetches are done by the cpu's DMA unit
+    pub fn clock(&mut self) {
+        self.apu.clock();
+        self.cart.cpu_clock();
     }
 
     // Write to PPU Vram
     pub fn ppu_write(&mut self, addr: u16, value: u8) {
-        if addr < 0x2000 { // Cannot write chr rom
+        if addr < 0x2000 {
+            // Chr ram, ignored by carts with chr rom
+            self.cart.ppu_write(addr, value);
         } else {
-            // let actual_addr = addr - 0x2000;
-            self.ram.set_ppu_memory(addr, value);
+            let mirroring = self.cart.mirroring();
+            if addr < 0x3F00 && mirroring == Mirroring::FourScreen {
+                self.cart.nametable_write(addr, value);
+            } else {
+                self.ram.set_ppu_memory(addr, value, mirroring);
+            }
         }
     }
 }
 //: }}}
+
+//: Bus SaveState {{{
+impl SaveState for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.oam_dma_page.is_some());
+        w.write_u8(self.oam_dma_page.unwrap_or(0));
+        w.write_u8(self.open_bus);
+        self.ppu_data.save_state(w);
+        self.apu.save_state(w);
+        self.ram.save_state(w);
+        self.input.save_state(w);
+        self.cart.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let dma = r.read_bool()?;
+        let page = r.read_u8()?;
+        self.oam_dma_page = dma.then_some(page);
+        self.open_bus = r.read_u8()?;
+        self.ppu_data.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.ram.load_state(r)?;
+        self.input.load_state(r)?;
+        self.cart.load_state(r)
+    }
+}
+//: }}}
@@ -1,12 +1,13 @@
 // Vim folding
 // vim:foldmethod=marker
-// Basicly mapper 0 right now
 #![allow(dead_code)]
 #![allow(unused_variables)]
+use crate::mapper::{self, Mapper};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 use crate::utils;
-use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::result::Result;
 
 //: Cart Structs {{{
@@ -18,12 +19,155 @@ pub struct NesHeader {
 pub struct Cart {
     /// The Header data for the Cartridge
     pub header: NesHeader,
-    /// Contains the chr data
+    /// Decoded header, what the rest of the emulator looks at
+    pub info: CartInfo,
+    /// Contains the chr data, rom or ram
     pub chr: Vec<u8>,
+    /// Chr is ram (the header has no chr rom), writable by the ppu
+    pub chr_ram: bool,
     /// Contains the prg data
     pub prg: Vec<u8>,
+    /// Prg ram at $6000-$7FFF, kept in a .sav file if the cart has a battery
+    pub prg_ram: Vec<u8>,
     /// The Trainer Area follows the 16-byte Header and precedes the PRG-ROM area if bit 2 of Header byte 6 is set. It is always 512 bytes in size if present, and contains data to be loaded into CPU memory at $7000. It is only used by some games that were modified to run on different hardware from the original cartridges, such as early RAM cartridges and emulators, and which put some additional compatibility code into those address ranges.
     pub trainer: Option<[u8; 512]>,
+    /// Handles bank switching, picked from the mapper number in the header
+    pub mapper: Box<dyn Mapper>,
+    /// crc32 of prg then chr rom, see rom_crc()
+    rom_crc: u32,
+    /// md5 of prg then chr rom, how FCEUX identifies roms
+    rom_md5: [u8; 16],
+    /// How the nametables are wired, MapperControlled if the mapper switches it
+    pub mirroring: Mirroring,
+    /// Nametable vram on four screen boards, 4KB covering $2000-$2FFF
+    pub nametable_ram: Vec<u8>,
+}
+
+/// Decoded iNES / NES 2.0 header, sizes are in bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CartInfo {
+    /// Header is in the NES 2.0 format
+    pub nes2: bool,
+    /// 12-bit mapper number (8-bit for iNES 1.0)
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    /// Battery backed prg ram
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    /// Battery backed chr ram
+    pub chr_nvram_size: usize,
+    /// Hardwired mirroring or four screen, mappers may override the former
+    pub mirroring: Mirroring,
+    pub four_screen: bool,
+    pub battery: bool,
+    pub trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    /// Default expansion device (NES 2.0 byte 15)
+    pub expansion_device: u8,
+}
+
+/// Cpu/ppu timing the game was made for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Works on NTSC and PAL
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    /// NES 2.0 extended console type (byte 13)
+    Extended(u8),
+}
+
+/// Nametable mirroring
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    /// $2000 = $2400, $2800 = $2C00
+    Horizontal,
+    /// $2000 = $2800, $2400 = $2C00
+    Vertical,
+    /// Every nametable uses the lower 1KB of vram
+    SingleScreenA,
+    /// Every nametable uses the upper 1KB of vram
+    SingleScreenB,
+    /// Four separate nametables, the cart provides the extra vram
+    FourScreen,
+    /// The mapper picks one of the above at runtime, see Cart::mirroring
+    MapperControlled,
+}
+// }}}
+
+//: CartError {{{
+#[derive(Debug)]
+pub enum CartError {
+    /// The rom file could not be read
+    Io(io::Error),
+    /// Smaller than the 16 byte header
+    TooShort,
+    /// Does not start with "NES\x1a"
+    BadMagic,
+    /// Header says there is a trainer but the file ends first
+    TruncatedTrainer,
+    /// File ends before all the prg rom the header asks for
+    TruncatedPrg { expected: usize, found: usize },
+    /// File ends before all the chr rom the header asks for
+    TruncatedChr { expected: usize, found: usize },
+    /// Header has 0 banks of prg rom
+    NoPrg,
+    /// NES 2.0 exponent-multiplier size too large to address
+    BadRomSize,
+    /// Mapper number this emulator does not implement
+    UnsupportedMapper(u16),
+    /// Save data given to a cart without battery backed ram
+    NoBattery,
+    /// Save data is not the size of the cart's prg ram
+    SaveSizeMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for CartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartError::Io(e) => write!(f, "Could not read rom: {e}"),
+            CartError::TooShort => write!(f, "Rom is too short to contain a header"),
+            CartError::BadMagic => write!(f, "ROM does not contain magic bytes"),
+            CartError::TruncatedTrainer => write!(f, "Rom ends inside the trainer"),
+            CartError::TruncatedPrg { expected, found } => write!(
+                f,
+                "Rom is truncated: header says {expected} bytes of prg rom but only {found} are present"
+            ),
+            CartError::TruncatedChr { expected, found } => write!(
+                f,
+                "Rom is truncated: header says {expected} bytes of chr rom but only {found} are present"
+            ),
+            CartError::NoPrg => write!(f, "Rom has no prg rom"),
+            CartError::BadRomSize => write!(f, "Header gives a rom size too large to load"),
+            CartError::UnsupportedMapper(n) => write!(f, "Unsupported mapper {n}"),
+            CartError::NoBattery => write!(f, "Cartridge has no battery backed ram"),
+            CartError::SaveSizeMismatch { expected, found } => write!(
+                f,
+                "Save is {found} bytes but the cartridge has {expected} bytes of prg ram"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 // }}}
 
@@ -35,134 +179,548 @@ impl NesHeader {
     pub fn mirror(&self) -> bool {
         (self.data[6] & 1) != 0
     }
+    /// Mirroring as specified by the header
+    pub fn mirroring(&self) -> Mirroring {
+        if self.four_screen() {
+            Mirroring::FourScreen
+        } else if self.mirror() {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
     /// Returns true Cartridge contains battery-backed PRG RAM ($6000-7FFF) or other persistent memory
-    fn battery(&self) -> bool {
+    pub fn battery(&self) -> bool {
         (self.data[6] & (1 << 1)) != 0
     }
     /// If trainer data exists
-    fn trainer(&self) -> bool {
+    pub fn trainer(&self) -> bool {
         (self.data[6] & (1 << 2)) != 0
     }
     /// Ignore mirror control; instead four screen vram is provided
-    fn four_screen(&self) -> bool {
+    pub fn four_screen(&self) -> bool {
         (self.data[6] & (1 << 3)) != 0
     }
     /// Gets the mapper number
-    fn mapper(&self) -> u8 {
-        (self.data[7] & 0b1111000) | ((self.data[6] & 0b11110000) >> 4)
+    /// NES 2.0 adds 4 more bits in byte 8
+    /// iNES 1.0 dumps with junk in bytes 12-15 (like "DiskDude!") only get the lower 4 bits
+    pub fn mapper(&self) -> u16 {
+        let low = ((self.data[6] & 0b11110000) >> 4) as u16;
+        if self.nes2() {
+            low | (self.data[7] & 0b11110000) as u16 | ((self.data[8] & 0x0F) as u16) << 8
+        } else if self.data[12..=15].iter().any(|b| *b != 0) {
+            low
+        } else {
+            low | (self.data[7] & 0b11110000) as u16
+        }
     }
-    fn unisystem(&self) -> bool {
-        (self.data[7] & 1) != 0
+    /// NES 2.0 submapper, picks between boards that share a mapper number
+    pub fn submapper(&self) -> u8 {
+        if self.nes2() {
+            self.data[8] >> 4
+        } else {
+            0
+        }
     }
-    /// PlayChoice-10 (8 KB of Hint Screen data stored after CHR data)
-    /// In the context of this emulator, this data is simply ignored
-    fn playchoice(&self) -> bool {
-        (self.data[7] & (1 << 1)) != 0
+    /// Console type
+    /// 0: NES/Famicom
+    /// 1: Vs. System
+    /// 2: PlayChoice-10 (8 KB of Hint Screen data stored after CHR data)
+    /// 3: Extended, NES 2.0 only, the type is in byte 13
+    pub fn console_type(&self) -> ConsoleType {
+        match self.data[7] & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ if self.nes2() => ConsoleType::Extended(self.data[13] & 0x0F),
+            // iNES 1.0 only has the two separate flag bits
+            _ => ConsoleType::VsSystem,
+        }
     }
     /// Determines if this is in the nes2.0 format
-    /// This is simply for bookeeping
-    fn nes2(&self) -> bool {
+    pub fn nes2(&self) -> bool {
         ((self.data[7] & 0b00001100) >> 2) == 2
     }
-    /// Determines size of the prg ram
-    fn prg_ram(&self) -> usize {
-        if self.data[8] == 0 {
-            return 8192;
+    /// Determines size of the volatile prg ram
+    /// Note: iNES 1.0 can't tell work ram from battery ram, so everything counts as battery
+    /// backed if the battery bit is set
+    pub fn prg_ram(&self) -> usize {
+        if self.nes2() {
+            Self::shift_size(self.data[10] & 0x0F)
+        } else if self.battery() {
+            0
+        } else {
+            self.ines_prg_ram()
+        }
+    }
+    /// Determines size of the battery backed prg ram
+    pub fn prg_nvram(&self) -> usize {
+        if self.nes2() {
+            Self::shift_size(self.data[10] >> 4)
+        } else if self.battery() {
+            self.ines_prg_ram()
         } else {
-            (self.data[8] as usize) * 8192
+            0
         }
     }
-    /// NTSC vs PAL
-    /// 0: NTSC
-    /// 1: PAL
-    /// Note: No ROM images in circulation make use of this bit
-    fn tv_system(&self) -> bool {
-        (self.data[9] & 1) != 0
+    // iNES 1.0 byte 8, in 8KB units with 0 meaning 8KB for compatibility
+    fn ines_prg_ram(&self) -> usize {
+        (self.data[8].max(1) as usize) * 8192
+    }
+    /// Determines size of the volatile chr ram
+    pub fn chr_ram(&self) -> usize {
+        if self.nes2() {
+            Self::shift_size(self.data[11] & 0x0F)
+        } else if self.chr_size() == Some(0) {
+            8192
+        } else {
+            0
+        }
+    }
+    /// Determines size of the battery backed chr ram
+    pub fn chr_nvram(&self) -> usize {
+        if self.nes2() {
+            Self::shift_size(self.data[11] >> 4)
+        } else {
+            0
+        }
+    }
+    // NES 2.0 ram sizes are stored as shift counts, 64 << n bytes with 0 meaning none
+    fn shift_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+    /// Cpu/ppu timing
+    /// iNES 1.0 only has the NTSC/PAL bit in byte 9
+    /// Note: No iNES 1.0 ROM images in circulation make use of this bit
+    pub fn timing(&self) -> Timing {
+        if !self.nes2() {
+            return if self.data[9] & 1 != 0 {
+                Timing::Pal
+            } else {
+                Timing::Ntsc
+            };
+        }
+        match self.data[12] & 0b11 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+        }
+    }
+    /// Default expansion device (NES 2.0 byte 15), 1 is the standard controllers
+    pub fn expansion_device(&self) -> u8 {
+        if self.nes2() {
+            self.data[15] & 0b00111111
+        } else {
+            0
+        }
     }
     /// Usually zeroed out data but sometimes may contain ripper's name or something
-    fn ripper_name(&self) -> [u8; 7] {
+    fn ripper_name(&self) -> [u8; 9] {
         // Rust, my beloved, why
         self.data[7..=15].try_into().expect("Invalid length")
     }
-    /// Gets the size of the prg rom
-    fn prg_size(&self) -> usize {
-        self.data[4] as usize * 16384
+    /// Gets the size of the prg rom, None if it does not fit in a usize
+    /// NES 2.0 adds 4 more bits in byte 9, or an exponent-multiplier form if those are all 1
+    pub fn prg_size(&self) -> Option<usize> {
+        if self.nes2() {
+            Self::rom_size(self.data[4], self.data[9] & 0x0F, 16384)
+        } else {
+            Some(self.data[4] as usize * 16384)
+        }
     }
     /// Gets the size of the chr rom
-    /// A value of 0 indicates that the board uses chr-ram
-    fn chr_size(&self) -> usize {
-        self.data[5] as usize * 8192
+    /// A value of 0 indicates that the board uses chr-ram, None that it does not fit in a usize
+    pub fn chr_size(&self) -> Option<usize> {
+        if self.nes2() {
+            Self::rom_size(self.data[5], self.data[9] >> 4, 8192)
+        } else {
+            Some(self.data[5] as usize * 8192)
+        }
+    }
+    // NES 2.0 rom size from the lsb byte and msb nibble
+    // An msb of $F means the lsb is EEEEEEMM, size = 2^E * (MM * 2 + 1)
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b11) as usize * 2 + 1;
+            1usize.checked_shl(exponent)?.checked_mul(multiplier)
+        } else {
+            (((msb as usize) << 8) | lsb as usize).checked_mul(unit)
+        }
     }
     /// Checks to see if the magic bytes of the rom are accurrate
     fn magic(&self) -> bool {
         self.data[0..=3] == *b"NES\x1a"
     }
+
+    /// Everything the header says about the board
+    pub fn info(&self) -> Result<CartInfo, CartError> {
+        let (Some(prg_rom_size), Some(chr_rom_size)) = (self.prg_size(), self.chr_size()) else {
+            return Err(CartError::BadRomSize);
+        };
+        Ok(CartInfo {
+            nes2: self.nes2(),
+            mapper: self.mapper(),
+            submapper: self.submapper(),
+            prg_rom_size,
+            chr_rom_size,
+            prg_ram_size: self.prg_ram(),
+            prg_nvram_size: self.prg_nvram(),
+            chr_ram_size: self.chr_ram(),
+            chr_nvram_size: self.chr_nvram(),
+            mirroring: self.mirroring(),
+            four_screen: self.four_screen(),
+            battery: self.battery(),
+            trainer: self.trainer(),
+            timing: self.timing(),
+            console_type: self.console_type(),
+            expansion_device: self.expansion_device(),
+        })
+    }
 }
 
 impl Cart {
-    pub fn new(filename: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn new(filename: &str) -> Result<Self, CartError> {
         // Loads the file
-        let mut file = File::open(filename)?;
-        let size = file.metadata()?.len() as usize;
-        let mut buffer = vec![0u8; size];
-        file.read(&mut buffer)?;
+        let buffer = fs::read(filename).map_err(CartError::Io)?;
+        Self::from_bytes(&buffer)
+    }
 
+    /// Loads a cartridge from an iNES image in memory
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self, CartError> {
         // Reads the header data
-        let data = buffer[0..=15].try_into()?;
+        let Some(data) = buffer.get(0..16) else {
+            return Err(CartError::TooShort);
+        };
+        let header = NesHeader {
+            data: data.try_into().unwrap(),
+        };
         let mut ptr = 16;
-        let header = NesHeader { data };
         if !header.magic() {
-            return Err("ROM does not contain magic bytes")?;
+            return Err(CartError::BadMagic);
         }
 
-        let trainer = None;
-        if header.trainer() {
-            let trainer_data: [u8; 512] = buffer[ptr..=(ptr + 512)].try_into()?;
-            let trainer = Some(trainer_data);
+        let info = header.info()?;
+
+        let mut trainer: Option<[u8; 512]> = None;
+        if info.trainer {
+            let Some(trainer_data) = buffer.get(ptr..ptr + 512) else {
+                return Err(CartError::TruncatedTrainer);
+            };
+            trainer = Some(trainer_data.try_into().unwrap());
             ptr += 512;
         }
 
-        let mut prg = vec![0u8; header.prg_size()];
-        let mut chr = vec![0u8; header.chr_size()];
-        utils::readbuf_vec(&mut prg, &mut buffer, &mut ptr, header.prg_size());
-        utils::readbuf_vec(&mut chr, &mut buffer, &mut ptr, header.chr_size());
+        if buffer.len() - ptr < info.prg_rom_size {
+            return Err(CartError::TruncatedPrg {
+                expected: info.prg_rom_size,
+                found: buffer.len() - ptr,
+            });
+        }
+        let mut prg = vec![0u8; info.prg_rom_size];
+        utils::readbuf_vec(&mut prg, buffer, &mut ptr, info.prg_rom_size);
+
+        if buffer.len() - ptr < info.chr_rom_size {
+            return Err(CartError::TruncatedChr {
+                expected: info.chr_rom_size,
+                found: buffer.len() - ptr,
+            });
+        }
+        let mut chr = vec![0u8; info.chr_rom_size];
+        utils::readbuf_vec(&mut chr, buffer, &mut ptr, info.chr_rom_size);
+
+        // Identify the rom before chr ram is allocated, chr ram contents change while running
+        let rom_crc = utils::crc32_update(utils::crc32(&prg), &chr);
+        let rom_md5 = utils::md5(&[prg.as_slice(), chr.as_slice()].concat());
+
+        // No chr rom means the board has chr ram, 8KB unless NES 2.0 says otherwise
+        let chr_ram = chr.is_empty();
+        if chr_ram {
+            let size = info.chr_ram_size + info.chr_nvram_size;
+            chr = vec![0u8; if size == 0 { 0x2000 } else { size }];
+        }
+
+        if prg.is_empty() {
+            return Err(CartError::NoPrg);
+        }
+
+        let Some(mapper) = mapper::new_mapper(&info) else {
+            return Err(CartError::UnsupportedMapper(info.mapper));
+        };
 
-        return Ok(Cart {
+        // The trainer gets loaded at $7000, so a cart with one gets at least 8KB of prg ram
+        let mut prg_ram_size = info.prg_ram_size + info.prg_nvram_size;
+        if trainer.is_some() {
+            prg_ram_size = prg_ram_size.max(0x2000);
+        }
+        let mut prg_ram = vec![0u8; prg_ram_size];
+        if let Some(trainer) = &trainer {
+            prg_ram[0x1000..0x1200].copy_from_slice(trainer);
+        }
+
+        // Four screen boards ignore the mapper's mirroring control
+        let mirroring = if info.mirroring != Mirroring::FourScreen && mapper.mirroring().is_some() {
+            Mirroring::MapperControlled
+        } else {
+            info.mirroring
+        };
+        let nametable_ram = if mirroring == Mirroring::FourScreen {
+            vec![0u8; 0x1000]
+        } else {
+            Vec::new()
+        };
+
+        Ok(Cart {
             header,
+            info,
             trainer,
             prg,
+            prg_ram,
             chr,
-        });
+            chr_ram,
+            mapper,
+            rom_crc,
+            rom_md5,
+            mirroring,
+            nametable_ram,
+        })
     }
 
     pub fn cpu_read(&self, addr: u16) -> u8 {
-        // mapper 0
-        if addr < 0x8000 {
+        if addr < 0x6000 {
             // not dealt with
             return 0;
-        } else {
-            let mut raw_addr: u16 = addr;
-            if self.prg.len() == 0 {
-                return 0;
-            }
-            raw_addr -= 0x8000;
-            if raw_addr as usize >= self.prg.len() {
-                raw_addr %= self.prg.len() as u16;
+        }
+        if addr < 0x8000 {
+            // Prg ram, reads as 0 if missing or disabled
+            return match self.mapper.prg_ram_map(addr, false) {
+                Some(offset) if !self.prg_ram.is_empty() => {
+                    self.prg_ram[offset % self.prg_ram.len()]
+                }
+                _ => 0,
+            };
+        }
+        self.prg[self.mapper.cpu_map_read(addr) % self.prg.len()]
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = if self.mapper.bus_conflicts() {
+                value & self.cpu_read(addr)
+            } else {
+                value
+            };
+            self.mapper.cpu_write(addr, value);
+        } else if addr >= 0x6000 && !self.prg_ram.is_empty() {
+            if let Some(offset) = self.mapper.prg_ram_map(addr, true) {
+                let len = self.prg_ram.len();
+                self.prg_ram[offset % len] = value;
             }
-            return self.prg[raw_addr as usize];
         }
     }
 
+    /// Battery backed prg ram, the contents of the .sav file
+    /// None if the cart has no battery
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        if self.info.battery && !self.prg_ram.is_empty() {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    /// Restore battery backed prg ram from a .sav file
+    pub fn load_save_ram(&mut self, data: &[u8]) -> Result<(), CartError> {
+        if self.save_ram().is_none() {
+            return Err(CartError::NoBattery);
+        }
+        if data.len() != self.prg_ram.len() {
+            return Err(CartError::SaveSizeMismatch {
+                expected: self.prg_ram.len(),
+                found: data.len(),
+            });
+        }
+        self.prg_ram.copy_from_slice(data);
+        Ok(())
+    }
+
     pub fn ppu_read(&self, addr: u16) -> u8 {
-        // mapper 0
-        let mut raw_addr: u16 = addr;
-        if self.chr.len() == 0 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[self.mapper.ppu_map_read(addr) % self.chr.len()]
+    }
+
+    /// Ppu write to $0000-$1FFF, only lands if the cart has chr ram
+    pub fn ppu_write(&mut self, addr: u16, value: u8) {
+        if self.chr_ram {
+            let offset = self.mapper.ppu_map_read(addr) % self.chr.len();
+            self.chr[offset] = value;
+        }
+    }
+
+    /// Pattern fetch made by the ppu while rendering
+    pub fn ppu_fetch(&mut self, addr: u16) {
+        self.mapper.ppu_fetch(addr);
+    }
+
+    /// One cpu cycle has passed
+    pub fn cpu_clock(&mut self) {
+        self.mapper.cpu_clock();
+    }
+
+    /// Mapper interrupt request
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    /// Identifies the rom (crc32 of prg then chr rom), used to match save states
+    pub fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    /// Identifies the rom in movie files (md5 of prg then chr rom)
+    pub fn rom_md5(&self) -> [u8; 16] {
+        self.rom_md5
+    }
+
+    /// Current nametable mirroring, never MapperControlled
+    pub fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            Mirroring::MapperControlled => {
+                self.mapper.mirroring().unwrap_or(self.info.mirroring)
+            }
+            m => m,
+        }
+    }
+
+    /// Nametable read on a four screen board, $2000-$3EFF
+    pub fn nametable_read(&self, addr: u16) -> u8 {
+        if self.nametable_ram.is_empty() {
             return 0;
         }
-        if raw_addr as usize >= self.prg.len() {
-            raw_addr %= self.prg.len() as u16;
+        self.nametable_ram[((addr - 0x2000) & 0x0FFF) as usize]
+    }
+
+    /// Nametable write on a four screen board, $2000-$3EFF
+    pub fn nametable_write(&mut self, addr: u16, value: u8) {
+        if !self.nametable_ram.is_empty() {
+            self.nametable_ram[((addr - 0x2000) & 0x0FFF) as usize] = value;
+        }
+    }
+}
+// }}}
+
+//: Cart SaveState {{{
+// Rom contents are identified by the rom crc, only ram and the mapper state are saved
+impl SaveState for Cart {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_vec(&self.prg_ram);
+        if self.chr_ram {
+            w.write_vec(&self.chr);
         }
-        return self.chr[raw_addr as usize];
+        w.write_vec(&self.nametable_ram);
+        self.mapper.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_vec_into(&mut self.prg_ram)?;
+        if self.chr_ram {
+            r.read_vec_into(&mut self.chr)?;
+        }
+        r.read_vec_into(&mut self.nametable_ram)?;
+        self.mapper.load_state(r)
+    }
+}
+// }}}
+
+//: Test roms {{{
+/// iNES 1.0 image for tests, prg and chr are whole 16KB and 8KB banks
+#[cfg(test)]
+pub fn test_rom(mapper: u8, prg: &[u8], chr: &[u8]) -> Vec<u8> {
+    let mut rom = b"NES\x1a".to_vec();
+    rom.push((prg.len() / 0x4000) as u8);
+    rom.push((chr.len() / 0x2000) as u8);
+    rom.push(mapper << 4);
+    rom.push(mapper & 0xF0);
+    rom.extend_from_slice(&[0; 8]);
+    rom.extend_from_slice(prg);
+    rom.extend_from_slice(chr);
+    rom
+}
+// }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patched(rom: &[u8], patch: impl Fn(&mut Vec<u8>)) -> Vec<u8> {
+        let mut rom = rom.to_vec();
+        patch(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn bad_dumps() {
+        let rom = test_rom(0, &[0xEA; 0x8000], &[0; 0x2000]);
+        let cases = [
+            (rom[..4].to_vec(), "TooShort"),
+            (patched(&rom, |r| r[0] = b'X'), "BadMagic"),
+            (rom[..16 + 0x4000].to_vec(), "TruncatedPrg { expected: 32768, found: 16384 }"),
+            (rom[..16 + 0x9000].to_vec(), "TruncatedChr { expected: 8192, found: 4096 }"),
+            // Trainer flag set without one, the chr comes up short
+            (patched(&rom, |r| r[6] |= 0x04), "TruncatedChr { expected: 8192, found: 7680 }"),
+            (
+                patched(&rom, |r| {
+                    r[6] |= 0x04;
+                    r.truncate(16 + 0x100);
+                }),
+                "TruncatedTrainer",
+            ),
+            // NES 2.0 prg size of 2^63 * 7 bytes
+            (
+                patched(&rom, |r| {
+                    r[7] |= 0x08;
+                    r[4] = 0xFF;
+                    r[9] = 0x0F;
+                }),
+                "BadRomSize",
+            ),
+            (test_rom(0, &[], &[0; 0x2000]), "NoPrg"),
+            (test_rom(5, &[0xEA; 0x8000], &[]), "UnsupportedMapper(5)"),
+        ];
+        for (rom, expected) in cases {
+            match Cart::from_bytes(&rom) {
+                Ok(_) => panic!("{expected} loaded"),
+                Err(e) => assert_eq!(format!("{e:?}"), expected),
+            }
+        }
+    }
+
+    #[test]
+    fn trainer() {
+        let rom = test_rom(0, &[0xEA; 0x8000], &[0; 0x2000]);
+        let trainer: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        let mut with_trainer = rom[..16].to_vec();
+        with_trainer[6] |= 0x04;
+        with_trainer.extend_from_slice(&trainer);
+        with_trainer.extend_from_slice(&rom[16..]);
+
+        let cart = Cart::from_bytes(&with_trainer).unwrap();
+        assert!(cart.info.trainer);
+        // Loaded at $7000 even though the header asks for no prg ram
+        assert_eq!(cart.prg_ram.len(), 0x2000);
+        assert_eq!(&cart.prg_ram[0x1000..0x1200], &trainer[..]);
+        assert_eq!(cart.cpu_read(0x7000), 0x00);
+        assert_eq!(cart.cpu_read(0x71FF), 0xFF);
+        // Prg starts after it
+        assert_eq!(cart.cpu_read(0x8000), 0xEA);
     }
 }
 // }}}
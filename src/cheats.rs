@@ -0,0 +1,306 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+use crate::ram::Ram;
+use std::fmt;
+
+// Cheats, applied by the bus so the game never knows
+//
+// Game Genie codes patch cart reads at $8000-$FFFF, 6 letter codes always replace the
+// byte and 8 letter codes only when the rom holds the compare value (so bank switched
+// code at the same address is left alone).
+// Ram codes freeze a byte of cpu ram, it is written back at the end of every frame like
+// a Pro Action Replay does. They are written "AAAA:VV" or as a 6 digit PAR code "AAAAVV".
+//
+// Cheat files live next to the rom as <name>.cht, one cheat per line:
+//   SXIOPO Infinite lives
+//   -0075:09 Start on world 9, the leading - means disabled
+// Lines starting with # are comments.
+// Cheats are not part of save states, loading a state keeps the current cheats.
+
+// Game Genie letters in order of the nibble they stand for
+const GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+//: CheatError {{{
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheatError {
+    /// Code is neither a Game Genie nor a ram code
+    BadCode(String),
+    /// Ram code address is outside of cpu ram
+    NotRam(u16),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheatError::BadCode(code) => write!(f, "{code} is not a cheat code"),
+            CheatError::NotRam(addr) => write!(f, "${addr:04X} is not in cpu ram"),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+//: }}}
+
+//: Cheat {{{
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheatKind {
+    /// Replace a rom byte, if compare is set only when the rom holds that value
+    Rom { addr: u16, value: u8, compare: Option<u8> },
+    /// Keep a cpu ram byte at value
+    Ram { addr: u16, value: u8 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    // Code as typed, written back to the cheat file
+    pub code: String,
+    pub name: String,
+    pub kind: CheatKind,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// Decode a Game Genie or ram code
+    pub fn parse(code: &str, name: &str) -> Result<Self, CheatError> {
+        let kind = match decode_game_genie(code) {
+            Some(kind) => kind,
+            None => decode_ram_code(code)?,
+        };
+        Ok(Self {
+            code: code.to_uppercase(),
+            name: name.to_string(),
+            kind,
+            enabled: true,
+        })
+    }
+}
+
+// Game Genie letters to nibbles
+//   6 letters: address and value
+//   8 letters: address, value and compare
+fn decode_game_genie(code: &str) -> Option<CheatKind> {
+    let n: Vec<u16> = code
+        .chars()
+        .map(|c| GENIE_LETTERS.find(c.to_ascii_uppercase()).map(|i| i as u16))
+        .collect::<Option<_>>()?;
+    if n.len() != 6 && n.len() != 8 {
+        return None;
+    }
+
+    // The bits are shuffled across the letters
+    let addr = 0x8000
+        | ((n[3] & 7) << 12)
+        | ((n[5] & 7) << 8)
+        | ((n[4] & 8) << 8)
+        | ((n[2] & 7) << 4)
+        | ((n[1] & 8) << 4)
+        | (n[4] & 7)
+        | (n[3] & 8);
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+
+    if n.len() == 6 {
+        let value = (value | (n[5] & 8)) as u8;
+        return Some(CheatKind::Rom { addr, value, compare: None });
+    }
+    let value = (value | (n[7] & 8)) as u8;
+    let compare = (((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)) as u8;
+    Some(CheatKind::Rom {
+        addr,
+        value,
+        compare: Some(compare),
+    })
+}
+
+// "AAAA:VV" or the PAR form "AAAAVV"
+fn decode_ram_code(code: &str) -> Result<CheatKind, CheatError> {
+    let bad_code = || CheatError::BadCode(code.to_string());
+    let (addr, value) = match code.split_once(':') {
+        Some(parts) => parts,
+        None if code.len() == 6 && code.is_char_boundary(4) => code.split_at(4),
+        None => return Err(bad_code()),
+    };
+    let addr = u16::from_str_radix(addr, 16).map_err(|_| bad_code())?;
+    let value = u8::from_str_radix(value, 16).map_err(|_| bad_code())?;
+    // Mirrors of ram are fine, they all land on the same byte
+    if addr >= 0x2000 {
+        return Err(CheatError::NotRam(addr));
+    }
+    Ok(CheatKind::Ram { addr, value })
+}
+//: }}}
+
+//: Cheats {{{
+/// Every cheat for the loaded game, the bus asks it about each rom read
+#[derive(Clone, Debug)]
+pub struct Cheats {
+    pub list: Vec<Cheat>,
+    // Switch for all cheats at once, individual cheats keep their own setting
+    pub enabled: bool,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Self {
+            list: Vec::new(),
+            enabled: true,
+        }
+    }
+
+    /// Parse a cheat file, errors carry the line number
+    pub fn parse(text: &str) -> Result<Self, (usize, CheatError)> {
+        let mut cheats = Self::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (code, enabled) = match code.strip_prefix('-') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            let mut cheat = Cheat::parse(code, name.trim()).map_err(|e| (number + 1, e))?;
+            cheat.enabled = enabled;
+            cheats.list.push(cheat);
+        }
+        Ok(cheats)
+    }
+
+    /// Cheat file text that parses back into these cheats
+    pub fn to_file(&self) -> String {
+        let mut text = String::new();
+        for cheat in &self.list {
+            let disabled = if cheat.enabled { "" } else { "-" };
+            let line = format!("{disabled}{} {}", cheat.code, cheat.name);
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.list.push(cheat);
+    }
+
+    /// Turn one cheat on or off, false if there is no such cheat
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.list.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn active(&self) -> impl Iterator<Item = &CheatKind> {
+        self.list
+            .iter()
+            .filter(|c| self.enabled && c.enabled)
+            .map(|c| &c.kind)
+    }
+
+    /// Value the cpu sees when reading value from the cart at addr
+    pub fn patch_read(&self, addr: u16, value: u8) -> u8 {
+        for kind in self.active() {
+            if let CheatKind::Rom {
+                addr: a,
+                value: v,
+                compare,
+            } = *kind
+            {
+                if a == addr && compare.is_none_or(|c| c == value) {
+                    return v;
+                }
+            }
+        }
+        value
+    }
+
+    /// Write the frozen values back into cpu ram
+    pub fn apply_ram(&self, ram: &mut Ram) {
+        for kind in self.active() {
+            if let CheatKind::Ram { addr, value } = *kind {
+                ram.set_cpu_memory(addr, value);
+            }
+        }
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn six_letter_code() {
+        let cheat = Cheat::parse("sxiopo", "").unwrap();
+        assert_eq!(cheat.code, "SXIOPO");
+        assert_eq!(
+            cheat.kind,
+            CheatKind::Rom { addr: 0x91D9, value: 0xAD, compare: None }
+        );
+    }
+
+    #[test]
+    fn eight_letter_code_compares() {
+        // Same address, the 6th letter's high bit moves from the value to the compare
+        let cheat = Cheat::parse("SXIOPOAP", "").unwrap();
+        assert_eq!(
+            cheat.kind,
+            CheatKind::Rom { addr: 0x91D9, value: 0xA5, compare: Some(0x18) }
+        );
+
+        let mut cheats = Cheats::new();
+        cheats.add(cheat);
+        assert_eq!(cheats.patch_read(0x91D9, 0x18), 0xA5);
+        // Another bank at the same address
+        assert_eq!(cheats.patch_read(0x91D9, 0x19), 0x19);
+        assert_eq!(cheats.patch_read(0x91DA, 0x18), 0x18);
+
+        cheats.enabled = false;
+        assert_eq!(cheats.patch_read(0x91D9, 0x18), 0x18);
+    }
+
+    #[test]
+    fn ram_codes() {
+        assert_eq!(
+            Cheat::parse("0075:09", "").unwrap().kind,
+            CheatKind::Ram { addr: 0x0075, value: 0x09 }
+        );
+        assert_eq!(
+            Cheat::parse("007509", "").unwrap().kind,
+            CheatKind::Ram { addr: 0x0075, value: 0x09 }
+        );
+        assert_eq!(Cheat::parse("2000:01", ""), Err(CheatError::NotRam(0x2000)));
+        assert_eq!(
+            Cheat::parse("SXIOP", ""),
+            Err(CheatError::BadCode("SXIOP".to_string()))
+        );
+    }
+
+    #[test]
+    fn cheat_file_round_trip() {
+        let text = "SXIOPO Infinite lives\n-0075:09 Start on world 9\n";
+        let cheats = Cheats::parse(&format!("# Super Mario Bros.\n\n{text}")).unwrap();
+        assert_eq!(cheats.list.len(), 2);
+        assert!(cheats.list[0].enabled);
+        assert!(!cheats.list[1].enabled);
+        assert_eq!(cheats.list[1].name, "Start on world 9");
+        assert_eq!(cheats.to_file(), text);
+
+        assert_eq!(
+            Cheats::parse("SXIOPO\n2000:01 Not ram").unwrap_err(),
+            (2, CheatError::NotRam(0x2000))
+        );
+    }
+}
+//: }}}
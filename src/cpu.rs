@@ -3,6 +3,8 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 use crate::bus::Bus;
+use crate::dma::Dma;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 use crate::utils::output_debug_info;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -11,8 +13,15 @@ const NMI_VECTOR: u16 = 0xFFFA;
 const RESET_VECTOR: u16 = 0xFFFC;
 const IRQ_VECTOR: u16 = 0xFFFE;
 
+// Steps from here on are the operand cycles, after the addressing mode has worked out addr
+const OPERAND_STEP: u8 = 0x10;
+
+// XAA and LXA OR A with a value that depends on the chip and its temperature before the
+// AND, $EE is what most chips do and what the usual test roms expect
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
 //: Cpu {{{
-pub struct Cpu<'a> {
+pub struct Cpu {
     pub a: u8,     // Accumulator
     pub x: u8,     // Register
     pub y: u8,     // Register
@@ -20,10 +29,52 @@ pub struct Cpu<'a> {
     pub stp: u8,   // Stack Pointer
     pub stat: u8,  // Status Register
     pub cycl: u32, // CPU Ticks
-    pub next: u32, // Tick of next instruction
 
-    pub irq_siginal: bool, // IRQ Flag 
-    pub bus: Rc<RefCell<Bus<'a>>>, // Reference to main bus
+    // Instruction in progress, every clock runs one cycle (one bus access) of it
+    pub op: u8,             // Opcode being run
+    step: u8,               // Next cycle of the instruction, 0 fetches an opcode
+    cycles: u8,             // Cycles taken so far, checked against CYCLE_COUNTS
+    addr: u16,              // Effective address
+    ptr: u8,                // Zero page pointer of the indirect modes
+    data: u8,               // Operand, or the value being modified
+    crossed: bool,          // Indexing crossed a page, the high byte of addr needs the carry
+    interrupt: bool,        // The BRK sequence is running for an IRQ or NMI
+    stall: u32,             // Cycles left of the reset sequence
+    dma: Dma,               // OAM and DMC DMA, halts the cpu while it uses the bus
+    jammed: bool,           // A KIL opcode stopped the cpu, only reset clears it
+
+    events: Vec<CpuEvent>, // Waiting for the frontend, see take_events
+
+    // Interrupt polling, the lines are sampled at the end of every cycle but the cpu acts
+    // on what it saw at the end of the second to last cycle of an instruction
+    nmi_line: bool,    // NMI line last cycle, NMI triggers when it goes high
+    nmi_pending: bool, // NMI edge seen and not handled yet
+    take_nmi: bool,    // nmi_pending as of the cycle before
+    irq_pending: bool, // IRQ line high and I clear
+    take_irq: bool,    // irq_pending as of the cycle before
+    skip_poll: bool,   // This cycle keeps take_nmi/take_irq as they were, see branch_step
+
+    pub irq_siginal: bool, // IRQ requested through irq(), held until it is handled
+    pub bus: Rc<RefCell<Bus>>, // Reference to main bus
+}
+//: }}}
+
+//: CpuEvent {{{
+/// Something the frontend or debugger should hear about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuEvent {
+    /// A KIL opcode locked up the cpu, pc is the address of the opcode
+    Jammed { pc: u16, opcode: u8 },
+}
+
+impl std::fmt::Display for CpuEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuEvent::Jammed { pc, opcode } => {
+                write!(f, "Cpu jammed by opcode ${opcode:02X} at ${pc:04X}, reset to continue")
+            }
+        }
+    }
 }
 //: }}}
 
@@ -71,10 +122,10 @@ pub static ADDRESSING_MODE_LOOKUP: [AddrM; 0x100] = [
     AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::IIY, AddrM::ZIX, AddrM::ZIX, AddrM::ZIX, AddrM::ZIX, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::AIY, AddrM::AIX, AddrM::AIX, AddrM::AIX, AddrM::AIX,
     AddrM::IMP, AddrM::IIX, AddrM::NUL, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::ACC, AddrM::IMD, AddrM::IND, AddrM::ABS, AddrM::ABS, AddrM::ABS,
     AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::IIY, AddrM::ZIX, AddrM::ZIX, AddrM::ZIX, AddrM::ZIX, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::AIY, AddrM::AIX, AddrM::AIX, AddrM::AIX, AddrM::AIX,
-    AddrM::IMD, AddrM::IIX, AddrM::IMD, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::IMP, AddrM::NUL, AddrM::ABS, AddrM::ABS, AddrM::ABS, AddrM::ABS,
-    AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::NUL, AddrM::ZIX, AddrM::ZIX, AddrM::ZIY, AddrM::ZIY, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::NUL, AddrM::NUL, AddrM::AIX, AddrM::NUL, AddrM::NUL,
-    AddrM::IMD, AddrM::IIX, AddrM::IMD, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::IMP, AddrM::NUL, AddrM::ABS, AddrM::ABS, AddrM::ABS, AddrM::ABS,
-    AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::IIY, AddrM::ZIX, AddrM::ZIX, AddrM::ZIY, AddrM::ZIY, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::NUL, AddrM::AIX, AddrM::AIX, AddrM::AIY, AddrM::AIY,
+    AddrM::IMD, AddrM::IIX, AddrM::IMD, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::IMP, AddrM::IMD, AddrM::ABS, AddrM::ABS, AddrM::ABS, AddrM::ABS,
+    AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::IIY, AddrM::ZIX, AddrM::ZIX, AddrM::ZIY, AddrM::ZIY, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::AIY, AddrM::AIX, AddrM::AIX, AddrM::AIY, AddrM::AIY,
+    AddrM::IMD, AddrM::IIX, AddrM::IMD, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::IMP, AddrM::IMD, AddrM::ABS, AddrM::ABS, AddrM::ABS, AddrM::ABS,
+    AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::IIY, AddrM::ZIX, AddrM::ZIX, AddrM::ZIY, AddrM::ZIY, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::AIY, AddrM::AIX, AddrM::AIX, AddrM::AIY, AddrM::AIY,
     AddrM::IMD, AddrM::IIX, AddrM::IMD, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::IMP, AddrM::IMD, AddrM::ABS, AddrM::ABS, AddrM::ABS, AddrM::ABS,
     AddrM::REL, AddrM::IIY, AddrM::NUL, AddrM::IIY, AddrM::ZIX, AddrM::ZIX, AddrM::ZIX, AddrM::ZIX, AddrM::IMP, AddrM::AIY, AddrM::IMP, AddrM::AIY, AddrM::AIX, AddrM::AIX, AddrM::AIX, AddrM::AIX,
     AddrM::IMD, AddrM::IIX, AddrM::IMD, AddrM::IIX, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::ZPG, AddrM::IMP, AddrM::IMD, AddrM::IMP, AddrM::IMD, AddrM::ABS, AddrM::ABS, AddrM::ABS, AddrM::ABS,
@@ -99,10 +150,10 @@ static CYCLE_COUNTS: [u8; 0x100] = [
     2|BA ,5|PBA,0    ,8    ,4    ,4    ,6    ,6    ,2    ,4|PBA,2    ,7    ,4|PBA,4|PBA,7    ,7    ,
     6    ,6    ,0    ,8    ,3    ,3    ,5    ,5    ,4    ,2    ,2    ,2    ,5    ,4    ,6    ,6    ,
     2|BA ,5|PBA,0    ,8    ,4    ,4    ,6    ,6    ,2    ,4|PBA,2    ,7    ,4|PBA,4|PBA,7    ,7    ,
-    2    ,6    ,2    ,6    ,3    ,3    ,3    ,3    ,2    ,2    ,2    ,0    ,4    ,4    ,4    ,4    ,
-    2|BA ,6    ,2    ,0    ,4    ,4    ,4    ,4    ,2    ,5    ,2    ,0    ,4    ,5    ,4    ,0    ,
-    2    ,6    ,2    ,6    ,3    ,3    ,3    ,3    ,2    ,2    ,2    ,0    ,4    ,4    ,4    ,4    ,
-    2|BA ,5|PBA,0    ,5|PBA,4    ,4    ,4    ,4    ,2    ,4|PBA,2    ,0    ,4|PBA,4|PBA,4|PBA,4    ,
+    2    ,6    ,2    ,6    ,3    ,3    ,3    ,3    ,2    ,2    ,2    ,2    ,4    ,4    ,4    ,4    ,
+    2|BA ,6    ,2    ,6    ,4    ,4    ,4    ,4    ,2    ,5    ,2    ,5    ,5    ,5    ,5    ,5    ,
+    2    ,6    ,2    ,6    ,3    ,3    ,3    ,3    ,2    ,2    ,2    ,2    ,4    ,4    ,4    ,4    ,
+    2|BA ,5|PBA,0    ,5|PBA,4    ,4    ,4    ,4    ,2    ,4|PBA,2    ,4|PBA,4|PBA,4|PBA,4|PBA,4|PBA,
     2    ,6    ,2    ,8    ,3    ,3    ,5    ,5    ,2    ,2    ,2    ,2    ,4    ,4    ,6    ,6    ,
     2|BA ,5|PBA,0    ,8    ,4    ,4    ,6    ,6    ,2    ,4|PBA,2    ,7    ,4|PBA,4|PBA,7    ,7    ,
     2    ,6    ,2    ,8    ,3    ,3    ,5    ,5    ,2    ,2    ,2    ,2    ,4    ,4    ,6    ,6    ,
@@ -112,8 +163,36 @@ static CYCLE_COUNTS: [u8; 0x100] = [
 //: }}}
 
 
+//: Kind {{{
+// What an instruction does with its operand, this decides the bus accesses once the
+// address is known
+#[derive(PartialEq, Eq)]
+enum Kind {
+    Read,   // Read the operand
+    Write,  // Write a register to memory
+    Modify, // Read, write the value back unchanged, then write the result
+}
+
+fn kind(opcode: u8) -> Kind {
+    match opcode {
+        // STA, STX, STY, *SAX, *SHA, *TAS, *SHY, *SHX
+        0x81 | 0x85 | 0x8D | 0x91 | 0x95 | 0x99 | 0x9D | 0x86 | 0x8E | 0x96 | 0x84 | 0x8C
+        | 0x94 | 0x83 | 0x87 | 0x8F | 0x97 | 0x93 | 0x9F | 0x9B | 0x9C | 0x9E => Kind::Write,
+        // ASL, ROL, LSR, ROR, DEC, INC
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E | 0x46 | 0x4E | 0x56 | 0x5E
+        | 0x66 | 0x6E | 0x76 | 0x7E | 0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE
+        // *SLO, *RLA, *SRE, *RRA, *DCP, *ISB
+        | 0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F | 0x23 | 0x27 | 0x2F | 0x33 | 0x37
+        | 0x3B | 0x3F | 0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F | 0x63 | 0x67 | 0x6F
+        | 0x73 | 0x77 | 0x7B | 0x7F | 0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF | 0xE3
+        | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF => Kind::Modify,
+        _ => Kind::Read,
+    }
+}
+//: }}}
+
 //: CPU_DEBUG {{{
-impl std::fmt::Debug for Cpu<'_> {
+impl std::fmt::Debug for Cpu {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Cpu")
             .field("a", &self.a)
@@ -123,16 +202,77 @@ impl std::fmt::Debug for Cpu<'_> {
             .field("stp", &self.stp)
             .field("stat", &self.stat)
             .field("cycl", &self.cycl)
-            .field("next", &self.next)
+            .field("op", &self.op)
+            .field("step", &self.step)
+            .field("jammed", &self.jammed)
             .finish()
     }
 }
 //: }}}
 
+//: Cpu SaveState {{{
+impl SaveState for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.a);
+        w.write_u8(self.x);
+        w.write_u8(self.y);
+        w.write_u16(self.pc);
+        w.write_u8(self.stp);
+        w.write_u8(self.stat);
+        w.write_u32(self.cycl);
+        w.write_u8(self.op);
+        w.write_u8(self.step);
+        w.write_u8(self.cycles);
+        w.write_u16(self.addr);
+        w.write_u8(self.ptr);
+        w.write_u8(self.data);
+        w.write_bool(self.crossed);
+        w.write_bool(self.interrupt);
+        w.write_u32(self.stall);
+        self.dma.save_state(w);
+        w.write_bool(self.jammed);
+        w.write_bool(self.nmi_line);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.take_nmi);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.take_irq);
+        w.write_bool(self.irq_siginal);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.a = r.read_u8()?;
+        self.x = r.read_u8()?;
+        self.y = r.read_u8()?;
+        self.pc = r.read_u16()?;
+        self.stp = r.read_u8()?;
+        self.stat = r.read_u8()?;
+        self.cycl = r.read_u32()?;
+        self.op = r.read_u8()?;
+        self.step = r.read_u8()?;
+        self.cycles = r.read_u8()?;
+        self.addr = r.read_u16()?;
+        self.ptr = r.read_u8()?;
+        self.data = r.read_u8()?;
+        self.crossed = r.read_bool()?;
+        self.interrupt = r.read_bool()?;
+        self.stall = r.read_u32()?;
+        self.dma.load_state(r)?;
+        self.jammed = r.read_bool()?;
+        self.nmi_line = r.read_bool()?;
+        self.nmi_pending = r.read_bool()?;
+        self.take_nmi = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.take_irq = r.read_bool()?;
+        self.irq_siginal = r.read_bool()?;
+        Ok(())
+    }
+}
+//: }}}
+
 //: Cpu Funtions {{{
-impl<'a> Cpu<'a> {
+impl Cpu {
     // Setup functions
-    pub fn new(bus: Rc<RefCell<Bus<'a>>>) -> Self {
+    pub fn new(bus: Rc<RefCell<Bus>>) -> Self {
         // Non zero are known startup values
         Self {
             a: 0u8,
@@ -142,7 +282,24 @@ impl<'a> Cpu<'a> {
             stp: 0xFD,
             stat: 0x24,
             cycl: 0u32,
-            next: 0u32,
+            op: 0u8,
+            step: 0u8,
+            cycles: 0u8,
+            addr: 0u16,
+            ptr: 0u8,
+            data: 0u8,
+            crossed: false,
+            interrupt: false,
+            stall: 0u32,
+            dma: Dma::new(),
+            jammed: false,
+            events: Vec::new(),
+            nmi_line: false,
+            nmi_pending: false,
+            take_nmi: false,
+            irq_pending: false,
+            take_irq: false,
+            skip_poll: false,
             irq_siginal: false,
             bus: bus,
         }
@@ -172,66 +329,68 @@ impl<'a> Cpu<'a> {
         self.bus.borrow_mut().write(addr, val);
     }
 
+    // Read the byte at pc and move past it
+    fn fetch(&mut self) -> u8 {
+        let value = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    fn push(&mut self, value: u8) {
+        self.write(0x100 + self.stp as u16, value);
+        self.stp = self.stp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.stp = self.stp.wrapping_add(1);
+        self.read(0x100 + self.stp as u16)
+    }
+
     // Interface functions
+    // One cpu cycle, which is one bus read or write (or a cycle halted)
     pub fn clock(&mut self) {
-        // CPU is paused during oam copy
-        if self.bus.borrow_mut().oam_dma_cpu {
-            self.next += 513;
-            if self.cycl % 2 == 1 {
-                // Wait an additional cycle if odd cycle tick
-                // I'm not sure if this is the right way to do
-                self.next += 1;
-            }
-            self.bus.borrow_mut().oam_dma_cpu = false;
+        // Hand DMA requests to the DMA unit
+        {
+            let mut bus = self.bus.borrow_mut();
+            if let Some(page) = bus.oam_dma_page.take() {
+                self.dma.start_oam(page);
+            }
+            if bus.apu.dmc_fetch_addr().is_some() {
+                self.dma.start_dmc();
+            }
         }
 
         // Loop clock every 60000 cycles
         if self.cycl > 60000 {
             self.cycl -= 60000;
-            self.next -= 60000;
         }
 
-        // We are not cycle accurate, opcodes all get run in one cycle 
-        // However we want them to overall take the correct amount of time,
-        // so we use cycl and next
-        if self.cycl == self.next {
-            if self.irq_siginal && self.get_flag(Flags::ID) == 0 {
-                // Handle an interrupt request
-                self.interrupt(IRQ_VECTOR);
-                self.irq_siginal = false;
-                self.next = self.cycl + 7;
-            } else if self.bus.borrow().nmi_signal {
-                // Handle a non maskable interrupt request
-                self.interrupt(NMI_VECTOR);
-                self.bus.borrow_mut().nmi_signal = false;
-                self.next = self.cycl + 8;
-            } else {
-                if self.bus.borrow().cpu_debug {
-                    output_debug_info(self);
-                }
-
-                // Read opcode from memory
-                let opcode: u8 = self.read(self.pc);
-                self.pc += 1;
-
-                // Calculate base cycle time
-                let opcode_cycles: u8 = CYCLE_COUNTS[opcode as usize] & 0x0F;
-
-                // Handle addressing modes
-                let (real_address, mut cycle_addition) = self.set_address_mode(opcode);
-
-                // Execute the opcode
-                cycle_addition += self.execute(opcode, real_address);
-
-                // Find total instruction time
-                self.next = self.cycl + (opcode_cycles as u32) + (cycle_addition as u32);
-            }
+        // DMA reads on even cycles and writes on odd ones
+        let get = self.cycl.is_multiple_of(2);
+        let cpu_read = self.next_read();
+
+        if self.stall > 0 {
+            self.stall -= 1;
+        } else if self.dma.takes_cycle(cpu_read) {
+            self.dma.cycle(&mut self.bus.borrow_mut(), cpu_read, get);
+        } else if self.jammed {
+            // Stuck until reset, the ppu and apu carry on without it
+        } else if self.step == 0 {
+            self.start_instruction();
+        } else {
+            self.instruction_step();
         }
+        self.poll_interrupts();
 
         // Increment internal counter every clock
         self.cycl += 1;
     }
 
+    // True between instructions, the next clock fetches an opcode (or starts an interrupt)
+    pub fn at_boundary(&self) -> bool {
+        self.step == 0 && self.stall == 0 && !self.dma.active()
+    }
+
     pub fn reset(&mut self) {
         // Reset changes several valse, and runs from wherever the reset vector points to
         self.set_flag(Flags::ID, true);
@@ -241,7 +400,28 @@ impl<'a> Cpu<'a> {
         let pc_two = self.read(RESET_VECTOR + 1);
         self.pc = ((pc_two as u16) << 8) + pc_one as u16;
         self.cycl = 0;
-        self.next = 8;
+
+        // Anything half run is dropped, the reset sequence takes 7 cycles
+        self.step = 0;
+        self.interrupt = false;
+        self.nmi_pending = false;
+        self.take_nmi = false;
+        self.irq_pending = false;
+        self.take_irq = false;
+        self.skip_poll = false;
+        self.dma = Dma::new();
+        self.stall = 7;
+        self.jammed = false;
+    }
+
+    /// Address and opcode of the KIL that stopped the cpu, None while it is running
+    pub fn jammed(&self) -> Option<(u16, u8)> {
+        self.jammed.then_some((self.pc, self.op))
+    }
+
+    /// Events since the last call, oldest first
+    pub fn take_events(&mut self) -> Vec<CpuEvent> {
+        std::mem::take(&mut self.events)
     }
 
     pub fn irq(&mut self) {
@@ -250,29 +430,6 @@ impl<'a> Cpu<'a> {
     }
 
     // Internal functions
-    fn interrupt(&mut self, addr: u16) {
-        // Push pc to stack
-        self.bus
-            .borrow_mut()
-            .write(0x100 + self.stp as u16, (self.pc >> 8) as u8);
-        self.stp = self.stp.wrapping_sub(1);
-
-        self.bus
-            .borrow_mut()
-            .write(0x100 + self.stp as u16, self.pc as u8);
-        self.stp = self.stp.wrapping_sub(1);
-
-        // Push the status register to stack 
-        self.bus
-            .borrow_mut()
-            .write(0x0100 + self.stp as u16, self.stat);
-        self.stp = self.stp.wrapping_sub(1);
-
-        // Move execution to the interrupt location
-        let pc_one = self.read(addr);
-        let pc_two = self.read(addr + 1);
-        self.pc = ((pc_two as u16) << 8) + pc_one as u16;
-    }
 
     // Set a bit in the status register
     fn set_flag(&mut self, flag: Flags, value: bool) {
@@ -295,326 +452,543 @@ impl<'a> Cpu<'a> {
         }
     }
 
-    //: set_address_mode {{{
-    // Different addressing modes will either give and address or an operand.
-    // To make things easier, we always return an address, either the direct address
-    // or one pointing to the specified operand. We also return any additional cycles taken.
-    fn set_address_mode(&mut self, opcode: u8) -> (u16, u8) {
-        let check_for_page_boundary: bool = (CYCLE_COUNTS[opcode as usize] & PBA) != 0x00;
-
-        let mut real_address: u16;
-        let mut cycle_addition: u8 = 0;
-        match ADDRESSING_MODE_LOOKUP[opcode as usize] {
-            // Absolute and debugging addressing modes
-            // Address is in the instruction
-            AddrM::ABS | AddrM::ADR => {
-                real_address = self.read_word_little(self.pc);
-
-                self.pc += 2;
-            }
-            // Absolute Indexed X
-            // PEEK(arg) + X
-            AddrM::AIX => {
-                real_address = self.read_word_little(self.pc);
+    // End of every cycle, sample the interrupt lines.
+    // NMI is edge triggered, it is remembered until handled even if the line drops again.
+    // IRQ is level triggered, the APU frame counter, DMC and mapper all hold the same line
+    // so it is only seen while one of them still wants it
+    fn poll_interrupts(&mut self) {
+        if self.jammed {
+            return;
+        }
+        let bus = self.bus.borrow();
 
-                real_address = real_address.wrapping_add(self.x as u16);
+        if self.skip_poll {
+            self.skip_poll = false;
+        } else {
+            self.take_nmi = self.nmi_pending;
+            self.take_irq = self.irq_pending;
+        }
 
-                if check_for_page_boundary && (real_address & 0xFF) < (self.x as u16) {
-                    cycle_addition += 1;
-                }
+        let nmi_line = bus.nmi_line();
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
 
-                self.pc += 2;
-            }
-            // Absolute Indexed Y
-            // PEEK(arg) + Y
-            AddrM::AIY => {
-                real_address = self.read_word_little(self.pc);
+        let irq_line = self.irq_siginal || bus.irq_signal();
+        self.irq_pending = irq_line && self.get_flag(Flags::ID) == 0;
+    }
 
-                real_address = real_address.wrapping_add(self.y as u16);
+    // Address the coming cycle reads, None if it writes. DMA can only halt the cpu on a
+    // read, so this has to agree with the cycle functions below
+    fn next_read(&self) -> Option<u16> {
+        let stack = 0x100 + self.stp as u16;
+        let pulled = 0x100 + self.stp.wrapping_add(1) as u16;
+        let step = self.step;
+        if step == 0 || self.jammed {
+            return Some(self.pc);
+        }
+        if step >= OPERAND_STEP {
+            return match kind(self.op) {
+                Kind::Read => Some(self.addr),
+                Kind::Write => None,
+                // Only the first cycle reads, then the value is written twice
+                Kind::Modify => (step == OPERAND_STEP).then_some(self.addr),
+            };
+        }
 
-                if check_for_page_boundary && (real_address & 0xFF) < (self.y as u16) {
-                    cycle_addition += 1;
-                }
+        match self.op {
+            0x00 => match step {
+                1 => Some(self.pc),
+                2..=4 => None,
+                5 => Some(self.addr),
+                _ => Some(self.addr + 1),
+            },
+            0x20 => match step {
+                1 | 5 => Some(self.pc),
+                2 => Some(stack),
+                _ => None,
+            },
+            0x40 | 0x28 | 0x68 => match step {
+                1 => Some(self.pc),
+                2 => Some(stack),
+                _ => Some(pulled),
+            },
+            0x60 => match step {
+                1 | 5 => Some(self.pc),
+                2 => Some(stack),
+                _ => Some(pulled),
+            },
+            0x08 | 0x48 => (step == 1).then_some(self.pc),
+            _ => match (&ADDRESSING_MODE_LOOKUP[self.op as usize], step) {
+                (AddrM::ZIX | AddrM::ZIY, 2) => Some(self.addr),
+                (AddrM::AIX | AddrM::AIY | AddrM::IND, 3) => Some(self.addr),
+                (AddrM::IND, 4) => Some((self.addr & 0xFF00) | (self.addr.wrapping_add(1) & 0xFF)),
+                (AddrM::IIX, 2 | 3) | (AddrM::IIY, 2) => Some(self.ptr as u16),
+                (AddrM::IIX, 4) | (AddrM::IIY, 3) => Some(self.ptr.wrapping_add(1) as u16),
+                (AddrM::IIY, 4) => Some(self.addr),
+                // Opcode operands, implied dummy reads and branches all read at pc
+                _ => Some(self.pc),
+            },
+        }
+    }
 
-                self.pc += 2;
-            }
-            // Immediate
-            // Opcode is in the instruction
-            AddrM::IMD => {
-                real_address = self.pc;
-                self.pc += 1;
+    //: Instruction cycles {{{
+    // First cycle, fetch an opcode or start handling an interrupt
+    fn start_instruction(&mut self) {
+        self.step = 1;
+        self.cycles = 1;
+
+        // The interrupt sequence doesn't poll, the handler always runs one instruction
+        // before another interrupt
+        let after_interrupt = self.op == 0x00;
+        if (self.take_nmi || self.take_irq) && !after_interrupt {
+            // Which vector is only decided once pc is pushed, see brk_step
+            self.begin_interrupt();
+        } else {
+            if self.bus.borrow().cpu_debug {
+                output_debug_info(self);
             }
-            // Indirect 
-            // Only used by JMP instruction. 
-            // PEEK(PEEK(arg))
-            AddrM::IND => {
-                let low_byte: u8 = self.read(self.pc);
-                let high_byte: u8 = self.read(self.pc + 1);
-                let effective_address: u16 =
-                    ((high_byte as u16) << 8).wrapping_add(low_byte as u16);
+            self.op = self.fetch();
+        }
+    }
 
-                real_address = self.read_word_little_wrap(effective_address);
+    // The fetched opcode is thrown away and a BRK runs in its place, without moving pc
+    fn begin_interrupt(&mut self) {
+        self.read(self.pc);
+        self.op = 0x00;
+        self.interrupt = true;
+    }
 
-                self.pc += 2;
-            }
-            // Indexed Indirect
-            // PEEK(PEEK(arg + X))
-            AddrM::IIX => {
-                let loc: u8 = self.read(self.pc);
-                let low_byte: u8 = self.read(loc.wrapping_add(self.x) as u16);
-                let high_byte: u8 = self.read((loc.wrapping_add(self.x)).wrapping_add(1) as u16);
-                real_address = ((high_byte as u16) << 8) + low_byte as u16;
-                //real_address = bus.read_word_little(effective_address);
+    // Last cycle of an instruction, the next clock starts another
+    fn finish(&mut self) {
+        // The timing table has no entry for interrupts or invalid opcodes
+        let timing = CYCLE_COUNTS[self.op as usize];
+        let base = timing & 0x0F;
+        let valid = !matches!(ADDRESSING_MODE_LOOKUP[self.op as usize], AddrM::NUL);
+        if !self.interrupt && valid && base != 0 {
+            let extra = if timing & BA != 0 {
+                2
+            } else if timing & PBA != 0 {
+                1
+            } else {
+                0
+            };
+            debug_assert!(
+                (base..=base + extra).contains(&self.cycles),
+                "Opcode {:02X} took {} cycles, CYCLE_COUNTS says {}",
+                self.op,
+                self.cycles,
+                base
+            );
+        }
+        self.step = 0;
+        self.interrupt = false;
+    }
+
+    // Every cycle after the opcode fetch
+    fn instruction_step(&mut self) {
+        self.cycles += 1;
+        if self.step >= OPERAND_STEP {
+            self.operand_step();
+            return;
+        }
 
-                self.pc += 1;
+        let step = self.step;
+        self.step += 1;
+        match self.op {
+            0x00 => self.brk_step(step),
+            0x20 => self.jsr_step(step),
+            0x40 => self.rti_step(step),
+            0x60 => self.rts_step(step),
+            0x08 | 0x48 => self.push_step(step),
+            0x28 | 0x68 => self.pull_step(step),
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+                self.jam()
             }
-            // Indirect Indexed
-            // PEEK(PEEK(arg) + Y)
-            AddrM::IIY => {
-                let loc: u8 = self.read(self.pc);
-                let low_byte: u8 = self.read(loc as u16);
-                let high_byte: u8 = self.read(loc.wrapping_add(1) as u16);
-                let effective_address: u16 = ((high_byte as u16) << 8) + low_byte as u16;
+            _ => self.address_step(step),
+        }
+    }
 
-                real_address = effective_address.wrapping_add(self.y as u16);
+    // *KIL, the cpu reads the next byte and then locks up. It stops fetching and ignores
+    // interrupts until reset
+    fn jam(&mut self) {
+        self.read(self.pc);
+        self.pc = self.pc.wrapping_sub(1);
+        self.jammed = true;
+        self.events.push(CpuEvent::Jammed {
+            pc: self.pc,
+            opcode: self.op,
+        });
+    }
 
-                if check_for_page_boundary && (real_address & 0xFF) < (self.y as u16) {
-                    cycle_addition += 1;
+    // BRK and interrupts, push pc and status then jump through the vector
+    fn brk_step(&mut self, step: u8) {
+        match step {
+            1 => {
+                // BRK skips the byte after it, interrupts leave pc alone
+                if self.interrupt {
+                    self.read(self.pc);
+                } else {
+                    self.fetch();
                 }
-
-                self.pc += 1;
             }
-            // Relative
-            // Offset used in branching instructions
-            AddrM::REL => {
-                real_address = self.pc;
-                self.pc += 1;
+            2 => self.push((self.pc >> 8) as u8),
+            3 => self.push(self.pc as u8),
+            4 => {
+                // The vector is picked now, an NMI seen during the first four cycles
+                // hijacks a BRK or IRQ and runs the NMI handler instead
+                self.addr = if self.nmi_pending {
+                    self.nmi_pending = false;
+                    NMI_VECTOR
+                } else {
+                    if self.interrupt {
+                        self.irq_siginal = false;
+                    }
+                    IRQ_VECTOR
+                };
+
+                // Only BRK pushes the B flag, even when hijacked
+                let mut stat = (self.stat & !(Flags::B1 as u8)) | (Flags::B2 as u8);
+                if !self.interrupt {
+                    stat |= Flags::B1 as u8;
+                }
+                self.push(stat);
             }
-            // Zero Page
-            // Value is in the zero page so we only specify the low order byte
-            AddrM::ZPG => {
-                real_address = self.read(self.pc) as u16;
-                self.pc += 1;
-            }
-            // Zero Page Indexed X
-            // PEEK(00_arg + X)
-            AddrM::ZIX => {
-                real_address = (self.read(self.pc) as u16).wrapping_add(self.x as u16);
-                real_address &= 0xFF;
-                self.pc += 1;
-            }
-            // Zero Page Indexed Y
-            // PEEK(00_arg + Y)
-            AddrM::ZIY => {
-                real_address = (self.read(self.pc) as u16).wrapping_add(self.y as u16);
-                real_address &= 0xFF;
-                self.pc += 1;
+            5 => {
+                self.data = self.read(self.addr);
+                self.set_flag(Flags::ID, true);
             }
-            // Accumulator and Implicit
-            // Value is implied or in the accumulator
             _ => {
-                // ACC / IMP
-                return (0, 0);
+                self.pc = ((self.read(self.addr + 1) as u16) << 8) | self.data as u16;
+                self.finish();
             }
         }
-        return (real_address, cycle_addition);
     }
-    //: }}}
 
-    //: Execute helpers {{{
-    // Common code used by branching instructions
-    fn branch(&mut self, real_address: u16) -> u8 {
-        // Note that the original 6502 does not correctly fetch target addresses if it
-        // falls on a page boundary when branching. We do not currently implement this bug, but perhaps we
-        // should.
-                
-        let old_pc = self.pc;
-        let mut offset: u8 = self.read(real_address);
-
-        if offset <= 0x7F {
-            // Positive offset (forwards)
-            self.pc = self.pc.wrapping_add(offset as u16);
-        } else {
-            // Negative offset (backwards)
-            // Undo two's complement and sub
-            offset = !offset;
-            offset += 1;
-            self.pc = self.pc.wrapping_sub(offset as u16);
+    // JSR, the return address pushed is the last byte of the JSR
+    fn jsr_step(&mut self, step: u8) {
+        match step {
+            1 => self.data = self.fetch(),
+            2 => {
+                // Internal cycle, the stack is read and ignored
+                self.read(0x100 + self.stp as u16);
+            }
+            3 => self.push((self.pc >> 8) as u8),
+            4 => self.push(self.pc as u8),
+            _ => {
+                self.pc = ((self.read(self.pc) as u16) << 8) | self.data as u16;
+                self.finish();
+            }
         }
+    }
 
-        if self.pc & 0xFF00 != old_pc & 0xFF00 {
-            // Page Boundary + Branch Taken Cycle additions
-            return 2; 
+    // RTI (Return from interrupt)
+    fn rti_step(&mut self, step: u8) {
+        match step {
+            1 => {
+                self.read(self.pc);
+            }
+            2 => {
+                self.read(0x100 + self.stp as u16);
+            }
+            3 => self.stat = self.pull() & 0b11101111 | (Flags::B2 as u8), // B flag
+            4 => self.data = self.pull(),
+            _ => {
+                self.pc = ((self.pull() as u16) << 8) | self.data as u16;
+                self.finish();
+            }
         }
-
-        // Branch Taken Cycle Addition
-        return 1; 
     }
-    //: }}}
 
-    //: execute {{{
-    // Execute an opcode given a real_address to the operand (real_address is like a pointer)
-    fn execute(&mut self, opcode: u8, real_address: u16) -> u8 {
-        let mut cycle_addition = 0;
-
-        // Note opcodes with a * are unofficial
-        match opcode {
-            0x0B | 0x2B => {
-                // *AAC (And And Copy)
-                self.a &= self.read(real_address);
-
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-                self.set_flag(Flags::CA, self.get_flag(Flags::NG) != 0);
+    // RTS (Return From Subroutine)
+    fn rts_step(&mut self, step: u8) {
+        match step {
+            1 => {
+                self.read(self.pc);
             }
-            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                // ADC (Add With Carry)
-                let read_val = self.read(real_address);
-                let tmp: u16 = (self.a as u16)
-                    .wrapping_add(read_val as u16)
-                    .wrapping_add(self.get_flag(Flags::CA) as u16);
-
-                // Overflow flag
-                self.set_flag(
-                    Flags::OV,
-                    ((self.a ^ read_val) & 0x80 == 0) && ((self.a ^ tmp as u8) & 0x80 == 0x80),
-                );
-
-                self.a = tmp as u8;
-
-                self.set_flag(Flags::CA, tmp > 0xFF);
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+            2 => {
+                self.read(0x100 + self.stp as u16);
             }
-            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                // AND (Logical AND)
-                self.a &= self.read(real_address);
-
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+            3 => self.data = self.pull(),
+            4 => self.pc = ((self.pull() as u16) << 8) | self.data as u16,
+            _ => {
+                // Step past the last byte of the JSR
+                self.read(self.pc);
+                self.pc = self.pc.wrapping_add(1);
+                self.finish();
             }
-            0x0A => {
-                // ASL (Arithmetic Shift Left) Accumulator
-                self.set_flag(Flags::CA, (self.a & 0x80) != 0);
+        }
+    }
 
-                self.a = self.a << 1;
+    // PHA (Push Accumulator) and PHP (Push Processor Status)
+    fn push_step(&mut self, step: u8) {
+        if step == 1 {
+            self.read(self.pc);
+            return;
+        }
+        let value = if self.op == 0x48 {
+            self.a
+        } else {
+            self.stat | (Flags::B1 as u8)
+        };
+        self.push(value);
+        self.finish();
+    }
 
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+    // PLA (Pull Accumulator) and PLP (Pull Processor Status)
+    fn pull_step(&mut self, step: u8) {
+        match step {
+            1 => {
+                self.read(self.pc);
             }
-            0x06 | 0x16 | 0x0E | 0x1E => {
-                // ASL (Arithmetic Shift Left)
-                let mut operand = self.read(real_address);
-                self.set_flag(Flags::CA, (operand & 0x80) != 0);
-
-                operand = operand << 1;
-
-                self.set_flag(Flags::ZE, operand == 0x00);
-                self.set_flag(Flags::NG, (operand & 0x80) != 0);
-
-                self.write(real_address, operand);
+            2 => {
+                self.read(0x100 + self.stp as u16);
             }
-            0x4B => {
-                // *ASR (And + Shift Right)
-                self.a &= self.read(real_address);
-                self.a = self.a >> 1;
-
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0); // Nintendulator is clever and just sets it to zero, but not me.
+            _ => {
+                let value = self.pull();
+                if self.op == 0x68 {
+                    self.a = value;
+                    self.set_flag(Flags::ZE, self.a == 0x00);
+                    self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                } else {
+                    self.stat = value & 0b11101111 | (Flags::B2 as u8);
+                }
+                self.finish();
             }
-            0x6B => {
-                // *ARR (And + Rotate)
-                self.a &= self.read(real_address);
-
-                let high_bit: u8 = self.get_flag(Flags::CA);
-                self.a = (self.a >> 1) + (high_bit << 7);
-
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+        }
+    }
+    //: }}}
 
-                self.set_flag(Flags::CA, ((self.a >> 6) & 1) != 0);
-                self.set_flag(
-                    Flags::OV,
-                    self.get_flag(Flags::CA) ^ ((self.a >> 5) & 1) != 0,
-                );
+    //: address_step {{{
+    // Cycles that work out the address of the operand. Once it is known the instruction
+    // moves on to operand_step, immediate operands need no extra cycle so they go straight
+    // there.
+    fn address_step(&mut self, step: u8) {
+        match ADDRESSING_MODE_LOOKUP[self.op as usize] {
+            // Accumulator and Implicit
+            // The byte after the opcode is read and ignored
+            AddrM::IMP | AddrM::ACC | AddrM::NUL => {
+                self.read(self.pc);
+                self.execute_implied();
+                self.finish();
             }
-            0xCB => {
-                // *AXS (A and X Subtract)
-                let tmp: u16 =
-                    ((self.a & self.x) as u16).wrapping_sub(self.read(real_address) as u16);
-                self.x = tmp as u8;
-
-                self.set_flag(Flags::CA, tmp <= 0xFF);
-                self.set_flag(Flags::ZE, self.x == 0x00);
-                self.set_flag(Flags::NG, (self.x & 0x80) != 0);
+            // Immediate
+            // Operand is in the instruction
+            AddrM::IMD => {
+                self.addr = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                self.start_operand();
             }
-            0x90 => {
-                // BCC (Branch if Carry Clear)
-                if self.get_flag(Flags::CA) == 0 {
-                    cycle_addition += self.branch(real_address);
+            // Zero Page
+            // Value is in the zero page so we only specify the low order byte
+            AddrM::ZPG => {
+                self.addr = self.fetch() as u16;
+                self.step = OPERAND_STEP;
+            }
+            // Zero Page Indexed X / Y
+            // The unindexed address is read while the index is added, it wraps in the zero page
+            AddrM::ZIX | AddrM::ZIY => match step {
+                1 => self.addr = self.fetch() as u16,
+                _ => {
+                    self.read(self.addr);
+                    let index = match ADDRESSING_MODE_LOOKUP[self.op as usize] {
+                        AddrM::ZIX => self.x,
+                        _ => self.y,
+                    };
+                    self.addr = (self.addr + index as u16) & 0xFF;
+                    self.step = OPERAND_STEP;
                 }
-            }
-            0xB0 => {
-                // BCS (Branch if Carry Set)
-                if self.get_flag(Flags::CA) != 0 {
-                    cycle_addition += self.branch(real_address);
+            },
+            // Absolute and debugging addressing modes
+            // Address is in the instruction, JMP is done once it has it
+            AddrM::ABS | AddrM::ADR => match step {
+                1 => self.addr = self.fetch() as u16,
+                _ => {
+                    self.addr |= (self.fetch() as u16) << 8;
+                    if self.op == 0x4C {
+                        self.pc = self.addr;
+                        self.finish();
+                    } else {
+                        self.step = OPERAND_STEP;
+                    }
                 }
-            }
-            0xF0 => {
-                // BEQ (Branch if Equal)
-                if self.get_flag(Flags::ZE) != 0 {
-                    cycle_addition += self.branch(real_address);
+            },
+            // Absolute Indexed X / Y
+            // PEEK(arg) + X
+            AddrM::AIX | AddrM::AIY => match step {
+                1 => self.addr = self.fetch() as u16,
+                2 => {
+                    self.addr |= (self.fetch() as u16) << 8;
+                    let index = match ADDRESSING_MODE_LOOKUP[self.op as usize] {
+                        AddrM::AIX => self.x,
+                        _ => self.y,
+                    };
+                    self.add_index(index);
                 }
-            }
-            0x24 | 0x2C => {
-                // BIT (Bit test)
-                let read_val = self.read(real_address);
-                self.set_flag(Flags::ZE, self.a & read_val == 0);
-                self.set_flag(Flags::OV, read_val & 0x70 != 0);
-                self.set_flag(Flags::NG, read_val & 0x80 != 0);
-            }
-            0x30 => {
-                // BMI (Branch if Minus)
-                if self.get_flag(Flags::NG) != 0 {
-                    cycle_addition += self.branch(real_address);
+                _ => self.index_fixup_step(),
+            },
+            // Indirect 
+            // Only used by JMP instruction. 
+            // PEEK(PEEK(arg))
+            AddrM::IND => match step {
+                1 => self.addr = self.fetch() as u16,
+                2 => self.addr |= (self.fetch() as u16) << 8,
+                3 => self.data = self.read(self.addr),
+                _ => {
+                    // The high byte comes from the same page as the low byte
+                    let high = self.read((self.addr & 0xFF00) | (self.addr.wrapping_add(1) & 0xFF));
+                    self.pc = ((high as u16) << 8) | self.data as u16;
+                    self.finish();
                 }
-            }
-            0xD0 => {
-                // BNE (Branch if Not Equal)
-                if self.get_flag(Flags::ZE) == 0 {
-                    cycle_addition += self.branch(real_address);
+            },
+            // Indexed Indirect
+            // PEEK(PEEK(arg + X))
+            AddrM::IIX => match step {
+                1 => self.ptr = self.fetch(),
+                2 => {
+                    self.read(self.ptr as u16);
+                    self.ptr = self.ptr.wrapping_add(self.x);
                 }
-            }
-            0x10 => {
-                // BPL (Branch on Plus)
-                if self.get_flag(Flags::NG) == 0 {
-                    cycle_addition += self.branch(real_address);
+                3 => self.addr = self.read(self.ptr as u16) as u16,
+                _ => {
+                    self.addr |= (self.read(self.ptr.wrapping_add(1) as u16) as u16) << 8;
+                    self.step = OPERAND_STEP;
                 }
-            }
-            0x00 => {
-                // BRK (Force Interrupt)
-                self.write(0x0100 + self.stp as u16, (self.pc >> 8) as u8);
-                self.stp = self.stp.wrapping_sub(1);
+            },
+            // Indirect Indexed
+            // PEEK(PEEK(arg) + Y)
+            AddrM::IIY => match step {
+                1 => self.ptr = self.fetch(),
+                2 => self.addr = self.read(self.ptr as u16) as u16,
+                3 => {
+                    self.addr |= (self.read(self.ptr.wrapping_add(1) as u16) as u16) << 8;
+                    self.add_index(self.y);
+                }
+                _ => self.index_fixup_step(),
+            },
+            // Relative
+            // Offset used in branching instructions
+            AddrM::REL => self.branch_step(step),
+        }
+    }
 
-                self.write(0x0100 + self.stp as u16, self.pc as u8);
-                self.stp = self.stp.wrapping_sub(1);
+    // Add an index to the low byte of addr, crossed remembers the carry for the high byte
+    fn add_index(&mut self, index: u8) {
+        let (low, crossed) = (self.addr as u8).overflowing_add(index);
+        self.addr = (self.addr & 0xFF00) | low as u16;
+        self.crossed = crossed;
+    }
 
-                self.write(0x0100 + self.stp as u16, self.stat);
-                self.stp = self.stp.wrapping_sub(1);
-                self.set_flag(Flags::ID, true);
-                self.pc = IRQ_VECTOR;
+    // Indexed modes read from the address before the carry reaches the high byte. If
+    // there was no carry a read is done, anything else reads again from the fixed address
+    fn index_fixup_step(&mut self) {
+        if !self.crossed && kind(self.op) == Kind::Read {
+            self.start_operand();
+            return;
+        }
+        self.read(self.addr);
+        if self.crossed {
+            self.addr = self.addr.wrapping_add(0x100);
+        }
+        self.step = OPERAND_STEP;
+    }
+
+    // Branches take 2 cycles, 3 if taken and 4 if pc moves to another page.
+    // Note that the original 6502 reads from the wrong page before fixing the high byte
+    fn branch_step(&mut self, step: u8) {
+        match step {
+            1 => {
+                self.data = self.fetch();
+                if !self.branch_taken() {
+                    self.finish();
+                }
             }
-            0x50 => {
-                // BVC (Branch if Overflow Clear)
-                if self.get_flag(Flags::OV) == 0 {
-                    cycle_addition += self.branch(real_address);
+            2 => {
+                self.read(self.pc);
+                self.addr = self.pc.wrapping_add(self.data as i8 as u16);
+                if self.addr & 0xFF00 == self.pc & 0xFF00 {
+                    // A taken branch that stays on the page doesn't poll interrupts on its
+                    // last cycle, an IRQ or NMI that shows up during it waits an extra
+                    // instruction
+                    self.skip_poll = true;
+                    self.pc = self.addr;
+                    self.finish();
+                } else {
+                    self.pc = (self.pc & 0xFF00) | (self.addr & 0xFF);
                 }
             }
-            0x70 => {
-                // BVS (Branch if Overflow Set)
-                if self.get_flag(Flags::OV) != 0 {
-                    cycle_addition += self.branch(real_address);
+            _ => {
+                self.read(self.pc);
+                self.pc = self.addr;
+                self.finish();
+            }
+        }
+    }
+
+    fn branch_taken(&self) -> bool {
+        match self.op {
+            0x10 => self.get_flag(Flags::NG) == 0, // BPL (Branch on Plus)
+            0x30 => self.get_flag(Flags::NG) != 0, // BMI (Branch if Minus)
+            0x50 => self.get_flag(Flags::OV) == 0, // BVC (Branch if Overflow Clear)
+            0x70 => self.get_flag(Flags::OV) != 0, // BVS (Branch if Overflow Set)
+            0x90 => self.get_flag(Flags::CA) == 0, // BCC (Branch if Carry Clear)
+            0xB0 => self.get_flag(Flags::CA) != 0, // BCS (Branch if Carry Set)
+            0xD0 => self.get_flag(Flags::ZE) == 0, // BNE (Branch if Not Equal)
+            0xF0 => self.get_flag(Flags::ZE) != 0, // BEQ (Branch if Equal)
+            _ => false,
+        }
+    }
+
+    fn start_operand(&mut self) {
+        self.step = OPERAND_STEP;
+        self.operand_step();
+    }
+
+    // Cycles once the address is known
+    fn operand_step(&mut self) {
+        let step = self.step - OPERAND_STEP;
+        self.step += 1;
+        match kind(self.op) {
+            Kind::Read => {
+                let value = self.read(self.addr);
+                self.execute_read(value);
+                self.finish();
+            }
+            Kind::Write => {
+                let value = self.store_value();
+                self.write(self.addr, value);
+                self.finish();
+            }
+            // The old value is written back while the new one is worked out
+            Kind::Modify => match step {
+                0 => self.data = self.read(self.addr),
+                1 => {
+                    self.write(self.addr, self.data);
+                    self.data = self.execute_modify(self.data);
+                }
+                _ => {
+                    self.write(self.addr, self.data);
+                    self.finish();
                 }
+            },
+        }
+    }
+    //: }}}
+
+    //: execute_implied {{{
+    // Instructions without an operand, they only touch registers
+    fn execute_implied(&mut self) {
+        // Note opcodes with a * are unofficial
+        match self.op {
+            0x0A => {
+                // ASL (Arithmetic Shift Left) Accumulator
+                self.set_flag(Flags::CA, (self.a & 0x80) != 0);
+
+                self.a = self.a << 1;
+
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
             0x18 => {
                 // CLC (Clear Carry Flag)
@@ -632,51 +1006,6 @@ impl<'a> Cpu<'a> {
                 // CLV (Clear Overflow Flag)
                 self.set_flag(Flags::OV, false);
             }
-            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
-                // CMP (Compare Accumulator)
-                let m: u8 = self.read(real_address);
-                let res: u8 = self.a.wrapping_sub(m);
-                self.set_flag(Flags::CA, self.a >= m);
-                self.set_flag(Flags::ZE, self.a == m);
-                self.set_flag(Flags::NG, (res & 0x80) != 0);
-            }
-            0xE0 | 0xE4 | 0xEC => {
-                // CPX (Compare X Register)
-                let m = self.read(real_address);
-                let res: u8 = self.x.wrapping_sub(m);
-                self.set_flag(Flags::CA, self.x >= m);
-                self.set_flag(Flags::ZE, self.x == m);
-                self.set_flag(Flags::NG, (res & 0x80) != 0);
-            }
-            0xC0 | 0xC4 | 0xCC => {
-                // CPY (Compare Y Register)
-                let m = self.read(real_address);
-                let res: u8 = self.y.wrapping_sub(m);
-                self.set_flag(Flags::CA, self.y >= m);
-                self.set_flag(Flags::ZE, self.y == m);
-                self.set_flag(Flags::NG, (res & 0x80) != 0);
-            }
-            0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => {
-                // *DCP (Decrement + Compare)
-                let m: u8 = self.read(real_address);
-                let tmp_res: u8 = m.wrapping_sub(1);
-                self.write(real_address, tmp_res);
-
-                let res: u8 = self.a.wrapping_sub(tmp_res);
-                self.set_flag(Flags::CA, self.a >= res);
-                self.set_flag(Flags::ZE, res == 0);
-                self.set_flag(Flags::NG, (res & 0x80) != 0);
-            }
-            0xC6 | 0xD6 | 0xCE | 0xDE => {
-                // DEC (Decrement Memory)
-                let m: u8 = self.read(real_address);
-                let res: u8 = m.wrapping_sub(1);
-
-                self.write(real_address, res);
-
-                self.set_flag(Flags::ZE, res == 0);
-                self.set_flag(Flags::NG, (res & 0x80) != 0);
-            }
             0xCA => {
                 // DEX (Decrement X Register)
                 self.x = self.x.wrapping_sub(1);
@@ -691,24 +1020,6 @@ impl<'a> Cpu<'a> {
                 self.set_flag(Flags::ZE, self.y == 0);
                 self.set_flag(Flags::NG, (self.y & 0x80) != 0);
             }
-            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
-                // EOR (Exclusive OR)
-                let m: u8 = self.read(real_address);
-
-                self.a ^= m;
-                self.set_flag(Flags::ZE, self.a == 0);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
-            0xE6 | 0xF6 | 0xEE | 0xFE => {
-                // INC (Increment Memory)
-                let m: u8 = self.read(real_address);
-                let res: u8 = m.wrapping_add(1);
-
-                self.write(real_address, res);
-
-                self.set_flag(Flags::ZE, res == 0);
-                self.set_flag(Flags::NG, (res & 0x80) != 0);
-            }
             0xE8 => {
                 // INX (Increment X Register)
                 self.x = self.x.wrapping_add(1);
@@ -723,365 +1034,708 @@ impl<'a> Cpu<'a> {
                 self.set_flag(Flags::ZE, self.y == 0);
                 self.set_flag(Flags::NG, (self.y & 0x80) != 0);
             }
+            0x4A => {
+                // LSR (Logical Shift Right) for Accumulator
+                self.set_flag(Flags::CA, (self.a & 0x01) != 0);
 
-            0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF => {
-                // *ISB (Increment + Subtract)
-                let m: u8 = self.read(real_address);
-                let res: u8 = m.wrapping_add(1);
+                self.a >>= 1;
 
-                self.write(real_address, res);
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+            }
+            0x2A => {
+                // ROL (Rotate Left) for accumulator
+                let low_bit: u8 = self.get_flag(Flags::CA);
+                self.set_flag(Flags::CA, (self.a & 0x80) != 0);
 
-                let tmp: u16 = (self.a as u16)
-                    .wrapping_sub(res as u16)
-                    .wrapping_sub((1 as u16).wrapping_sub(self.get_flag(Flags::CA) as u16));
+                self.a = (self.a << 1) + low_bit;
 
-                self.set_flag(
-                    Flags::OV,
-                    (((self.a as u16) ^ tmp) & ((!self.read(real_address) as u16) ^ tmp) & 0x80)
-                        != 0x00,
-                );
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+            }
+            0x6A => {
+                // ROR (Rotate Right) for accumulator
+                let high_bit: u8 = self.get_flag(Flags::CA);
+                self.set_flag(Flags::CA, (self.a & 0x01) != 0);
 
-                self.a = tmp as u8;
+                self.a = (self.a >> 1) + (high_bit << 7);
 
-                self.set_flag(Flags::CA, tmp <= 0xFF);
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-
-            0x4C | 0x6C => {
-                // JMP (Jump)
-                self.pc = real_address;
+            0x38 => {
+                // SEC (Set Carry)
+                self.set_flag(Flags::CA, true);
+            }
+            0xF8 => {
+                // SED (Set Decimal)
+                self.set_flag(Flags::DC, true);
+            }
+            0x78 => {
+                // SEI (Set Interrupt)
+                self.set_flag(Flags::ID, true);
+            }
+            0xAA => {
+                // TAX (Transfer A to X)
+                self.x = self.a;
+                self.set_flag(Flags::ZE, self.x == 0x00);
+                self.set_flag(Flags::NG, (self.x & 0x80) != 0);
+            }
+            0xA8 => {
+                // TAY (Transfer A to Y)
+                self.y = self.a;
+                self.set_flag(Flags::ZE, self.y == 0x00);
+                self.set_flag(Flags::NG, (self.y & 0x80) != 0);
+            }
+            0xBA => {
+                // TSX (Transfer Stack Pointer to X)
+                self.x = self.stp;
+                self.set_flag(Flags::ZE, self.x == 0x00);
+                self.set_flag(Flags::NG, (self.x & 0x80) != 0);
+            }
+            0x8A => {
+                // TXA (Transfer X to A)
+                self.a = self.x;
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+            }
+            0x9A => {
+                // TXS (Transfer X to Stack Pointer)
+                self.stp = self.x;
+            }
+            0x98 => {
+                // TYA (Transfer Y to A)
+                self.a = self.y;
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-            0x20 => {
-                // JSR (Jump to Subroutine)
-                let return_point: u16 = self.pc - 1;
+            _ => {} // NOP, and anything invalid is treated as one
+        }
+    }
+    //: }}}
 
-                self.write(0x100 + self.stp as u16, (return_point >> 8) as u8);
-                self.stp = self.stp.wrapping_sub(1);
+    //: execute_read {{{
+    // Instructions that read an operand, value is the byte read
+    fn execute_read(&mut self, value: u8) {
+        match self.op {
+            0x0B | 0x2B => {
+                // *AAC (And And Copy)
+                self.a &= value;
 
-                self.write(0x100 + self.stp as u16, return_point as u8);
-                self.stp = self.stp.wrapping_sub(1);
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                self.set_flag(Flags::CA, self.get_flag(Flags::NG) != 0);
+            }
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                // ADC (Add With Carry)
+                self.add_with_carry(value);
+            }
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                // AND (Logical AND)
+                self.a &= value;
 
-                self.pc = real_address;
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
+            0x4B => {
+                // *ASR (And + Shift Right)
+                self.a &= value;
+                self.a >>= 1;
 
-            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
-                // *KIL Crash
-                println!("Crash (KIL Opcode) Encountered. Ignoring");
-                // std::process::exit(0);
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0); // Nintendulator is clever and just sets it to zero, but not me.
             }
+            0x6B => {
+                // *ARR (And + Rotate)
+                self.a &= value;
 
+                let high_bit: u8 = self.get_flag(Flags::CA);
+                self.a = (self.a >> 1) + (high_bit << 7);
+
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+
+                self.set_flag(Flags::CA, ((self.a >> 6) & 1) != 0);
+                self.set_flag(
+                    Flags::OV,
+                    self.get_flag(Flags::CA) ^ ((self.a >> 5) & 1) != 0,
+                );
+            }
+            0xCB => {
+                // *AXS (A and X Subtract)
+                let tmp: u16 = ((self.a & self.x) as u16).wrapping_sub(value as u16);
+                self.x = tmp as u8;
+
+                self.set_flag(Flags::CA, tmp <= 0xFF);
+                self.set_flag(Flags::ZE, self.x == 0x00);
+                self.set_flag(Flags::NG, (self.x & 0x80) != 0);
+            }
+            0x24 | 0x2C => {
+                // BIT (Bit test)
+                self.set_flag(Flags::ZE, self.a & value == 0);
+                self.set_flag(Flags::OV, value & 0x70 != 0);
+                self.set_flag(Flags::NG, value & 0x80 != 0);
+            }
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
+                // CMP (Compare Accumulator)
+                let res: u8 = self.a.wrapping_sub(value);
+                self.set_flag(Flags::CA, self.a >= value);
+                self.set_flag(Flags::ZE, self.a == value);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+            }
+            0xE0 | 0xE4 | 0xEC => {
+                // CPX (Compare X Register)
+                let res: u8 = self.x.wrapping_sub(value);
+                self.set_flag(Flags::CA, self.x >= value);
+                self.set_flag(Flags::ZE, self.x == value);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+            }
+            0xC0 | 0xC4 | 0xCC => {
+                // CPY (Compare Y Register)
+                let res: u8 = self.y.wrapping_sub(value);
+                self.set_flag(Flags::CA, self.y >= value);
+                self.set_flag(Flags::ZE, self.y == value);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+            }
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
+                // EOR (Exclusive OR)
+                self.a ^= value;
+                self.set_flag(Flags::ZE, self.a == 0);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+            }
             0xA3 | 0xA7 | 0xAF | 0xB3 | 0xB7 | 0xBF => {
                 // LAX (Load Accumulator and X)
-                self.a = self.read(real_address);
+                self.a = value;
                 self.x = self.a;
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-
             0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
                 // LDA (Load Accumulator)
-                self.a = self.read(real_address);
+                self.a = value;
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-
             0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
                 // LDX (Load X)
-                self.x = self.read(real_address);
+                self.x = value;
                 self.set_flag(Flags::ZE, self.x == 0x00);
                 self.set_flag(Flags::NG, (self.x & 0x80) != 0);
             }
-
             0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
                 // LDY (Load Y)
-                self.y = self.read(real_address);
+                self.y = value;
                 self.set_flag(Flags::ZE, self.y == 0x00);
                 self.set_flag(Flags::NG, (self.y & 0x80) != 0);
             }
-
-            0x4A => {
-                // LSR (Logical Shift Right) for Accumulator
-                self.set_flag(Flags::CA, (self.a & 0x01) != 0);
-
-                self.a = self.a >> 1;
-
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
+                // ORA (Or Memory with Accumulator)
+                self.a |= value;
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-            0x46 | 0x56 | 0x4E | 0x5E => {
-                // LSR (Logical Shift Right) for Memory
-                let mut operand = self.read(real_address);
-
-                self.set_flag(Flags::CA, (operand & 0x01) != 0);
-
-                operand = operand >> 1;
-
-                self.write(real_address, operand);
-
-                self.set_flag(Flags::ZE, operand == 0x00);
-                self.set_flag(Flags::NG, (operand & 0x80) != 0);
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 | 0xEB => {
+                // SBC (Subtract with carry)
+                self.subtract_with_carry(value);
             }
-            0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA | 0x80 | 0x82 | 0x89 | 0xC2 | 0xE2
-            | 0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC | 0x04 | 0x44 | 0x64 | 0x14 | 0x34
-            | 0x54 | 0x74 | 0xD4 | 0xF4 => { // NOP (No Operation)
+            0x8B => {
+                // *XAA (Transfer X to A then AND), unstable on real chips
+                self.a = (self.a | UNSTABLE_MAGIC) & self.x & value;
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
-                // ORA (Or Memory with Accumulator)
-                self.a |= self.read(real_address);
+            0xAB => {
+                // *LXA (Load A and X), unstable on real chips
+                self.a = (self.a | UNSTABLE_MAGIC) & value;
+                self.x = self.a;
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
             }
-            0x48 => {
-                // PHA (Push Accumulator)
-                self.write(0x100 + self.stp as u16, self.a);
-                self.stp = self.stp.wrapping_sub(1);
+            0xBB => {
+                // *LAS (Load A, X and Stack pointer)
+                let res = value & self.stp;
+                self.a = res;
+                self.x = res;
+                self.stp = res;
+                self.set_flag(Flags::ZE, res == 0x00);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
             }
-            0x08 => {
-                // PHP (Push Processor Status)
-                self.write(0x100 + self.stp as u16, self.stat | (Flags::B1 as u8));
-                self.stp = self.stp.wrapping_sub(1);
+            _ => {} // NOP with an operand, the read still happens
+        }
+    }
+
+    fn add_with_carry(&mut self, value: u8) {
+        let tmp: u16 = (self.a as u16)
+            .wrapping_add(value as u16)
+            .wrapping_add(self.get_flag(Flags::CA) as u16);
+
+        // Overflow flag
+        self.set_flag(
+            Flags::OV,
+            ((self.a ^ value) & 0x80 == 0) && ((self.a ^ tmp as u8) & 0x80 == 0x80),
+        );
+
+        self.a = tmp as u8;
+
+        self.set_flag(Flags::CA, tmp > 0xFF);
+        self.set_flag(Flags::ZE, self.a == 0x00);
+        self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+    }
+
+    fn subtract_with_carry(&mut self, value: u8) {
+        let tmp: u16 = (self.a as u16)
+            .wrapping_sub(value as u16)
+            .wrapping_sub(1u16.wrapping_sub(self.get_flag(Flags::CA) as u16));
+
+        self.set_flag(
+            Flags::OV,
+            (((self.a as u16) ^ tmp) & ((!value as u16) ^ tmp) & 0x80) != 0x00,
+        );
+
+        self.a = tmp as u8;
+
+        self.set_flag(Flags::CA, tmp <= 0xFF);
+        self.set_flag(Flags::ZE, self.a == 0x00);
+        self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+    }
+    //: }}}
+
+    //: execute_modify {{{
+    // Read-modify-write instructions, returns the value written back
+    fn execute_modify(&mut self, value: u8) -> u8 {
+        match self.op {
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                // ASL (Arithmetic Shift Left)
+                self.set_flag(Flags::CA, (value & 0x80) != 0);
+
+                let res = value << 1;
+
+                self.set_flag(Flags::ZE, res == 0x00);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+                res
             }
-            0x68 => {
-                // PLA (Pull Accumulator)
-                self.stp = self.stp.wrapping_add(1);
-                self.a = self.read(0x100 + self.stp as u16);
+            0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => {
+                // *DCP (Decrement + Compare)
+                let tmp_res: u8 = value.wrapping_sub(1);
 
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                let res: u8 = self.a.wrapping_sub(tmp_res);
+                self.set_flag(Flags::CA, self.a >= res);
+                self.set_flag(Flags::ZE, res == 0);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+                tmp_res
+            }
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                // DEC (Decrement Memory)
+                let res: u8 = value.wrapping_sub(1);
+
+                self.set_flag(Flags::ZE, res == 0);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+                res
             }
-            0x28 => {
-                // PLP (Pull Processor Status)
-                self.stp = self.stp.wrapping_add(1);
-                self.stat = self.read(0x0100 + self.stp as u16) & 0b11101111 | (Flags::B2 as u8);
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                // INC (Increment Memory)
+                let res: u8 = value.wrapping_add(1);
+
+                self.set_flag(Flags::ZE, res == 0);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+                res
             }
+            0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF => {
+                // *ISB (Increment + Subtract)
+                let res: u8 = value.wrapping_add(1);
+                self.subtract_with_carry(res);
+                res
+            }
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                // LSR (Logical Shift Right) for Memory
+                self.set_flag(Flags::CA, (value & 0x01) != 0);
 
+                let res = value >> 1;
+
+                self.set_flag(Flags::ZE, res == 0x00);
+                self.set_flag(Flags::NG, (res & 0x80) != 0);
+                res
+            }
             0x23 | 0x27 | 0x2F | 0x33 | 0x37 | 0x3B | 0x3F => {
                 // *RLA (ROL + AND)
                 let low_bit: u8 = self.get_flag(Flags::CA);
-                let read_val = self.read(real_address);
-                self.set_flag(Flags::CA, (read_val & 0x80) != 0);
+                self.set_flag(Flags::CA, (value & 0x80) != 0);
 
-                let tmp: u8 = (read_val << 1) + low_bit;
+                let tmp: u8 = (value << 1) + low_bit;
 
                 self.a &= tmp;
 
-                self.write(real_address, tmp);
-
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                tmp
             }
-
             0x26 | 0x36 | 0x2E | 0x3E => {
                 // ROL (Rotate Left)
                 let low_bit: u8 = self.get_flag(Flags::CA);
-                let read_val = self.read(real_address);
-                self.set_flag(Flags::CA, (read_val & 0x80) != 0);
+                self.set_flag(Flags::CA, (value & 0x80) != 0);
 
-                let tmp: u8 = (read_val << 1) + low_bit;
-                self.write(real_address, tmp);
+                let tmp: u8 = (value << 1) + low_bit;
 
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (tmp & 0x80) != 0);
+                tmp
             }
-            0x2A => {
-                // ROL (Rotate Left) for accumulator
-                let low_bit: u8 = self.get_flag(Flags::CA);
-                self.set_flag(Flags::CA, (self.a & 0x80) != 0);
-
-                self.a = (self.a << 1) + low_bit;
-
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
-
             0x66 | 0x76 | 0x6E | 0x7E => {
                 // ROR (Rotate Right)
                 let high_bit: u8 = self.get_flag(Flags::CA);
-                let read_val = self.read(real_address);
-                self.set_flag(Flags::CA, (read_val & 0x01) != 0);
+                self.set_flag(Flags::CA, (value & 0x01) != 0);
 
-                let tmp: u8 = (read_val >> 1) + (high_bit << 7);
-                self.write(real_address, tmp);
+                let tmp: u8 = (value >> 1) + (high_bit << 7);
 
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (tmp & 0x80) != 0);
+                tmp
             }
-            0x6A => {
-                // ROR (Rotate Right) for accumulator
+            0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => {
+                // *RRA (ROR + ADC)
                 let high_bit: u8 = self.get_flag(Flags::CA);
-                self.set_flag(Flags::CA, (self.a & 0x01) != 0);
+                self.set_flag(Flags::CA, (value & 0x01) != 0);
 
-                self.a = (self.a >> 1) + (high_bit << 7);
-
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                let tmp: u8 = (value >> 1) + (high_bit << 7);
+                self.add_with_carry(tmp);
+                tmp
             }
+            0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => {
+                // *SLO (ASL + ORA)
+                self.set_flag(Flags::CA, (value & 0x80) != 0);
 
-            0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => {
-                // *RRA (ROR + ADC)
-                let high_bit: u8 = self.get_flag(Flags::CA);
-                let read_val = self.read(real_address);
-                self.set_flag(Flags::CA, (read_val & 0x01) != 0);
+                let res = value << 1;
 
-                let tmp: u8 = (read_val >> 1) + (high_bit << 7);
-                self.write(real_address, tmp);
+                self.a |= res;
 
-                let sum: u16 = (self.a as u16)
-                    .wrapping_add(tmp as u16)
-                    .wrapping_add(self.get_flag(Flags::CA) as u16);
+                self.set_flag(Flags::ZE, self.a == 0x00);
+                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                res
+            }
+            0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => {
+                // *SRE (LSR + EOR)
+                self.set_flag(Flags::CA, (value & 0x01) != 0);
 
-                self.set_flag(
-                    Flags::OV,
-                    ((self.a ^ self.read(real_address)) & 0x80 == 0)
-                        && ((self.a ^ sum as u8) & 0x80 == 0x80),
-                );
+                let res = value >> 1;
 
-                self.a = sum as u8;
+                self.a ^= res;
 
-                self.set_flag(Flags::CA, sum > 0xFF);
                 self.set_flag(Flags::ZE, self.a == 0x00);
                 self.set_flag(Flags::NG, (self.a & 0x80) != 0);
+                res
             }
+            _ => value,
+        }
+    }
+    //: }}}
 
-            0x40 => {
-                // RTI (Return from interrupt)
-                self.stp = self.stp.wrapping_add(1);
-                self.stat = self.read(0x0100 + self.stp as u16) & 0b11101111 | (Flags::B2 as u8); // B flag
+    //: store_value {{{
+    // Value a store instruction writes
+    fn store_value(&mut self) -> u8 {
+        match self.op {
+            0x86 | 0x96 | 0x8E => self.x,          // STX (Store X)
+            0x84 | 0x94 | 0x8C => self.y,          // STY (Store Y)
+            0x83 | 0x87 | 0x8F | 0x97 => self.a & self.x, // *SAX (Store A and X)
+            0x93 | 0x9F => self.unstable_store(self.a & self.x), // *SHA (Store A and X and High)
+            0x9E => self.unstable_store(self.x),   // *SHX (Store X and High)
+            0x9C => self.unstable_store(self.y),   // *SHY (Store Y and High)
+            0x9B => {
+                // *TAS (Transfer A and X to Stack, then store like SHA)
+                self.stp = self.a & self.x;
+                self.unstable_store(self.stp)
+            }
+            _ => self.a,                           // STA (Store A)
+        }
+    }
 
-                self.stp = self.stp.wrapping_add(1);
-                let stack_one = self.read(0x0100 + self.stp as u16);
+    // The SH* stores AND the value with the high byte of the base address plus one. When
+    // indexing crosses a page the high byte of the address gets that value too
+    fn unstable_store(&mut self, value: u8) -> u8 {
+        let base_high = ((self.addr >> 8) as u8).wrapping_sub(self.crossed as u8);
+        let value = value & base_high.wrapping_add(1);
+        if self.crossed {
+            self.addr = (self.addr & 0xFF) | ((value as u16) << 8);
+        }
+        value
+    }
+    //: }}}
+}
+//: }}}
 
-                self.stp = self.stp.wrapping_add(1);
-                let stack_two = self.read(0x0100 + self.stp as u16);
+//: Test cpu {{{
+// Cpu on an NROM board running code from $8000, the NMI handler is at $9000 and the IRQ
+// handler at $9100. Both handlers spin, the pushed return address shows where the cpu was
+#[cfg(test)]
+pub fn test_prg(code: &[u8]) -> Vec<u8> {
+    let mut prg = vec![0xEA; 0x8000];
+    prg[..code.len()].copy_from_slice(code);
+    prg[0x1000..0x1003].copy_from_slice(&[0x4C, 0x00, 0x90]);
+    prg[0x1100..0x1103].copy_from_slice(&[0x4C, 0x00, 0x91]);
+    prg[0x7FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0x91]);
+    prg
+}
 
-                self.pc = ((stack_two as u16) << 8) + stack_one as u16;
-            }
-            0x60 => {
-                // RTS (Return From Subroutine)
-                self.stp = self.stp.wrapping_add(1);
-                let stack_one = self.read(0x0100 + self.stp as u16);
-                self.stp = self.stp.wrapping_add(1);
-                let stack_two = self.read(0x0100 + self.stp as u16);
+#[cfg(test)]
+pub fn test_cpu(code: &[u8]) -> Cpu {
+    let prg = test_prg(code);
+    let bus = Rc::new(RefCell::new(crate::mapper::test_bus(0, &prg, &[])));
+    let mut cpu = Cpu::new(bus);
+    cpu.reset();
+    cpu
+}
 
-                self.pc = ((stack_two as u16) << 8) + stack_one as u16 + 1;
-            }
-            0x83 | 0x87 | 0x8F | 0x97 => {
-                // *SAX (Store A and X)
-                self.write(real_address, self.a & self.x);
+#[cfg(test)]
+impl Cpu {
+    // One cpu cycle, with the apu and mapper clocked alongside like Nes::tick
+    pub fn test_clock(&mut self) {
+        self.clock();
+        self.bus.borrow_mut().clock();
+    }
+
+    // Clock until the instruction at pc is about to start
+    pub fn run_to(&mut self, pc: u16) {
+        for _ in 0..100000 {
+            if self.at_boundary() && self.pc == pc {
+                return;
             }
-            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 | 0xEB => {
-                // SBC (Subtract with carry)
-                let read_val = self.read(real_address);
-                let tmp: u16 = (self.a as u16)
-                    .wrapping_sub(read_val as u16)
-                    .wrapping_sub((1 as u16).wrapping_sub(self.get_flag(Flags::CA) as u16));
+            self.test_clock();
+        }
+        panic!("cpu never got to {pc:04X}");
+    }
+}
+//: }}}
 
-                self.set_flag(
-                    Flags::OV,
-                    (((self.a as u16) ^ tmp) & ((!read_val as u16) ^ tmp) & 0x80) != 0x00,
-                );
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // How an interrupt played out: the handler the cpu went to, and the pc and status it
+    // pushed
+    #[derive(Debug, PartialEq)]
+    struct Taken {
+        handler: u16,
+        pc: u16,
+        stat: u8,
+    }
 
-                self.a = tmp as u8;
+    // Start the instruction at pc, run cycles of it, then raise an interrupt line
+    fn interrupt_after(code: &[u8], pc: u16, cycles: usize, nmi: bool) -> Taken {
+        let mut cpu = test_cpu(code);
+        cpu.bus.borrow_mut().write(0x2000, 0x80);
+        cpu.run_to(pc);
+        for _ in 0..cycles {
+            cpu.test_clock();
+        }
+        if nmi {
+            cpu.bus.borrow_mut().ppu_data.nmi_occurred = true;
+        } else {
+            cpu.irq_siginal = true;
+        }
 
-                self.set_flag(Flags::CA, tmp <= 0xFF);
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
-            0x38 => {
-                // SEC (Set Carry)
-                self.set_flag(Flags::CA, true);
-            }
-            0xF8 => {
-                // SED (Set Decimal)
-                self.set_flag(Flags::DC, true);
+        for _ in 0..100 {
+            cpu.test_clock();
+            if cpu.at_boundary() && (cpu.pc == 0x9000 || cpu.pc == 0x9100) {
+                let sp = 0x100 + cpu.stp as u16;
+                let mut bus = cpu.bus.borrow_mut();
+                return Taken {
+                    handler: cpu.pc,
+                    pc: u16::from_le_bytes([bus.read(sp + 2, true), bus.read(sp + 3, true)]),
+                    stat: bus.read(sp + 1, true),
+                };
             }
-            0x78 => {
-                // SEI (Set Interrupt)
-                self.set_flag(Flags::ID, true);
-            }
-            0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => {
-                // *SLO (ASL + ORA)
-                let mut operand = self.read(real_address);
+        }
+        panic!("interrupt was never taken");
+    }
 
-                self.set_flag(Flags::CA, (operand & 0x80) != 0);
+    fn irq_return(code: &[u8], pc: u16, cycles: usize) -> u16 {
+        interrupt_after(code, pc, cycles, false).pc
+    }
 
-                operand = operand << 1;
-                self.write(real_address, operand);
+    fn nmi_return(code: &[u8], pc: u16, cycles: usize) -> u16 {
+        interrupt_after(code, pc, cycles, true).pc
+    }
 
-                self.a |= operand;
+    #[test]
+    fn interrupts_polled_on_second_to_last_cycle() {
+        // CLI, LDA $0200 (4 cycles), NOPs
+        let code = [0x58, 0xAD, 0x00, 0x02];
+        // Seen at the end of the third cycle, taken after the LDA
+        assert_eq!(irq_return(&code, 0x8001, 2), 0x8004);
+        assert_eq!(nmi_return(&code, 0x8001, 2), 0x8004);
+        // Seen at the end of the last cycle, one more instruction runs first
+        assert_eq!(irq_return(&code, 0x8001, 3), 0x8005);
+        assert_eq!(nmi_return(&code, 0x8001, 3), 0x8005);
+    }
 
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
+    #[test]
+    fn cli_delays_irq() {
+        // The line is already high, CLI clears I too late and the NOP after it runs first
+        assert_eq!(irq_return(&[0x58], 0x8000, 0), 0x8002);
+    }
 
-            0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => {
-                // *SRE (LSR + EOR)
-                let mut operand = self.read(real_address);
+    #[test]
+    fn taken_branch_delays_interrupts() {
+        // CLI, CLC, BCC +0 (3 cycles, same page), NOPs
+        let code = [0x58, 0x18, 0x90, 0x00];
+        // Seen at the end of the first cycle, taken straight after the branch
+        assert_eq!(irq_return(&code, 0x8002, 0), 0x8004);
+        assert_eq!(nmi_return(&code, 0x8002, 0), 0x8004);
+        // Seen at the end of the second cycle, which would normally be in time, but the
+        // branch doesn't poll on its last cycle so the NOP runs first
+        assert_eq!(irq_return(&code, 0x8002, 1), 0x8005);
+        assert_eq!(nmi_return(&code, 0x8002, 1), 0x8005);
+    }
 
-                self.set_flag(Flags::CA, (operand & 0x01) != 0);
+    #[test]
+    fn nmi_hijacks_brk() {
+        // BRK, padding byte
+        let code = [0x00, 0x00];
+        let b = Flags::B1 as u8;
 
-                operand = operand >> 1;
-                self.write(real_address, operand);
+        // NMI seen by the fourth cycle, BRK pushes its pc and B flag but runs the NMI handler
+        let taken = interrupt_after(&code, 0x8000, 3, true);
+        assert_eq!((taken.handler, taken.pc, taken.stat & b), (0x9000, 0x8002, b));
 
-                self.a ^= operand;
+        // Too late, BRK goes through the IRQ vector and the NMI waits
+        let taken = interrupt_after(&code, 0x8000, 4, true);
+        assert_eq!((taken.handler, taken.pc, taken.stat & b), (0x9100, 0x8002, b));
+    }
 
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
+    #[test]
+    fn kil_jams_until_reset() {
+        // LDA #$80, STA $2000 (NMI on), KIL
+        let prg = test_prg(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x02]);
+        let rom = crate::cartridge::test_rom(0, &prg, &[0; 0x2000]);
+        let mut nes = crate::Nes::from_rom_bytes(&rom).unwrap();
 
-            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                // STA (Store A)
-                self.write(real_address, self.a);
-            }
-            0x86 | 0x96 | 0x8E => {
-                // STX (Store X)
-                self.write(real_address, self.x);
-            }
-            0x84 | 0x94 | 0x8C => {
-                // STY (Store Y)
-                self.write(real_address, self.y);
-            }
-            0xAA => {
-                // TAX (Transfer A to X)
-                self.x = self.a;
-                self.set_flag(Flags::ZE, self.x == 0x00);
-                self.set_flag(Flags::NG, (self.x & 0x80) != 0);
-            }
-            0xA8 => {
-                // TAY (Transfer A to Y)
-                self.y = self.a;
-                self.set_flag(Flags::ZE, self.y == 0x00);
-                self.set_flag(Flags::NG, (self.y & 0x80) != 0);
-            }
-            0xBA => {
-                // TSX (Transfer Stack Pointer to X)
-                self.x = self.stp;
-                self.set_flag(Flags::ZE, self.x == 0x00);
-                self.set_flag(Flags::NG, (self.x & 0x80) != 0);
-            }
-            0x8A => {
-                // TXA (Transfer X to A)
-                self.a = self.x;
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
-            0x9A => {
-                // TXS (Transfer X to Stack Pointer)
-                self.stp = self.x;
-            }
-            0x98 => {
-                // TYA (Transfer Y to A)
-                self.a = self.y;
-                self.set_flag(Flags::ZE, self.a == 0x00);
-                self.set_flag(Flags::NG, (self.a & 0x80) != 0);
-            }
-            _ => {
-                return 0; // Treat as nop
-            }
+        // The ppu carries on and its NMIs are ignored, the event only comes once
+        for _ in 0..3 {
+            nes.run_frame();
         }
-        return cycle_addition;
+        assert_eq!(nes.frame_count(), 3);
+        assert_eq!(nes.cpu().jammed(), Some((0x8005, 0x02)));
+        assert_eq!(nes.cpu().pc, 0x8005);
+        assert_eq!(
+            nes.take_cpu_events(),
+            vec![CpuEvent::Jammed { pc: 0x8005, opcode: 0x02 }]
+        );
+        nes.run_frame();
+        assert_eq!(nes.take_cpu_events(), vec![]);
+
+        // Stepping can't get it going either
+        nes.step_instruction();
+        assert_eq!(nes.cpu().jammed(), Some((0x8005, 0x02)));
+
+        nes.reset();
+        assert_eq!(nes.cpu().jammed(), None);
+        assert_eq!(nes.cpu().pc, 0x8000);
+        // Runs the program again, which jams again
+        nes.run_frame();
+        assert_eq!(nes.take_cpu_events().len(), 1);
+    }
+
+    // Run a single instruction at $8000 from a known A, X, Y, SP and memory
+    fn run_op(code: &[u8], a: u8, x: u8, y: u8, sp: u8, ram: &[(u16, u8)]) -> Cpu {
+        let mut cpu = test_cpu(code);
+        cpu.run_to(0x8000);
+        (cpu.a, cpu.x, cpu.y, cpu.stp) = (a, x, y, sp);
+        cpu.stat = N | Z | Flags::ID as u8 | Flags::B2 as u8;
+        for &(addr, value) in ram {
+            cpu.bus.borrow_mut().write(addr, value);
+        }
+        cpu.run_to(0x8000 + code.len() as u16);
+        cpu
+    }
+
+    fn peek(cpu: &Cpu, addr: u16) -> u8 {
+        cpu.bus.borrow_mut().read(addr, true)
+    }
+
+    fn nz(cpu: &Cpu) -> u8 {
+        cpu.stat & (Flags::NG as u8 | Flags::ZE as u8)
+    }
+
+    const N: u8 = Flags::NG as u8;
+    const Z: u8 = Flags::ZE as u8;
+
+    #[test]
+    fn ane() {
+        // A = (A | $EE) & X & imm
+        let cpu = run_op(&[0x8B, 0xFF], 0x00, 0xFF, 0x00, 0xFD, &[]);
+        assert_eq!((cpu.a, cpu.x, nz(&cpu)), (0xEE, 0xFF, N));
+        let cpu = run_op(&[0x8B, 0xF3], 0x11, 0x0F, 0x00, 0xFD, &[]);
+        assert_eq!((cpu.a, cpu.x, nz(&cpu)), (0x03, 0x0F, 0));
+        let cpu = run_op(&[0x8B, 0xFF], 0xFF, 0x00, 0x00, 0xFD, &[]);
+        assert_eq!((cpu.a, nz(&cpu)), (0x00, Z));
+    }
+
+    #[test]
+    fn lxa() {
+        // A = X = (A | $EE) & imm
+        let cpu = run_op(&[0xAB, 0x5A], 0x01, 0x33, 0x00, 0xFD, &[]);
+        assert_eq!((cpu.a, cpu.x, nz(&cpu)), (0x4A, 0x4A, 0));
+        let cpu = run_op(&[0xAB, 0xFF], 0x00, 0x00, 0x00, 0xFD, &[]);
+        assert_eq!((cpu.a, cpu.x, nz(&cpu)), (0xEE, 0xEE, N));
+        let cpu = run_op(&[0xAB, 0x11], 0x00, 0xFF, 0x00, 0xFD, &[]);
+        assert_eq!((cpu.a, cpu.x, nz(&cpu)), (0x00, 0x00, Z));
+    }
+
+    #[test]
+    fn las() {
+        // A = X = SP = mem & SP, LAS $0300,Y
+        let cpu = run_op(&[0xBB, 0x00, 0x03], 0x00, 0x00, 0x10, 0xF3, &[(0x0310, 0x5F)]);
+        assert_eq!((cpu.a, cpu.x, cpu.stp, nz(&cpu)), (0x53, 0x53, 0x53, 0));
+        let cpu = run_op(&[0xBB, 0x00, 0x03], 0x00, 0x00, 0x10, 0x80, &[(0x0310, 0x7F)]);
+        assert_eq!((cpu.a, cpu.x, cpu.stp, nz(&cpu)), (0x00, 0x00, 0x00, Z));
+    }
+
+    // An SH* store at $0300 + 5 stays on the page, the value is ANDed with $03 + 1
+    // One at $03F0 + $20 crosses, and the value it stores becomes the high byte too
+    // Flags are left alone
+    #[test]
+    fn sha() {
+        let ram = [(0x00F0, 0x00), (0x00F1, 0x03)];
+        for code in [&[0x9F, 0x00, 0x03][..], &[0x93, 0xF0]] {
+            let cpu = run_op(code, 0xFF, 0x0F, 0x05, 0xFD, &ram);
+            assert_eq!((peek(&cpu, 0x0305), nz(&cpu)), (0x04, N | Z));
+        }
+
+        let ram = [(0x00F0, 0xF0), (0x00F1, 0x03), (0x0410, 0xA5), (0x0010, 0xA5)];
+        for code in [&[0x9F, 0xF0, 0x03][..], &[0x93, 0xF0]] {
+            let cpu = run_op(code, 0xFB, 0xFF, 0x20, 0xFD, &ram);
+            assert_eq!((peek(&cpu, 0x0010), peek(&cpu, 0x0410)), (0x00, 0xA5));
+            let cpu = run_op(code, 0xFF, 0x0F, 0x20, 0xFD, &ram);
+            assert_eq!((peek(&cpu, 0x0410), peek(&cpu, 0x0010)), (0x04, 0xA5));
+        }
+    }
+
+    #[test]
+    fn shx() {
+        let cpu = run_op(&[0x9E, 0x00, 0x03], 0x00, 0xFF, 0x05, 0xFD, &[]);
+        assert_eq!((peek(&cpu, 0x0305), nz(&cpu)), (0x04, N | Z));
+        let ram = [(0x0410, 0xA5), (0x0010, 0xA5)];
+        let cpu = run_op(&[0x9E, 0xF0, 0x03], 0x00, 0xFB, 0x20, 0xFD, &ram);
+        assert_eq!((peek(&cpu, 0x0010), peek(&cpu, 0x0410)), (0x00, 0xA5));
+    }
+
+    #[test]
+    fn shy() {
+        let cpu = run_op(&[0x9C, 0x00, 0x03], 0x00, 0x05, 0xFF, 0xFD, &[]);
+        assert_eq!((peek(&cpu, 0x0305), nz(&cpu)), (0x04, N | Z));
+        let ram = [(0x0410, 0xA5), (0x0010, 0xA5)];
+        let cpu = run_op(&[0x9C, 0xF0, 0x03], 0x00, 0x20, 0xFF, 0xFD, &ram);
+        assert_eq!((peek(&cpu, 0x0410), peek(&cpu, 0x0010)), (0x04, 0xA5));
+    }
+
+    #[test]
+    fn tas() {
+        // SP = A & X, then stored like SHA
+        let cpu = run_op(&[0x9B, 0x00, 0x03], 0xF7, 0x3E, 0x05, 0xFD, &[]);
+        assert_eq!((cpu.stp, peek(&cpu, 0x0305)), (0x36, 0x04));
+        let ram = [(0x0410, 0xA5), (0x0010, 0xA5)];
+        let cpu = run_op(&[0x9B, 0xF0, 0x03], 0xF3, 0x3F, 0x20, 0xFD, &ram);
+        assert_eq!((cpu.stp, peek(&cpu, 0x0010), peek(&cpu, 0x0410)), (0x33, 0x00, 0xA5));
     }
-    //: }}}
 }
 //: }}}
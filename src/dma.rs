@@ -0,0 +1,264 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+use crate::bus::{Bus, JOYPAD_ONE_ADDR, JOYPAD_TWO_ADDR, OAM_DATA_ADDR};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+//: Overview Comment {{{
+/* The DMA unit of the 2A03, it copies a page to OAM ($4014 writes) and fetches DMC samples.
+ *
+ * It can only take the bus by halting the cpu, and the cpu only halts on a read cycle, so
+ * a DMA waits out any writes the cpu is doing. The first cycle (halt) is the cpu's own read,
+ * the cpu then sits on that read until the DMA is done and does it again.
+ *
+ * DMA reads happen on get (even) cycles and writes on put (odd) cycles:
+ *   OAM: halt, an alignment cycle if needed, then 256 reads each followed by a $2004 write,
+ *        513 or 514 cycles.
+ *   DMC: halt, dummy, an alignment cycle if needed, then the sample read, 3 or 4 cycles.
+ *        During an OAM copy the OAM cycles count as the halt and dummy, so it only costs
+ *        1 or 2 more.
+ * While waiting the cpu's address is read again, which is how a DMC fetch ends up clocking
+ * the controller an extra time. Reads of $4016/$4017 in a row only clock it once, so
+ * there only the halt cycle counts.
+ * */
+//: }}}
+
+//: Dma {{{
+#[derive(Default)]
+pub struct Dma {
+    oam_running: bool,
+    oam_page: u8,    // High byte of the page being copied
+    oam_count: u16,  // Cycles of the copy so far, the even ones read and the odd ones write
+    oam_value: u8,   // Byte read on the way to $2004
+    dmc_running: bool,
+    need_halt: bool,  // Waiting for the cpu to halt
+    need_dummy: bool, // The DMC needs one more cycle before it can read
+    halted: bool,     // The cpu is halted, every cycle belongs to the DMA
+    cpu_addr: u16,    // Address the cpu was reading when it was halted
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy a page to OAM, from a $4014 write
+    pub fn start_oam(&mut self, page: u8) {
+        self.oam_running = true;
+        self.oam_page = page;
+        self.oam_count = 0;
+        self.need_halt = true;
+    }
+
+    /// Fetch the next DMC sample byte, does nothing if a fetch is already on its way
+    pub fn start_dmc(&mut self) {
+        if self.dmc_running {
+            return;
+        }
+        self.dmc_running = true;
+        self.need_halt = true;
+        self.need_dummy = true;
+    }
+
+    /// True while a copy or fetch is waiting or running
+    pub fn active(&self) -> bool {
+        self.oam_running || self.dmc_running
+    }
+
+    /// True if the next cycle is the DMA's, cpu_read is what the cpu wants to read, None if
+    /// it is writing
+    pub fn takes_cycle(&self, cpu_read: Option<u16>) -> bool {
+        self.halted || (self.need_halt && cpu_read.is_some())
+    }
+
+    // Halt and dummy cycles are shared, an OAM cycle can stand in for either
+    fn use_cycle(&mut self) {
+        if self.need_halt {
+            self.need_halt = false;
+        } else if self.need_dummy {
+            self.need_dummy = false;
+        }
+    }
+
+    // Read the halted cpu's address again while waiting
+    fn dummy_read(&self, bus: &mut Bus) {
+        if self.cpu_addr != JOYPAD_ONE_ADDR && self.cpu_addr != JOYPAD_TWO_ADDR {
+            bus.read(self.cpu_addr, false);
+        }
+    }
+
+    /// One cpu cycle of DMA, only call it when takes_cycle is true.
+    /// get is true on the cycles DMA can read
+    pub fn cycle(&mut self, bus: &mut Bus, cpu_read: Option<u16>, get: bool) {
+        if !self.halted {
+            // The cpu's read goes ahead, it is done again once the DMA finishes
+            let addr = cpu_read.expect("DMA can only halt the cpu on a read");
+            bus.read(addr, false);
+            self.cpu_addr = addr;
+            self.halted = true;
+            self.need_halt = false;
+            return;
+        }
+
+        let dmc_ready = self.dmc_running && !self.need_halt && !self.need_dummy;
+        self.use_cycle();
+        if get && dmc_ready {
+            // Turning the DMC off while it waited cancels the fetch
+            if let Some(addr) = bus.apu.dmc_fetch_addr() {
+                let value = bus.read(addr, false);
+                bus.apu.dmc_fill_buffer(value);
+            }
+            self.dmc_running = false;
+        } else if get && self.oam_running {
+            let addr = ((self.oam_page as u16) << 8) | (self.oam_count / 2);
+            self.oam_value = bus.read(addr, false);
+            self.oam_count += 1;
+        } else if !get && self.oam_running && self.oam_count % 2 == 1 {
+            bus.write(OAM_DATA_ADDR, self.oam_value);
+            self.oam_count += 1;
+            if self.oam_count == 0x200 {
+                self.oam_running = false;
+            }
+        } else {
+            // Alignment, or the DMC still waiting for its dummy cycle
+            self.dummy_read(bus);
+        }
+
+        if !self.active() {
+            self.halted = false;
+        }
+    }
+}
+//: }}}
+
+//: Dma SaveState {{{
+impl SaveState for Dma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.oam_running);
+        w.write_u8(self.oam_page);
+        w.write_u16(self.oam_count);
+        w.write_u8(self.oam_value);
+        w.write_bool(self.dmc_running);
+        w.write_bool(self.need_halt);
+        w.write_bool(self.need_dummy);
+        w.write_bool(self.halted);
+        w.write_u16(self.cpu_addr);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.oam_running = r.read_bool()?;
+        self.oam_page = r.read_u8()?;
+        self.oam_count = r.read_u16()?;
+        self.oam_value = r.read_u8()?;
+        self.dmc_running = r.read_bool()?;
+        self.need_halt = r.read_bool()?;
+        self.need_dummy = r.read_bool()?;
+        self.halted = r.read_bool()?;
+        self.cpu_addr = r.read_u16()?;
+        Ok(())
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_cpu;
+    use crate::mapper::test_bus;
+
+    // $0200-$02FF holds 0-255
+    fn fill_page(bus: &mut Bus) {
+        for i in 0..=255u8 {
+            bus.write(0x0200 | i as u16, i);
+        }
+    }
+
+    // Bus with a one byte DMC sample waiting to be fetched
+    fn dmc_bus() -> Bus {
+        let mut bus = test_bus(0, &vec![0xEA; 0x8000], &[]);
+        bus.write(0x4012, 0x00);
+        bus.write(0x4013, 0x00);
+        bus.write(0x4015, 0x10);
+        assert_eq!(bus.apu.dmc_fetch_addr(), Some(0xC000));
+        bus
+    }
+
+    // Run the DMA with the cpu trying to read, cycles taken
+    fn run(dma: &mut Dma, bus: &mut Bus, mut get: bool, dmc_at: Option<usize>) -> usize {
+        let mut cycles = 0;
+        while dma.active() {
+            if Some(cycles) == dmc_at {
+                dma.start_dmc();
+            }
+            assert!(dma.takes_cycle(Some(0x8000)));
+            dma.cycle(bus, Some(0x8000), get);
+            get = !get;
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn oam_dma_from_cpu() {
+        // LDA #$02, STA $4014, NOP. The LDA $00 in front of the second one moves the write
+        // to the other parity
+        for (code, sta) in [
+            (&[0xA9, 0x02, 0x8D, 0x14, 0x40][..], 0x8002),
+            (&[0xA5, 0x00, 0xA9, 0x02, 0x8D, 0x14, 0x40], 0x8004),
+        ] {
+            let mut cpu = test_cpu(code);
+            cpu.run_to(0x8000);
+            fill_page(&mut cpu.bus.borrow_mut());
+            cpu.run_to(sta);
+            let start = cpu.cycl;
+            cpu.run_to(sta + 4);
+
+            // The write is the STA's fourth cycle, the cpu halts on the read after it. Reads
+            // need a get cycle so a write on a put cycle costs an extra alignment cycle
+            let write_on_put = (start + 3) % 2 == 1;
+            let expected = 4 + 2 + if write_on_put { 514 } else { 513 };
+            assert_eq!(cpu.cycl - start, expected);
+
+            let bus = cpu.bus.borrow();
+            assert!((0..=255u8).all(|i| bus.ppu_data.oam[i as usize] == i));
+        }
+    }
+
+    #[test]
+    fn dmc_dma_alignment() {
+        for (get, expected) in [(true, 3), (false, 4)] {
+            let mut bus = dmc_bus();
+            let mut dma = Dma::new();
+            dma.start_dmc();
+            // Only a read can be halted
+            assert!(!dma.takes_cycle(None));
+            // Halt, dummy, an alignment cycle if needed, then the sample read on a get
+            assert_eq!(run(&mut dma, &mut bus, get, None), expected);
+            assert_eq!(bus.apu.dmc_fetch_addr(), None);
+        }
+    }
+
+    #[test]
+    fn dmc_dma_during_oam_dma() {
+        // Halting on a get means an alignment cycle before the first read
+        for (get, oam_cycles) in [(true, 514), (false, 513)] {
+            let mut bus = dmc_bus();
+            fill_page(&mut bus);
+            let mut dma = Dma::new();
+            dma.start_oam(0x02);
+            assert_eq!(run(&mut dma, &mut bus, get, None), oam_cycles);
+
+            // The OAM cycles stand in for the DMC's halt and dummy, so it only costs the
+            // sample read and the alignment cycle after it
+            let mut bus = dmc_bus();
+            fill_page(&mut bus);
+            let mut dma = Dma::new();
+            dma.start_oam(0x02);
+            assert_eq!(run(&mut dma, &mut bus, get, Some(100)), oam_cycles + 2);
+            assert_eq!(bus.apu.dmc_fetch_addr(), None);
+            assert!((0..=255u8).all(|i| bus.ppu_data.oam[i as usize] == i));
+        }
+    }
+}
+//: }}}
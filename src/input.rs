@@ -2,10 +2,161 @@
 // vim:foldmethod=marker
 #![allow(dead_code)]
 #![allow(unused_variables)]
-use macroquad::prelude::*;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
-pub struct Input {
-    // Current Button State
+// Button bits, in the order the controller shifts them out
+pub const BUTTON_A: u8 = 0b00000001;
+pub const BUTTON_B: u8 = 0b00000010;
+pub const BUTTON_SELECT: u8 = 0b00000100;
+pub const BUTTON_START: u8 = 0b00001000;
+pub const BUTTON_UP: u8 = 0b00010000;
+pub const BUTTON_DOWN: u8 = 0b00100000;
+pub const BUTTON_LEFT: u8 = 0b01000000;
+pub const BUTTON_RIGHT: u8 = 0b10000000;
+
+// Names used by config files and input scripts
+pub const BUTTON_NAMES: [(&str, u8); 8] = [
+    ("a", BUTTON_A),
+    ("b", BUTTON_B),
+    ("select", BUTTON_SELECT),
+    ("start", BUTTON_START),
+    ("up", BUTTON_UP),
+    ("down", BUTTON_DOWN),
+    ("left", BUTTON_LEFT),
+    ("right", BUTTON_RIGHT),
+];
+
+// Button bit from its name, case insensitive
+pub fn button_from_name(name: &str) -> Option<u8> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, bit)| *bit)
+}
+
+// Number of controller ports on the console
+pub const PORT_COUNT: usize = 2;
+// Players with a four player adapter, players 1 and 3 are on port 1, 2 and 4 on port 2
+pub const PLAYER_COUNT: usize = 4;
+
+//: InputDevice {{{
+/// Anything that can be plugged into a controller port
+pub trait InputDevice: SaveState {
+    fn kind(&self) -> DeviceKind;
+
+    /// Bit 0 of a $4016 write
+    fn write_strobe(&mut self, strobe: bool);
+
+    /// Bits 0-4 of a $4016/$4017 read, the bus fills in the rest
+    fn read(&mut self) -> u8;
+
+    /// Same as read without side effects, for debug reads
+    fn peek(&self) -> u8;
+
+    /// Buttons from the frontend, see the BUTTON_* bits
+    /// Slot 1 is the second controller on a four player adapter
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {}
+
+    /// Zapper aim and trigger from the frontend
+    fn set_zapper(&mut self, zapper: ZapperInput) {}
+
+    /// A pixel just drawn by the ppu, used by light sensing devices
+    fn pixel(&mut self, x: u8, y: u8, rgb: [u8; 3]) {}
+
+    /// The ppu moved on to the next scanline
+    fn end_scanline(&mut self) {}
+
+    /// True if the device wants pixel()
+    fn senses_light(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    None,
+    Controller,
+    Zapper,
+    /// NES Four Score, takes up both ports
+    FourScore,
+    /// Famicom four player adapter on the expansion port, takes up both ports
+    FamicomAdapter,
+}
+
+impl DeviceKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            DeviceKind::None => 0,
+            DeviceKind::Controller => 1,
+            DeviceKind::Zapper => 2,
+            DeviceKind::FourScore => 3,
+            DeviceKind::FamicomAdapter => 4,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(DeviceKind::None),
+            1 => Some(DeviceKind::Controller),
+            2 => Some(DeviceKind::Zapper),
+            3 => Some(DeviceKind::FourScore),
+            4 => Some(DeviceKind::FamicomAdapter),
+            _ => None,
+        }
+    }
+
+    /// Four player adapters plug into both ports at once
+    pub fn is_multitap(self) -> bool {
+        matches!(self, DeviceKind::FourScore | DeviceKind::FamicomAdapter)
+    }
+
+    /// A freshly plugged in device of this kind, port matters for four player adapters
+    pub fn create(self, port: usize) -> Box<dyn InputDevice> {
+        match self {
+            DeviceKind::None => Box::new(Unplugged),
+            DeviceKind::Controller => Box::<Controller>::default(),
+            DeviceKind::Zapper => Box::<Zapper>::default(),
+            DeviceKind::FourScore => Box::new(FourScore::new(port)),
+            DeviceKind::FamicomAdapter => Box::<FamicomAdapter>::default(),
+        }
+    }
+}
+//: }}}
+
+//: Unplugged {{{
+/// Empty port, reads back 0
+pub struct Unplugged;
+
+impl InputDevice for Unplugged {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::None
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {}
+
+    fn read(&mut self) -> u8 {
+        0
+    }
+
+    fn peek(&self) -> u8 {
+        0
+    }
+}
+
+impl SaveState for Unplugged {
+    fn save_state(&self, w: &mut StateWriter) {}
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+//: }}}
+
+//: Controller {{{
+/// Standard controller
+#[derive(Default)]
+pub struct Controller {
+    // Button state as given by the frontend (keyboard, script, ...)
     // Layout:
     // 0 - A
     // 1 - B
@@ -15,54 +166,539 @@ pub struct Input {
     // 5 - Down
     // 6 - Left
     // 7 - Right
-    pub joypad_one: u8,
+    pub buttons: u8,
+
+    // Shift register, loaded from buttons while the strobe is high
+    shift: u8,
+    strobe: bool,
+}
+
+impl InputDevice for Controller {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Controller
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        // Latch while high, and on the falling edge so the newest state is kept
+        if strobe || self.strobe {
+            self.shift = self.buttons;
+        }
+        self.strobe = strobe;
+    }
+
+    // Serial read, one button per read
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            // Keeps reloading, so only A can be read
+            return self.buttons & 1;
+        }
+        let bit = self.shift & 1;
+        // Official controllers return 1 once all 8 buttons are read
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+
+    fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 1
+        } else {
+            self.shift & 1
+        }
+    }
+
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {
+        if slot == 0 {
+            self.buttons = buttons;
+        }
+    }
+}
+
+impl SaveState for Controller {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.buttons);
+        w.write_u8(self.shift);
+        w.write_bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = r.read_u8()?;
+        self.shift = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
+//: }}}
+
+//: Zapper {{{
+// Pixels around the aim point the photodiode sees
+const ZAPPER_RADIUS: i16 = 2;
+// Brightness (0-255) that counts as light
+const ZAPPER_THRESHOLD: u32 = 0x80;
+// Scanlines the photodiode stays on after seeing a bright pixel
+const ZAPPER_LIGHT_SCANLINES: u8 = 20;
+
+/// Where the zapper points and whether the trigger is pulled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZapperInput {
+    pub x: u8,
+    // 240 and up points away from the screen
+    pub y: u8,
+    pub trigger: bool,
+}
+
+impl Default for ZapperInput {
+    fn default() -> Self {
+        Self {
+            x: 0,
+            y: 0xFF,
+            trigger: false,
+        }
+    }
+}
+
+/// Light gun, only reads the trigger (bit 4) and light sensor (bit 3)
+#[derive(Default)]
+pub struct Zapper {
+    pub aim: ZapperInput,
+    // Scanlines left before the photodiode goes dark
+    light: u8,
+}
+
+impl InputDevice for Zapper {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Zapper
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {}
+
+    fn read(&mut self) -> u8 {
+        self.peek()
+    }
+
+    fn peek(&self) -> u8 {
+        // Light sense is active low
+        let light = if self.light > 0 { 0 } else { 0x08 };
+        let trigger = if self.aim.trigger { 0x10 } else { 0 };
+        light | trigger
+    }
+
+    fn set_zapper(&mut self, zapper: ZapperInput) {
+        self.aim = zapper;
+    }
+
+    fn pixel(&mut self, x: u8, y: u8, rgb: [u8; 3]) {
+        let dx = x as i16 - self.aim.x as i16;
+        let dy = y as i16 - self.aim.y as i16;
+        if dx.abs() > ZAPPER_RADIUS || dy.abs() > ZAPPER_RADIUS {
+            return;
+        }
+        // Rec. 601 luma
+        let [r, g, b] = rgb.map(|c| c as u32);
+        if (r * 299 + g * 587 + b * 114) / 1000 >= ZAPPER_THRESHOLD {
+            self.light = ZAPPER_LIGHT_SCANLINES;
+        }
+    }
+
+    fn end_scanline(&mut self) {
+        self.light = self.light.saturating_sub(1);
+    }
+
+    fn senses_light(&self) -> bool {
+        self.aim.y < 240
+    }
+}
+
+impl SaveState for Zapper {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.aim.x);
+        w.write_u8(self.aim.y);
+        w.write_bool(self.aim.trigger);
+        w.write_u8(self.light);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.aim.x = r.read_u8()?;
+        self.aim.y = r.read_u8()?;
+        self.aim.trigger = r.read_bool()?;
+        self.light = r.read_u8()?;
+        Ok(())
+    }
+}
+//: }}}
+
+//: FourScore {{{
+/// Half of an NES Four Score, the port reads 8 bits of the first controller, 8 of the
+/// second and then an 8 bit signature
+pub struct FourScore {
+    // Players 1 and 3 on port 1, 2 and 4 on port 2
+    pub buttons: [u8; 2],
+    // Identifies which port the adapter is on, shifted out lsb first like the buttons
+    signature: u8,
+    // 24 bit shift register
+    shift: u32,
+    strobe: bool,
+}
+
+impl FourScore {
+    pub fn new(port: usize) -> Self {
+        Self {
+            buttons: [0; 2],
+            signature: if port == 0 { 0x08 } else { 0x04 },
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift = self.buttons[0] as u32
+            | (self.buttons[1] as u32) << 8
+            | (self.signature as u32) << 16;
+    }
+}
+
+impl InputDevice for FourScore {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::FourScore
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        if strobe || self.strobe {
+            self.latch();
+        }
+        self.strobe = strobe;
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons[0] & 1;
+        }
+        let bit = (self.shift & 1) as u8;
+        // 1s once the signature is out
+        self.shift = (self.shift >> 1) | 0x800000;
+        bit
+    }
+
+    fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons[0] & 1
+        } else {
+            (self.shift & 1) as u8
+        }
+    }
+
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {
+        if let Some(b) = self.buttons.get_mut(slot) {
+            *b = buttons;
+        }
+    }
+}
+
+impl SaveState for FourScore {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.buttons[0]);
+        w.write_u8(self.buttons[1]);
+        w.write_u8(self.signature);
+        w.write_u32(self.shift);
+        w.write_bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.buttons[0] = r.read_u8()?;
+        self.buttons[1] = r.read_u8()?;
+        self.signature = r.read_u8()?;
+        self.shift = r.read_u32()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
+//: }}}
+
+//: FamicomAdapter {{{
+/// Half of a Famicom four player adapter, the extra controller on the expansion port is
+/// read on bit 1 alongside the normal one on bit 0
+#[derive(Default)]
+pub struct FamicomAdapter {
+    controllers: [Controller; 2],
+}
+
+impl InputDevice for FamicomAdapter {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::FamicomAdapter
+    }
 
-    // false: latch input
-    // true: read input continually
-    pub read_latch: bool,
+    fn write_strobe(&mut self, strobe: bool) {
+        for controller in self.controllers.iter_mut() {
+            controller.write_strobe(strobe);
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        self.controllers[0].read() | self.controllers[1].read() << 1
+    }
+
+    fn peek(&self) -> u8 {
+        self.controllers[0].peek() | self.controllers[1].peek() << 1
+    }
+
+    fn set_buttons(&mut self, slot: usize, buttons: u8) {
+        if let Some(controller) = self.controllers.get_mut(slot) {
+            controller.set_buttons(0, buttons);
+        }
+    }
+}
+
+impl SaveState for FamicomAdapter {
+    fn save_state(&self, w: &mut StateWriter) {
+        for controller in self.controllers.iter() {
+            controller.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+//: }}}
+
+//: Input {{{
+pub struct Input {
+    // Port 1 is read at $4016, port 2 at $4017
+    pub ports: [Box<dyn InputDevice>; PORT_COUNT],
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Input {
+    /// Standard controllers in both ports
     pub fn new() -> Self {
         Self {
-            joypad_one: 0,
-            read_latch: false,
+            ports: [DeviceKind::Controller.create(0), DeviceKind::Controller.create(1)],
+        }
+    }
+
+    /// Swap what is plugged into a port
+    /// Four player adapters always take both ports, and unplugging one half of an adapter
+    /// puts a standard controller in the other port
+    pub fn plug(&mut self, port: usize, kind: DeviceKind) {
+        if port >= PORT_COUNT {
+            return;
+        }
+        for (i, device) in self.ports.iter_mut().enumerate() {
+            if i == port || kind.is_multitap() {
+                *device = kind.create(i);
+            } else if device.kind().is_multitap() {
+                *device = DeviceKind::Controller.create(i);
+            }
         }
     }
 
-    // Helper functions to set the right bits
-    pub fn set_a_input(&mut self, val: bool) { if val { self.joypad_one |= 0b00000001; } else { self.joypad_one &= 0b11111110; } }
-    pub fn set_b_input(&mut self, val: bool) { if val { self.joypad_one |= 0b00000010; } else { self.joypad_one &= 0b11111101; } }
-    pub fn set_select_input(&mut self, val: bool) { if val { self.joypad_one |= 0b00000100; } else { self.joypad_one &= 0b11111011; } }
-    pub fn set_start_input(&mut self, val: bool) { if val { self.joypad_one |= 0b00001000; } else { self.joypad_one &= 0b11110111; } }
-    pub fn set_up_input(&mut self, val: bool) { if val { self.joypad_one |= 0b00010000; } else { self.joypad_one &= 0b11101111; } }
-    pub fn set_down_input(&mut self, val: bool) { if val { self.joypad_one |= 0b00100000; } else { self.joypad_one &= 0b11011111; } }
-    pub fn set_left_input(&mut self, val: bool) { if val { self.joypad_one |= 0b01000000; } else { self.joypad_one &= 0b10111111; } }
-    pub fn set_right_input(&mut self, val: bool) { if val { self.joypad_one |= 0b10000000; } else { self.joypad_one &= 0b01111111; } }
+    pub fn device_kind(&self, port: usize) -> DeviceKind {
+        self.ports.get(port).map_or(DeviceKind::None, |d| d.kind())
+    }
 
-    pub fn update_input(&mut self) {
-        // Note: do something better he for keybinding
-        if self.read_latch {
-            self.set_a_input(is_key_down(macroquad::prelude::KeyCode::A));
-            self.set_b_input(is_key_down(macroquad::prelude::KeyCode::O));
-            self.set_select_input(is_key_down(macroquad::prelude::KeyCode::E));
-            self.set_start_input(is_key_down(macroquad::prelude::KeyCode::U));
-            self.set_up_input(is_key_down(macroquad::prelude::KeyCode::Up));
-            self.set_down_input(is_key_down(macroquad::prelude::KeyCode::Down));
-            self.set_left_input(is_key_down(macroquad::prelude::KeyCode::Left));
-            self.set_right_input(is_key_down(macroquad::prelude::KeyCode::Right));
+    // Set every button of a player at once, see the BUTTON_* bits
+    // Players 3 and 4 only exist with a four player adapter
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        if let Some(device) = self.ports.get_mut(player % PORT_COUNT) {
+            device.set_buttons(player / PORT_COUNT, buttons);
         }
     }
 
-    // Latch input
-    pub fn set_latch(&mut self, val: bool) {
-        self.read_latch = val;
+    // Aim every zapper that is plugged in
+    pub fn set_zapper(&mut self, zapper: ZapperInput) {
+        for device in self.ports.iter_mut() {
+            device.set_zapper(zapper);
+        }
+    }
+
+    // Write to $4016, bit 0 is the strobe for every port
+    pub fn write_strobe(&mut self, value: u8) {
+        for device in self.ports.iter_mut() {
+            device.write_strobe(value & 1 != 0);
+        }
+    }
+
+    // Next bits of a port without side effects, for debug reads
+    pub fn peek(&self, port: usize) -> u8 {
+        self.ports.get(port).map_or(0, |d| d.peek())
     }
 
     // NES is setup so input is read one bit at a time
-    pub fn read_and_shift_joypad_one(&mut self) -> u8 {
-        let bit = self.joypad_one & 1;
-        self.joypad_one >>= 1;
-        bit
+    // Only bits 0-4 are driven, the bus fills in the rest
+    pub fn read(&mut self, port: usize) -> u8 {
+        self.ports.get_mut(port).map_or(0, |d| d.read())
+    }
+
+    // True if any device needs to see the pixels being drawn
+    pub fn senses_light(&self) -> bool {
+        self.ports.iter().any(|d| d.senses_light())
+    }
+
+    // Pixel output by the ppu, x 0-255 and y 0-239
+    pub fn pixel(&mut self, x: u8, y: u8, rgb: [u8; 3]) {
+        for device in self.ports.iter_mut() {
+            device.pixel(x, y, rgb);
+        }
+    }
+
+    pub fn end_scanline(&mut self) {
+        for device in self.ports.iter_mut() {
+            device.end_scanline();
+        }
+    }
+}
+
+impl SaveState for Input {
+    fn save_state(&self, w: &mut StateWriter) {
+        for device in self.ports.iter() {
+            w.write_u8(device.kind().to_u8());
+            device.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for (port, device) in self.ports.iter_mut().enumerate() {
+            // Plug in whatever was in the port when the state was saved
+            let kind = DeviceKind::from_u8(r.read_u8()?).ok_or(StateError::Corrupt("input device"))?;
+            if device.kind() != kind {
+                *device = kind.create(port);
+            }
+            device.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+//: }}}
+
+//: InputSource {{{
+// Console commands that can go with a frame of input
+pub const COMMAND_RESET: u8 = 0b01;
+pub const COMMAND_POWER: u8 = 0b10;
+
+/// Everything fed to the console for one frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameInput {
+    // COMMAND_* bits, applied before the frame runs
+    pub commands: u8,
+    // Buttons held by each player
+    pub buttons: [u8; PLAYER_COUNT],
+    // Aim and trigger for a zapper, if one is plugged in
+    pub zapper: ZapperInput,
+}
+
+/// Where the input for each frame comes from (keyboard, movie, script, ...)
+pub trait InputSource {
+    /// Input for the next frame, None once the source has run out
+    fn next_frame(&mut self) -> Option<FrameInput>;
+}
+//: }}}
+
+//: Autofire {{{
+/// Buttons pressed over several frames, started by a single host key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Macro {
+    pub player: usize,
+    // Buttons held on each frame of the macro
+    pub frames: Vec<u8>,
+}
+
+/// Frames for one press and release of a turbo button when the binding doesn't say
+pub const DEFAULT_TURBO_PERIOD: u32 = 4;
+
+/// A turbo button being held this frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Turbo {
+    pub player: usize,
+    pub buttons: u8,
+    // Frames for one press and release, at least 2
+    pub period: u32,
+}
+
+/// Turbo buttons and macros, applied to the host input once per frame before it reaches
+/// the console, so games only ever see normal button presses
+#[derive(Default)]
+pub struct Autofire {
+    frame: u32,
+    // Macros being played and how far along they are
+    running: Vec<(Macro, usize)>,
+}
+
+impl Autofire {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start playing a macro from its first frame
+    pub fn start(&mut self, m: &Macro) {
+        self.running.push((m.clone(), 0));
+    }
+
+    /// Add this frame's turbo and macro presses to input
+    /// turbo is the turbo buttons being held, each with its own rate
+    pub fn apply(&mut self, input: &mut FrameInput, turbo: &[Turbo]) {
+        // Pressed for the first half of each period, released for the rest
+        for t in turbo {
+            let period = t.period.max(2);
+            if self.frame % period < period / 2 {
+                if let Some(buttons) = input.buttons.get_mut(t.player) {
+                    *buttons |= t.buttons;
+                }
+            }
+        }
+        self.frame = self.frame.wrapping_add(1);
+
+        for (m, position) in self.running.iter_mut() {
+            if let Some(buttons) = input.buttons.get_mut(m.player) {
+                *buttons |= m.frames[*position];
+            }
+            *position += 1;
+        }
+        self.running.retain(|(m, position)| *position < m.frames.len());
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strobe and read a whole report from a port
+    fn report(input: &mut Input, port: usize) -> Vec<u8> {
+        input.write_strobe(1);
+        input.write_strobe(0);
+        (0..24).map(|_| input.read(port)).collect()
+    }
+
+    #[test]
+    fn four_score_report() {
+        let mut input = Input::new();
+        input.plug(0, DeviceKind::FourScore);
+        input.set_buttons(0, BUTTON_A);
+        input.set_buttons(1, BUTTON_B);
+        input.set_buttons(2, BUTTON_START);
+        input.set_buttons(3, BUTTON_RIGHT);
+
+        // Player 1 then 3, and the signature 0,0,0,1,0,0,0,0
+        let mut expected = vec![0; 24];
+        expected[0] = 1;
+        expected[8 + 3] = 1;
+        expected[16 + 3] = 1;
+        assert_eq!(report(&mut input, 0), expected);
+
+        // Player 2 then 4, and the signature 0,0,1,0,0,0,0,0
+        let mut expected = vec![0; 24];
+        expected[1] = 1;
+        expected[8 + 7] = 1;
+        expected[16 + 2] = 1;
+        assert_eq!(report(&mut input, 1), expected);
     }
 }
+//: }}}
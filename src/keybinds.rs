@@ -0,0 +1,597 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+use crate::input::*;
+use macroquad::input::{is_key_down, is_key_pressed, KeyCode};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// Key binding profiles live in <config dir>/nes_emulator/<profile>.keys
+//
+// Each line binds a player's button to one or more keys, players 3 and 4 need a four player
+// adapter:
+//   p1.a = A
+//   p2.start = Kp9, Enter
+// Lines starting with # are comments. Key names are the macroquad KeyCode names, case
+// insensitive.
+//
+// Turbo buttons toggle while their key is held, pressed for the first half of every
+// period frames. Each key can give its own period with *<frames>, the rest use
+// turbo_period:
+//   turbo_period = 4
+//   p1.turbo.a = S, X*8
+// Macros play a list of button steps when their key is pressed, each step is buttons joined
+// with + ("." for none) and an optional *<frames>, e.g. a hadouken:
+//   p1.macro.F1 = down*2, down+right*2, right+a
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Dvorak layout + arrows for player one, keypad for player two
+const DEFAULT_BINDINGS: [(usize, KeyCode, u8); 16] = [
+    (0, KeyCode::A, BUTTON_A),
+    (0, KeyCode::O, BUTTON_B),
+    (0, KeyCode::E, BUTTON_SELECT),
+    (0, KeyCode::U, BUTTON_START),
+    (0, KeyCode::Up, BUTTON_UP),
+    (0, KeyCode::Down, BUTTON_DOWN),
+    (0, KeyCode::Left, BUTTON_LEFT),
+    (0, KeyCode::Right, BUTTON_RIGHT),
+    (1, KeyCode::Kp2, BUTTON_A),
+    (1, KeyCode::Kp1, BUTTON_B),
+    (1, KeyCode::Kp7, BUTTON_SELECT),
+    (1, KeyCode::Kp9, BUTTON_START),
+    (1, KeyCode::Kp8, BUTTON_UP),
+    (1, KeyCode::Kp5, BUTTON_DOWN),
+    (1, KeyCode::Kp4, BUTTON_LEFT),
+    (1, KeyCode::Kp6, BUTTON_RIGHT),
+];
+
+//: Key names {{{
+const KEY_NAMES: [(&str, KeyCode); 120] = [
+    ("Space", KeyCode::Space),
+    ("Apostrophe", KeyCode::Apostrophe),
+    ("Comma", KeyCode::Comma),
+    ("Minus", KeyCode::Minus),
+    ("Period", KeyCode::Period),
+    ("Slash", KeyCode::Slash),
+    ("Key0", KeyCode::Key0),
+    ("Key1", KeyCode::Key1),
+    ("Key2", KeyCode::Key2),
+    ("Key3", KeyCode::Key3),
+    ("Key4", KeyCode::Key4),
+    ("Key5", KeyCode::Key5),
+    ("Key6", KeyCode::Key6),
+    ("Key7", KeyCode::Key7),
+    ("Key8", KeyCode::Key8),
+    ("Key9", KeyCode::Key9),
+    ("Semicolon", KeyCode::Semicolon),
+    ("Equal", KeyCode::Equal),
+    ("A", KeyCode::A),
+    ("B", KeyCode::B),
+    ("C", KeyCode::C),
+    ("D", KeyCode::D),
+    ("E", KeyCode::E),
+    ("F", KeyCode::F),
+    ("G", KeyCode::G),
+    ("H", KeyCode::H),
+    ("I", KeyCode::I),
+    ("J", KeyCode::J),
+    ("K", KeyCode::K),
+    ("L", KeyCode::L),
+    ("M", KeyCode::M),
+    ("N", KeyCode::N),
+    ("O", KeyCode::O),
+    ("P", KeyCode::P),
+    ("Q", KeyCode::Q),
+    ("R", KeyCode::R),
+    ("S", KeyCode::S),
+    ("T", KeyCode::T),
+    ("U", KeyCode::U),
+    ("V", KeyCode::V),
+    ("W", KeyCode::W),
+    ("X", KeyCode::X),
+    ("Y", KeyCode::Y),
+    ("Z", KeyCode::Z),
+    ("LeftBracket", KeyCode::LeftBracket),
+    ("Backslash", KeyCode::Backslash),
+    ("RightBracket", KeyCode::RightBracket),
+    ("GraveAccent", KeyCode::GraveAccent),
+    ("World1", KeyCode::World1),
+    ("World2", KeyCode::World2),
+    ("Escape", KeyCode::Escape),
+    ("Enter", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("Backspace", KeyCode::Backspace),
+    ("Insert", KeyCode::Insert),
+    ("Delete", KeyCode::Delete),
+    ("Right", KeyCode::Right),
+    ("Left", KeyCode::Left),
+    ("Down", KeyCode::Down),
+    ("Up", KeyCode::Up),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("CapsLock", KeyCode::CapsLock),
+    ("ScrollLock", KeyCode::ScrollLock),
+    ("NumLock", KeyCode::NumLock),
+    ("PrintScreen", KeyCode::PrintScreen),
+    ("Pause", KeyCode::Pause),
+    ("F1", KeyCode::F1),
+    ("F2", KeyCode::F2),
+    ("F3", KeyCode::F3),
+    ("F4", KeyCode::F4),
+    ("F5", KeyCode::F5),
+    ("F6", KeyCode::F6),
+    ("F7", KeyCode::F7),
+    ("F8", KeyCode::F8),
+    ("F9", KeyCode::F9),
+    ("F10", KeyCode::F10),
+    ("F11", KeyCode::F11),
+    ("F12", KeyCode::F12),
+    ("F13", KeyCode::F13),
+    ("F14", KeyCode::F14),
+    ("F15", KeyCode::F15),
+    ("F16", KeyCode::F16),
+    ("F17", KeyCode::F17),
+    ("F18", KeyCode::F18),
+    ("F19", KeyCode::F19),
+    ("F20", KeyCode::F20),
+    ("F21", KeyCode::F21),
+    ("F22", KeyCode::F22),
+    ("F23", KeyCode::F23),
+    ("F24", KeyCode::F24),
+    ("F25", KeyCode::F25),
+    ("Kp0", KeyCode::Kp0),
+    ("Kp1", KeyCode::Kp1),
+    ("Kp2", KeyCode::Kp2),
+    ("Kp3", KeyCode::Kp3),
+    ("Kp4", KeyCode::Kp4),
+    ("Kp5", KeyCode::Kp5),
+    ("Kp6", KeyCode::Kp6),
+    ("Kp7", KeyCode::Kp7),
+    ("Kp8", KeyCode::Kp8),
+    ("Kp9", KeyCode::Kp9),
+    ("KpDecimal", KeyCode::KpDecimal),
+    ("KpDivide", KeyCode::KpDivide),
+    ("KpMultiply", KeyCode::KpMultiply),
+    ("KpSubtract", KeyCode::KpSubtract),
+    ("KpAdd", KeyCode::KpAdd),
+    ("KpEnter", KeyCode::KpEnter),
+    ("KpEqual", KeyCode::KpEqual),
+    ("LeftShift", KeyCode::LeftShift),
+    ("LeftControl", KeyCode::LeftControl),
+    ("LeftAlt", KeyCode::LeftAlt),
+    ("LeftSuper", KeyCode::LeftSuper),
+    ("RightShift", KeyCode::RightShift),
+    ("RightControl", KeyCode::RightControl),
+    ("RightAlt", KeyCode::RightAlt),
+    ("RightSuper", KeyCode::RightSuper),
+    ("Menu", KeyCode::Menu),
+];
+
+fn key_from_name(name: &str) -> Option<KeyCode> {
+    KEY_NAMES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, key)| *key)
+}
+
+fn key_name(key: KeyCode) -> &'static str {
+    KEY_NAMES
+        .iter()
+        .find(|(_, k)| *k == key)
+        .map_or("Unknown", |(name, _)| name)
+}
+//: }}}
+
+//: BindingError {{{
+#[derive(Debug)]
+pub enum BindingError {
+    Io(io::Error),
+    /// Line is not <player>.<button> = <keys>
+    Syntax(usize),
+    UnknownPlayer(usize, String),
+    UnknownButton(usize, String),
+    UnknownKey(usize, String),
+    /// turbo_period or macro steps that don't parse
+    BadValue(usize, String),
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingError::Io(e) => write!(f, "{e}"),
+            BindingError::Syntax(line) => {
+                write!(f, "line {line}: expected <player>.<button> = <key>[, <key>...]")
+            }
+            BindingError::UnknownPlayer(line, player) => {
+                write!(f, "line {line}: unknown player {player}")
+            }
+            BindingError::UnknownButton(line, button) => {
+                write!(f, "line {line}: unknown button {button}")
+            }
+            BindingError::UnknownKey(line, key) => write!(f, "line {line}: unknown key {key}"),
+            BindingError::BadValue(line, value) => write!(f, "line {line}: bad value {value}"),
+        }
+    }
+}
+
+impl std::error::Error for BindingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BindingError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+//: }}}
+
+//: KeyBindings {{{
+/// Host keys mapped to controller buttons, turbo buttons and macros
+pub struct KeyBindings {
+    // (player, key, button bit)
+    bindings: Vec<(usize, KeyCode, u8)>,
+    // (player, key, button bit, period), the button fires every period frames while the
+    // key is held
+    turbo: Vec<(usize, KeyCode, u8, u32)>,
+    // Period for turbo keys that don't give one
+    turbo_period: u32,
+    // Macros and the key that starts them
+    macros: Vec<(KeyCode, Macro)>,
+    autofire: Autofire,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            bindings: DEFAULT_BINDINGS.to_vec(),
+            turbo: Vec::new(),
+            turbo_period: DEFAULT_TURBO_PERIOD,
+            macros: Vec::new(),
+            autofire: Autofire::new(),
+        }
+    }
+}
+
+// Buttons joined with +, "." for none
+fn parse_button_set(text: &str) -> Option<u8> {
+    if text == "." {
+        return Some(0);
+    }
+    text.split('+')
+        .try_fold(0, |buttons, name| Some(buttons | button_from_name(name.trim())?))
+}
+
+fn button_set_name(buttons: u8) -> String {
+    let names: Vec<&str> = BUTTON_NAMES
+        .iter()
+        .filter(|(_, bit)| buttons & bit != 0)
+        .map(|(name, _)| *name)
+        .collect();
+    if names.is_empty() {
+        ".".to_string()
+    } else {
+        names.join("+")
+    }
+}
+
+// Macro steps, "<buttons>[*<frames>]" separated by commas
+fn parse_macro_steps(text: &str) -> Option<Vec<u8>> {
+    let mut frames = Vec::new();
+    for step in text.split(',').map(str::trim) {
+        let (buttons, count) = match step.split_once('*') {
+            Some((buttons, count)) => (buttons.trim(), count.trim().parse::<usize>().ok()?),
+            None => (step, 1),
+        };
+        let buttons = parse_button_set(buttons)?;
+        frames.extend(std::iter::repeat_n(buttons, count));
+    }
+    if frames.is_empty() {
+        None
+    } else {
+        Some(frames)
+    }
+}
+
+// Turbo key, "<key>[*<period>]", None for the period if it is left to turbo_period
+fn parse_turbo_key(text: &str) -> Option<(KeyCode, Option<u32>)> {
+    let (key, period) = match text.split_once('*') {
+        Some((key, period)) => match period.trim().parse::<u32>() {
+            Ok(period) if period >= 2 => (key.trim(), Some(period)),
+            _ => return None,
+        },
+        None => (text, None),
+    };
+    Some((key_from_name(key)?, period))
+}
+
+fn macro_steps_text(frames: &[u8]) -> String {
+    let mut steps = Vec::new();
+    for run in frames.chunk_by(|a, b| a == b) {
+        let buttons = button_set_name(run[0]);
+        if run.len() == 1 {
+            steps.push(buttons);
+        } else {
+            steps.push(format!("{buttons}*{}", run.len()));
+        }
+    }
+    steps.join(", ")
+}
+
+impl KeyBindings {
+    /// Parse a profile, see the top of this file for the format
+    pub fn parse(text: &str) -> Result<Self, BindingError> {
+        let mut bindings = Self {
+            bindings: Vec::new(),
+            turbo: Vec::new(),
+            turbo_period: DEFAULT_TURBO_PERIOD,
+            macros: Vec::new(),
+            autofire: Autofire::new(),
+        };
+        // Turbo keys without a period of their own, filled in once turbo_period is known
+        let mut default_turbo = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let number = number + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((target, value)) = line.split_once('=') else {
+                return Err(BindingError::Syntax(number));
+            };
+            let (target, value) = (target.trim(), value.trim());
+
+            if target == "turbo_period" {
+                match value.parse::<u32>() {
+                    Ok(period) if period >= 2 => bindings.turbo_period = period,
+                    _ => return Err(BindingError::BadValue(number, value.to_string())),
+                }
+                continue;
+            }
+
+            let Some((player, button)) = target.split_once('.') else {
+                return Err(BindingError::Syntax(number));
+            };
+
+            // Players are numbered from 1
+            let player = match player.trim_start_matches(['p', 'P']).parse::<usize>() {
+                Ok(p) if (1..=PLAYER_COUNT).contains(&p) => p - 1,
+                _ => return Err(BindingError::UnknownPlayer(number, player.to_string())),
+            };
+
+            // p1.macro.<key> = <steps>
+            if let Some(key) = button.strip_prefix("macro.") {
+                let Some(key) = key_from_name(key) else {
+                    return Err(BindingError::UnknownKey(number, key.to_string()));
+                };
+                let Some(frames) = parse_macro_steps(value) else {
+                    return Err(BindingError::BadValue(number, value.to_string()));
+                };
+                bindings.macros.push((key, Macro { player, frames }));
+                continue;
+            }
+
+            // p1.turbo.<button> = <key>[*<period>]...
+            let (turbo, button) = match button.strip_prefix("turbo.") {
+                Some(button) => (true, button),
+                None => (false, button),
+            };
+            let Some(button) = button_from_name(button) else {
+                return Err(BindingError::UnknownButton(number, button.to_string()));
+            };
+
+            for key in value.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+                if turbo {
+                    match parse_turbo_key(key) {
+                        Some((key, Some(period))) => {
+                            bindings.turbo.push((player, key, button, period))
+                        }
+                        Some((key, None)) => default_turbo.push((player, key, button)),
+                        None if key.contains('*') => {
+                            return Err(BindingError::BadValue(number, key.to_string()))
+                        }
+                        None => return Err(BindingError::UnknownKey(number, key.to_string())),
+                    }
+                    continue;
+                }
+                match key_from_name(key) {
+                    Some(key) => bindings.bindings.push((player, key, button)),
+                    None => return Err(BindingError::UnknownKey(number, key.to_string())),
+                }
+            }
+        }
+        for (player, key, button) in default_turbo {
+            bindings.turbo.push((player, key, button, bindings.turbo_period));
+        }
+        Ok(bindings)
+    }
+
+    /// Profile text that parses back into these bindings
+    pub fn to_config(&self) -> String {
+        let mut text = String::from(
+            "# Key bindings, <player>.<button> = <key>[, <key>...]\n\
+             # Buttons: a b select start up down left right\n\
+             # Keys: A-Z, Key0-Key9, Kp0-Kp9, Up, Down, Left, Right, Space, Enter, LeftShift, ...\n\
+             #\n\
+             # <player>.turbo.<button> = <key>[*<frames>]... fires the button every <frames>\n\
+             # frames, or every turbo_period frames if the key doesn't say\n\
+             # <player>.macro.<key> = <buttons>[*<frames>], ... plays the steps when the key is\n\
+             # pressed, e.g. p1.macro.F1 = down*2, down+right*2, right+a\n",
+        );
+        text.push_str(&format!("\nturbo_period = {}\n", self.turbo_period));
+
+        let key_list = |list: &[(usize, KeyCode, u8)], player: usize, bit: u8| -> String {
+            let keys: Vec<&str> = list
+                .iter()
+                .filter(|(p, _, b)| *p == player && *b == bit)
+                .map(|(_, key, _)| key_name(*key))
+                .collect();
+            keys.join(", ")
+        };
+
+        for player in 0..PLAYER_COUNT {
+            text.push('\n');
+            for (name, bit) in BUTTON_NAMES {
+                let line = format!("p{}.{name} = {}", player + 1, key_list(&self.bindings, player, bit));
+                text.push_str(line.trim_end());
+                text.push('\n');
+            }
+            for (name, bit) in BUTTON_NAMES {
+                let keys: Vec<String> = self
+                    .turbo
+                    .iter()
+                    .filter(|(p, _, b, _)| *p == player && *b == bit)
+                    .map(|(_, key, _, period)| format!("{}*{period}", key_name(*key)))
+                    .collect();
+                let keys = keys.join(", ");
+                if !keys.is_empty() {
+                    text.push_str(&format!("p{}.turbo.{name} = {keys}\n", player + 1));
+                }
+            }
+            for (key, m) in self.macros.iter().filter(|(_, m)| m.player == player) {
+                let steps = macro_steps_text(&m.frames);
+                text.push_str(&format!("p{}.macro.{} = {steps}\n", player + 1, key_name(*key)));
+            }
+        }
+        text
+    }
+
+    /// Buttons held by every player right now
+    pub fn read_keyboard(&self) -> [u8; PLAYER_COUNT] {
+        let mut buttons = [0; PLAYER_COUNT];
+        for (player, key, button) in &self.bindings {
+            if is_key_down(*key) {
+                buttons[*player] |= button;
+            }
+        }
+        buttons
+    }
+}
+
+// The keyboard never runs out
+impl InputSource for KeyBindings {
+    fn next_frame(&mut self) -> Option<FrameInput> {
+        let mut input = FrameInput {
+            buttons: self.read_keyboard(),
+            ..Default::default()
+        };
+
+        let turbo: Vec<Turbo> = self
+            .turbo
+            .iter()
+            .filter(|(_, key, _, _)| is_key_down(*key))
+            .map(|&(player, _, buttons, period)| Turbo { player, buttons, period })
+            .collect();
+        for (key, m) in &self.macros {
+            if is_key_pressed(*key) {
+                self.autofire.start(m);
+            }
+        }
+        self.autofire.apply(&mut input, &turbo);
+        Some(input)
+    }
+}
+//: }}}
+
+//: Profile {{{
+/// Key bindings backed by a profile file, reloaded when the file changes
+pub struct Profile {
+    pub bindings: KeyBindings,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+// Per user config directory, following the platform convention
+fn config_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    if cfg!(windows) {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME").or_else(|| var("HOME").map(|home| home.join(".config")))
+    }
+}
+
+impl Profile {
+    /// Load a named profile, the default profile is created with the built in bindings
+    /// if it doesn't exist yet
+    pub fn load(name: &str) -> Result<Self, BindingError> {
+        let Some(dir) = config_dir() else {
+            // Nowhere to look, run with the built in bindings
+            return Ok(Self {
+                bindings: KeyBindings::default(),
+                path: None,
+                modified: None,
+            });
+        };
+        let path = dir.join("nes_emulator").join(format!("{name}.keys"));
+
+        if name == DEFAULT_PROFILE && !path.exists() {
+            fs::create_dir_all(path.parent().unwrap_or(&dir)).map_err(BindingError::Io)?;
+            fs::write(&path, KeyBindings::default().to_config()).map_err(BindingError::Io)?;
+        }
+
+        let mut profile = Self {
+            bindings: KeyBindings::default(),
+            path: Some(path),
+            modified: None,
+        };
+        profile.reload()?;
+        Ok(profile)
+    }
+
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    // Read the file again, the old bindings are kept if it fails to parse
+    fn reload(&mut self) -> Result<(), BindingError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let text = fs::read_to_string(path).map_err(BindingError::Io)?;
+        // Remember the time even on errors so a bad file is only reported once
+        self.modified = modified;
+        self.bindings = KeyBindings::parse(&text)?;
+        Ok(())
+    }
+
+    /// Reload if the file changed on disk, true if new bindings were loaded
+    pub fn reload_if_changed(&mut self) -> Result<bool, BindingError> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turbo_period_per_binding() {
+        let bindings = KeyBindings::parse("p1.turbo.a = S, X*8\nturbo_period = 6\n").unwrap();
+        let expected = vec![(0, KeyCode::X, BUTTON_A, 8), (0, KeyCode::S, BUTTON_A, 6)];
+        assert_eq!(bindings.turbo, expected);
+
+        let reparsed = KeyBindings::parse(&bindings.to_config()).unwrap();
+        assert_eq!(reparsed.turbo, expected);
+        assert!(KeyBindings::parse("p1.turbo.a = S*1").is_err());
+    }
+}
+//: }}}
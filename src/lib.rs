@@ -1,8 +1,339 @@
+// Vim folding
+// vim:foldmethod=marker
+pub mod apu;
+pub mod audio;
 pub mod bus;
 pub mod cartridge;
+pub mod cheats;
 pub mod cpu;
+pub mod dma;
+#[cfg(feature = "window")]
 pub mod graphics;
 pub mod input;
+#[cfg(feature = "window")]
+pub mod keybinds;
+pub mod mapper;
+pub mod movie;
 pub mod ppu;
 pub mod ram;
+pub mod savestate;
 pub mod utils;
+
+use bus::Bus;
+use cartridge::{Cart, CartError, CartInfo};
+use cheats::Cheats;
+use cpu::{Cpu, CpuEvent};
+use input::{DeviceKind, FrameInput, Input, ZapperInput, COMMAND_POWER, COMMAND_RESET, PORT_COUNT};
+use ppu::Ppu;
+use ram::Ram;
+use savestate::StateError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// NES 2.0 default expansion device values
+const EXPANSION_FOUR_SCORE: u8 = 0x02;
+const EXPANSION_FAMICOM_ADAPTER: u8 = 0x03;
+const EXPANSION_ZAPPER: u8 = 0x08;
+const EXPANSION_TWO_ZAPPERS: u8 = 0x09;
+
+//: Nes {{{
+/// The whole console, owns the cpu, ppu, bus and cartridge.
+/// Frontends load a rom, set the buttons, call run_frame and read back the
+/// frame buffer and audio.
+/// Only NTSC timing is emulated, PAL and Dendy carts (see CartInfo::timing) run at NTSC
+/// speed and pitch, so frontends should warn about them.
+pub struct Nes {
+    cpu: Cpu,
+    ppu: Ppu,
+    bus: Rc<RefCell<Bus>>,
+
+    // Rom image, kept for power cycling
+    rom: Vec<u8>,
+    // Master clock, the cpu runs every 12 ticks and the ppu every 4 (NTSC)
+    clock: u32,
+    // Frames completed since power on
+    frame: u64,
+}
+
+impl Nes {
+    /// Power on with an iNES image
+    pub fn from_rom_bytes(rom: &[u8]) -> Result<Self, CartError> {
+        let cart = Cart::from_bytes(rom)?;
+        let ram = Ram::new();
+
+        // NES 2.0 roms can say which devices the game expects
+        let mut input = Input::new();
+        match cart.info.expansion_device {
+            EXPANSION_ZAPPER => input.plug(1, DeviceKind::Zapper),
+            EXPANSION_TWO_ZAPPERS => {
+                input.plug(0, DeviceKind::Zapper);
+                input.plug(1, DeviceKind::Zapper);
+            }
+            EXPANSION_FOUR_SCORE => input.plug(0, DeviceKind::FourScore),
+            EXPANSION_FAMICOM_ADAPTER => input.plug(0, DeviceKind::FamicomAdapter),
+            _ => {}
+        }
+
+        let bus = Rc::new(RefCell::new(Bus::new(ram, cart, input)));
+        let mut cpu = Cpu::new(Rc::clone(&bus));
+        let ppu = Ppu::new(Rc::clone(&bus));
+        cpu.reset();
+
+        Ok(Self {
+            cpu,
+            ppu,
+            bus,
+            rom: rom.to_vec(),
+            clock: 0,
+            frame: 0,
+        })
+    }
+
+    /// Press the reset button
+    pub fn reset(&mut self) {
+        self.bus.borrow_mut().reset();
+        self.cpu.reset();
+    }
+
+    /// Turn the console off and on again, everything but the rom, battery ram, plugged in
+    /// devices and cheats is lost
+    pub fn power_cycle(&mut self) {
+        let sample_rate = self.sample_rate();
+        let cheats = self.cheats();
+        let save_ram = self.save_ram();
+        let devices: Vec<DeviceKind> = (0..PORT_COUNT).map(|port| self.device_kind(port)).collect();
+        let rom = std::mem::take(&mut self.rom);
+        // The rom loaded once already so it can't fail now
+        *self = Self::from_rom_bytes(&rom).expect("Rom failed to reload");
+        self.set_sample_rate(sample_rate);
+        self.set_cheats(cheats);
+        for (port, kind) in devices.into_iter().enumerate() {
+            self.plug(port, kind);
+        }
+        if let Some(save_ram) = save_ram {
+            // Same cart so the size matches
+            let _ = self.load_save_ram(&save_ram);
+        }
+    }
+
+    // One master clock tick
+    fn tick(&mut self) {
+        // Clock cpu and ppu at their respective clock divides
+        if self.clock.is_multiple_of(12) {
+            self.cpu.clock();
+            self.bus.borrow_mut().clock();
+        }
+        if self.clock.is_multiple_of(4) {
+            self.ppu.clock();
+            if self.ppu.render_frame {
+                self.ppu.render_frame = false;
+                self.frame += 1;
+                self.bus.borrow_mut().apply_ram_cheats();
+            }
+        }
+
+        // 3840 = 16 * 12 * 5 * 4 (NTSC & PAL clock divides)
+        self.clock = (self.clock + 1) % 3840;
+    }
+
+    // True if the next cpu clock starts an instruction (or interrupt)
+    fn cpu_at_boundary(&self) -> bool {
+        let bus = self.bus.borrow();
+        // A DMA asked for but not started yet belongs to the instruction before
+        self.cpu.at_boundary() && bus.oam_dma_page.is_none() && bus.apu.dmc_fetch_addr().is_none()
+    }
+
+    /// Run until the current instruction finishes and the next one is about to start.
+    /// A jammed cpu never gets there, so that runs a single cpu cycle
+    pub fn step_instruction(&mut self) {
+        let mut started = false;
+        loop {
+            let at_boundary = self.cpu_at_boundary() || self.cpu.jammed().is_some();
+            if self.clock.is_multiple_of(12) && at_boundary {
+                if started {
+                    return;
+                }
+                started = true;
+            }
+            self.tick();
+        }
+    }
+
+    /// Run until the ppu moves to the next scanline
+    pub fn step_scanline(&mut self) {
+        let scanline = self.ppu.scanline;
+        while self.ppu.scanline == scanline {
+            self.tick();
+        }
+    }
+
+    /// Run until the ppu finishes a frame
+    pub fn run_frame(&mut self) {
+        let frame = self.frame;
+        while self.frame == frame {
+            self.tick();
+        }
+    }
+
+    /// Last finished frame, 256x240 RGBA
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.ppu.screen[..]
+    }
+
+    /// Frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    /// Audio produced since the last call, mono samples at sample_rate()
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        let mut samples = Vec::new();
+        self.bus.borrow_mut().apu.drain_samples(&mut samples);
+        samples
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.bus.borrow_mut().apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.bus.borrow().apu.sample_rate()
+    }
+
+    /// Buttons held by a player, see the input::BUTTON_* bits.
+    /// Player 0 is on $4016 and player 1 on $4017, players 2 and 3 need a four player
+    /// adapter plugged in
+    pub fn set_buttons(&mut self, player: usize, state: u8) {
+        self.bus.borrow_mut().input_mut().set_buttons(player, state);
+    }
+
+    /// Feed one frame from an InputSource: reset/power commands first, then the buttons
+    pub fn apply_input(&mut self, input: &FrameInput) {
+        if input.commands & COMMAND_POWER != 0 {
+            self.power_cycle();
+        } else if input.commands & COMMAND_RESET != 0 {
+            self.reset();
+        }
+        for (player, buttons) in input.buttons.iter().enumerate() {
+            self.set_buttons(player, *buttons);
+        }
+        self.set_zapper(input.zapper);
+    }
+
+    /// Aim and trigger for any zapper that is plugged in
+    pub fn set_zapper(&mut self, zapper: ZapperInput) {
+        self.bus.borrow_mut().input_mut().set_zapper(zapper);
+    }
+
+    /// Plug a device into a controller port, port 0 is $4016 and port 1 $4017
+    pub fn plug(&mut self, port: usize, kind: DeviceKind) {
+        self.bus.borrow_mut().input_mut().plug(port, kind);
+    }
+
+    pub fn device_kind(&self, port: usize) -> DeviceKind {
+        self.bus.borrow().input().device_kind(port)
+    }
+
+    /// Replace the cheats, see cheats.rs
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        *self.bus.borrow_mut().cheats_mut() = cheats;
+    }
+
+    pub fn cheats(&self) -> Cheats {
+        self.bus.borrow().cheats().clone()
+    }
+
+    /// Switch all cheats on or off without forgetting them
+    pub fn set_cheats_enabled(&mut self, enabled: bool) {
+        self.bus.borrow_mut().cheats_mut().enabled = enabled;
+    }
+
+    pub fn cheats_enabled(&self) -> bool {
+        self.bus.borrow().cheats().enabled
+    }
+
+    /// Switch one cheat on or off, false if there is no such cheat
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        self.bus.borrow_mut().cheats_mut().set_enabled(index, enabled)
+    }
+
+    /// What the rom header says about the cartridge
+    pub fn cart_info(&self) -> CartInfo {
+        self.bus.borrow().cart_info().clone()
+    }
+
+    /// md5 of prg and chr rom, movies use it to check they are played on the right rom
+    pub fn rom_md5(&self) -> [u8; 16] {
+        self.bus.borrow().rom_md5()
+    }
+
+    /// Battery backed cart ram, what belongs in a .sav file
+    /// None if the cart has no battery
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.bus.borrow().save_ram().map(|ram| ram.to_vec())
+    }
+
+    /// Restore battery backed cart ram from a .sav file
+    pub fn load_save_ram(&mut self, data: &[u8]) -> Result<(), CartError> {
+        self.bus.borrow_mut().load_save_ram(data)
+    }
+
+    /// Read the cpu address space without side effects
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.bus.borrow_mut().read(addr, true)
+    }
+
+    /// What the cpu has reported since the last call, like a KIL opcode jamming it
+    pub fn take_cpu_events(&mut self) -> Vec<CpuEvent> {
+        self.cpu.take_events()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    /// Snapshot the whole machine, see savestate.rs
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::save_state(&self.cpu, &self.ppu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        savestate::load_state(&mut self.cpu, &mut self.ppu, data)
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+
+    #[test]
+    fn ppu_runs_three_dots_per_cpu_cycle() {
+        let mut prg = vec![0xEA; 0x8000];
+        prg[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        let mut nes = Nes::from_rom_bytes(&test_rom(0, &prg, &[0; 0x2000])).unwrap();
+
+        // Count clocks by watching the counters move, over 10 wraps of the master clock
+        let (mut cpu_cycles, mut ppu_dots) = (0, 0);
+        for _ in 0..3840 * 10 {
+            let cycl = nes.cpu.cycl;
+            let dot = (nes.ppu.scanline, nes.ppu.cycle);
+            nes.tick();
+            cpu_cycles += (nes.cpu.cycl != cycl) as u32;
+            ppu_dots += ((nes.ppu.scanline, nes.ppu.cycle) != dot) as u32;
+        }
+        assert_eq!(cpu_cycles, 3200);
+        assert_eq!(ppu_dots, 3 * cpu_cycles);
+    }
+}
+//: }}}
@@ -3,108 +3,370 @@
 
 use macroquad::prelude::*;
 use macroquad::window::next_frame;
-use nes_emulator::bus::Bus;
+#[cfg(feature = "live-audio")]
+use nes_emulator::audio::LiveSink;
+use nes_emulator::audio::{AudioSink, WavSink};
 use nes_emulator::bus::{WINDOW_HEIGHT, WINDOW_WIDTH};
-use nes_emulator::cartridge::Cart;
-use nes_emulator::cpu::Cpu;
 use nes_emulator::graphics::window_conf;
-use nes_emulator::input::Input;
-use nes_emulator::ppu::Ppu;
-use nes_emulator::ram::Ram;
-use std::cell::RefCell;
+use nes_emulator::cartridge::Timing;
+use nes_emulator::cheats::Cheats;
+use nes_emulator::input::{DeviceKind, InputSource, ZapperInput};
+use nes_emulator::keybinds::{Profile, DEFAULT_PROFILE};
+use nes_emulator::movie::{Movie, MoviePlayer};
+use nes_emulator::Nes;
 use std::env;
-use std::rc::Rc;
+use std::path::Path;
+
+// How often battery ram is written to the .sav file, about 5 seconds
+const SAV_INTERVAL_FRAMES: u64 = 300;
+// How often the key binding profile is checked for changes, about 1 second
+const KEYS_INTERVAL_FRAMES: u64 = 60;
+
+// Value following a command line flag
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, String> {
+    match args.iter().position(|a| a == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => Ok(Some(value.as_str())),
+            None => Err(format!("{flag} needs a value")),
+        },
+        None => Ok(None),
+    }
+}
+
+// Zapper aim from the mouse, the screen is drawn 3x size in the top left corner
+fn read_mouse() -> ZapperInput {
+    let (x, y) = mouse_position();
+    let (x, y) = (x / 3.0, y / 3.0);
+    let on_screen = x >= 0.0 && y >= 0.0 && x < WINDOW_WIDTH as f32 && y < WINDOW_HEIGHT as f32;
+    ZapperInput {
+        x: if on_screen { x as u8 } else { 0 },
+        y: if on_screen { y as u8 } else { 0xFF },
+        trigger: is_mouse_button_down(MouseButton::Left),
+    }
+}
+
+// Command line flags, the rom path is always the first argument
+//   --wav <file>       Capture audio instead of playing it
+//   --profile <name>   Key binding profile
+//   --play <file>      Play an fm2 movie from power on
+//   --record <file>    Record an fm2 movie from power on, written when the window closes
+//   --zapper           Zapper in port 2
+//   --multitap <type>  Four player adapter, "fourscore" or "famicom"
+struct Flags<'a> {
+    wav: Option<&'a str>,
+    profile: Option<&'a str>,
+    play: Option<&'a str>,
+    record: Option<&'a str>,
+    zapper: bool,
+    multitap: Option<DeviceKind>,
+}
+
+fn parse_flags(args: &[String]) -> Result<Flags<'_>, String> {
+    Ok(Flags {
+        wav: flag_value(args, "--wav")?,
+        profile: flag_value(args, "--profile")?,
+        play: flag_value(args, "--play")?,
+        record: flag_value(args, "--record")?,
+        zapper: args.iter().any(|a| a == "--zapper"),
+        multitap: match flag_value(args, "--multitap")? {
+            None => None,
+            Some("fourscore") => Some(DeviceKind::FourScore),
+            Some("famicom") => Some(DeviceKind::FamicomAdapter),
+            Some(other) => return Err(format!("Unknown multitap {other}")),
+        },
+    })
+}
 
 #[macroquad::main(window_conf)]
 async fn main() {
     let args: Vec<String> = env::args().collect();
 
     // Catridge loaded, currently the path is provided as the first argument
-    let main_cart = match Cart::new(args[1].as_str()) {
-        Ok(c) => c,
+    let rom = match std::fs::read(args[1].as_str()) {
+        Ok(r) => r,
         Err(e) => {
             eprintln!("{e}");
             return;
         }
     };
 
-    // Random Access Memory (both on nes and cart)
-    let mut main_ram = Ram::new(main_cart.header.mirror());
+    // The console, cpu/ppu/bus/cartridge all live inside
+    let mut nes = match Nes::from_rom_bytes(&rom) {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
 
-    // User input handler
-    let mut input = Input::new();
+    // Only NTSC timing is emulated, other regions run too fast and too high pitched
+    let timing = nes.cart_info().timing;
+    if timing == Timing::Pal || timing == Timing::Dendy {
+        eprintln!("Warning: {timing:?} cart, it runs with NTSC timing");
+    }
 
-    // Bus which links everything together
-    let main_bus = Bus::new(&mut main_ram, &main_cart, &mut input);
-    let main_bus_ref = Rc::new(RefCell::new(main_bus));
+    let flags = match parse_flags(&args) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    // Movies start from a clean power on, so the .sav is left alone while one is used
+    let movie_mode = flags.play.is_some() || flags.record.is_some();
 
-    // Central Processing Unit (6502)
-    let mut main_cpu = Cpu::new(Rc::clone(&main_bus_ref));
+    // Battery backed saves live next to the rom as <name>.sav
+    let sav_path = Path::new(&args[1]).with_extension("sav");
+    if nes.save_ram().is_some() && !movie_mode {
+        if let Ok(data) = std::fs::read(&sav_path) {
+            match nes.load_save_ram(&data) {
+                Ok(()) => println!("Loaded save from {}", sav_path.display()),
+                Err(e) => eprintln!("Could not load {}: {e}", sav_path.display()),
+            }
+        }
+    }
+    // Last save written, so unchanged ram isn't rewritten
+    let mut last_save = nes.save_ram();
 
-    // Pixel Processing Unit (2C02)
-    let mut main_ppu = Ppu::new(Rc::clone(&main_bus_ref));
+    // Cheats live next to the rom as <name>.cht, F2 switches them on and off
+    let cht_path = Path::new(&args[1]).with_extension("cht");
+    if let Ok(text) = std::fs::read_to_string(&cht_path) {
+        match Cheats::parse(&text) {
+            Ok(cheats) => {
+                println!("Loaded {} cheats from {}", cheats.list.len(), cht_path.display());
+                nes.set_cheats(cheats);
+            }
+            Err((line, e)) => eprintln!("{}:{line}: {e}", cht_path.display()),
+        }
+    }
 
-    // Move CPU into reset start to start the program
-    main_cpu.reset();
+    // Audio capture, --wav <file> writes everything the APU plays to a wav file
+    let mut audio_sink: Option<Box<dyn AudioSink>> = None;
+    if let Some(path) = flags.wav {
+        match WavSink::create(path, 44_100) {
+            Ok(sink) => audio_sink = Some(Box::new(sink)),
+            Err(e) => {
+                eprintln!("{e}");
+                return;
+            }
+        }
+    }
 
-    // Track clock for timings
-    let mut clock = 0;
+    // Otherwise play it, the game still runs without a sound device
+    #[cfg(feature = "live-audio")]
+    if audio_sink.is_none() {
+        match LiveSink::open() {
+            Ok(sink) => audio_sink = Some(Box::new(sink)),
+            Err(e) => eprintln!("No audio: {e}"),
+        }
+    }
+
+    if let Some(sink) = &audio_sink {
+        nes.set_sample_rate(sink.sample_rate());
+    }
+
+    // Key bindings, --profile <name> picks another profile than the default one
+    let profile_name = flags.profile.unwrap_or(DEFAULT_PROFILE);
+    let mut profile = match Profile::load(profile_name) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Could not load key binding profile {profile_name}: {e}");
+            return;
+        }
+    };
+    if let Some(path) = profile.path() {
+        println!("Key bindings from {}", path.display());
+    }
+
+    if let Some(kind) = flags.multitap {
+        nes.plug(0, kind);
+    }
+    // --zapper plugs a zapper into port 2, aimed with the mouse
+    if flags.zapper {
+        nes.plug(1, DeviceKind::Zapper);
+    }
+
+    let mut player = None;
+    if let Some(path) = flags.play {
+        let movie = match std::fs::read_to_string(path) {
+            Ok(text) => Movie::parse_fm2(&text).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match movie {
+            Ok(movie) => {
+                for (port, kind) in movie.devices().into_iter().enumerate() {
+                    nes.plug(port, kind);
+                }
+                if movie.rom_md5().is_some_and(|md5| md5 != nes.rom_md5()) {
+                    eprintln!("Warning: {path} was recorded with a different rom");
+                }
+                println!("Playing {path}, {} frames", movie.len());
+                player = Some(MoviePlayer::new(movie));
+            }
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                return;
+            }
+        }
+    }
+    let mut recording = flags.record.map(|_| {
+        let name = Path::new(&args[1]).file_stem().unwrap_or_default();
+        let pal = nes.cart_info().timing == Timing::Pal;
+        let devices = [nes.device_kind(0), nes.device_kind(1)];
+        Movie::new(&name.to_string_lossy(), nes.rom_md5(), pal, devices)
+    });
 
     // Flag to pause the game
     let mut pause = false;
 
+    // F5 saves to / F9 loads from this file
+    let state_path = format!("{}.state", args[1]);
+
     // Enable to view pattern table while playing
     let pattern_table_debug_veiw = false;
 
+    // Closing the window writes the .sav first
+    prevent_quit();
+
     loop {
+        // Write the .sav every few seconds and when quitting
+        let quit = is_quit_requested();
+        if !movie_mode && (quit || nes.frame_count().is_multiple_of(SAV_INTERVAL_FRAMES)) {
+            let save = nes.save_ram();
+            if save != last_save {
+                if let Some(data) = &save {
+                    if let Err(e) = std::fs::write(&sav_path, data) {
+                        eprintln!("Could not write {}: {e}", sav_path.display());
+                    }
+                }
+                last_save = save;
+            }
+        }
+        if quit {
+            if let (Some(path), Some(movie)) = (flags.record, &recording) {
+                match std::fs::write(path, movie.to_fm2()) {
+                    Ok(()) => println!("Recorded {} frames to {path}", movie.len()),
+                    Err(e) => eprintln!("Could not write {path}: {e}"),
+                }
+            }
+            break;
+        }
+
+        // Pick up edits to the key binding profile while running
+        if nes.frame_count().is_multiple_of(KEYS_INTERVAL_FRAMES) {
+            match profile.reload_if_changed() {
+                Ok(true) => println!("Reloaded key bindings"),
+                Ok(false) => {}
+                Err(e) => eprintln!("Could not reload key bindings: {e}"),
+            }
+        }
+
         if !pause {
             use std::time::Instant;
             let now = Instant::now();
-            while !main_ppu.render_frame {
-                // Clock cpu and ppu at their respective clock divides
-                // Currently NTSC
-                if clock % 12 == 0 {
-                    main_cpu.clock();
-                }
-                if clock % 4 == 0 {
-                    main_ppu.clock();
-                }
 
-                // 3840 = 16 * 12 * 5 * 4 (NTSC & PAL clock divides)
-                if clock == 3840 {
-                    clock = 0;
-                } else {
-                    clock += 1;
-                }
+            // Input for this frame, from the movie until it runs out then the keyboard
+            let input = player.as_mut().and_then(|p| p.next_frame());
+            if input.is_none() && player.take().is_some() {
+                println!("Movie finished");
+            }
+            let input = input.unwrap_or_else(|| {
+                let mut input = profile.bindings.next_frame().unwrap_or_default();
+                input.zapper = read_mouse();
+                input
+            });
+            nes.apply_input(&input);
+            if let Some(movie) = recording.as_mut() {
+                movie.push(input);
+            }
+
+            nes.run_frame();
+            for event in nes.take_cpu_events() {
+                eprintln!("{event}");
             }
             let elapsed = now.elapsed();
-        }
 
-        // If the ppu has finished drawing to the frame buffer, allow macroquad to render a frame
-        if main_ppu.render_frame || pause {
-            // Pausing logic
-            if is_key_pressed(KeyCode::Space) {
-                pause = !pause;
-                if pause {
-                    println!("Pause");
-                } else {
-                    println!("Play");
+            // Hand this frame's audio to the sink
+            if let Some(sink) = audio_sink.as_mut() {
+                if let Err(e) = sink.write_samples(&nes.audio_samples()) {
+                    eprintln!("{e}");
+                    audio_sink = None;
                 }
             }
+        }
 
-            let delta = get_frame_time();
+        // The ppu has finished drawing to the frame buffer, allow macroquad to render a frame
+        // Pausing logic
+        if is_key_pressed(KeyCode::Space) {
+            pause = !pause;
+            if pause {
+                println!("Pause");
+            } else {
+                println!("Play");
+            }
+        }
+
+        if is_key_pressed(KeyCode::F2) {
+            let enabled = !nes.cheats_enabled();
+            nes.set_cheats_enabled(enabled);
+            println!("Cheats {}", if enabled { "on" } else { "off" });
+        }
+
+        // Save states
+        if is_key_pressed(KeyCode::F5) {
+            let state = nes.save_state();
+            match std::fs::write(&state_path, state) {
+                Ok(()) => println!("Saved state to {state_path}"),
+                Err(e) => eprintln!("Could not save state: {e}"),
+            }
+        }
+        if is_key_pressed(KeyCode::F9) {
+            match std::fs::read(&state_path) {
+                Ok(state) => match nes.load_state(&state) {
+                    Ok(()) => println!("Loaded state from {state_path}"),
+                    Err(e) => eprintln!("Could not load state: {e}"),
+                },
+                Err(e) => eprintln!("Could not load state: {e}"),
+            }
+        }
+
+        let delta = get_frame_time();
+
+        // Drawe from the frame buffer
+        let texture = Texture2D::from_rgba8(WINDOW_WIDTH, WINDOW_HEIGHT, nes.frame_buffer());
+        draw_texture_ex(
+            &texture,
+            0.0,
+            0.0,
+            WHITE,
+            DrawTextureParams {
+                dest_size: Some(Vec2 {
+                    x: WINDOW_WIDTH as f32 * 3.0,
+                    y: WINDOW_HEIGHT as f32 * 3.0,
+                }),
+                source: None,
+                rotation: 0.0,
+                flip_x: false,
+                flip_y: false,
+                pivot: None,
+            },
+        );
+
+        // pattern table debug start
+        if pattern_table_debug_veiw {
+            nes.ppu_mut().fill_pattern_tables();
+            let plane_left = Texture2D::from_rgba8(128, 128, &nes.ppu().pattern_table_left[..]);
+            let plane_right = Texture2D::from_rgba8(128, 128, &nes.ppu().pattern_table_right[..]);
 
-            // Drawe from main_ppu.screen
-            let texture = Texture2D::from_rgba8(WINDOW_WIDTH, WINDOW_HEIGHT, &main_ppu.screen);
             draw_texture_ex(
-                &texture,
-                0.0,
+                &plane_left,
+                WINDOW_WIDTH as f32 * 3.0,
                 0.0,
                 WHITE,
                 DrawTextureParams {
                     dest_size: Some(Vec2 {
-                        x: WINDOW_WIDTH as f32 * 3.0,
-                        y: WINDOW_HEIGHT as f32 * 3.0,
+                        x: (128 * 3) as f32,
+                        y: (128 * 3) as f32,
                     }),
                     source: None,
                     rotation: 0.0,
@@ -114,55 +376,27 @@ async fn main() {
                 },
             );
 
-            // pattern table debug start
-            if pattern_table_debug_veiw {
-                main_ppu.fill_pattern_tables();
-                let plane_left = Texture2D::from_rgba8(128, 128, &main_ppu.pattern_table_left);
-                let plane_right = Texture2D::from_rgba8(128, 128, &main_ppu.pattern_table_right);
-
-                draw_texture_ex(
-                    &plane_left,
-                    WINDOW_WIDTH as f32 * 3.0,
-                    0.0,
-                    WHITE,
-                    DrawTextureParams {
-                        dest_size: Some(Vec2 {
-                            x: (128 * 3) as f32,
-                            y: (128 * 3) as f32,
-                        }),
-                        source: None,
-                        rotation: 0.0,
-                        flip_x: false,
-                        flip_y: false,
-                        pivot: None,
-                    },
-                );
-
-                draw_texture_ex(
-                    &plane_right,
-                    WINDOW_WIDTH as f32 * 3.0 + (128 * 3) as f32,
-                    0.0,
-                    WHITE,
-                    DrawTextureParams {
-                        dest_size: Some(Vec2 {
-                            x: (128 * 3) as f32,
-                            y: (128 * 3) as f32,
-                        }),
-                        source: None,
-                        rotation: 0.0,
-                        flip_x: false,
-                        flip_y: false,
-                        pivot: None,
-                    },
-                );
-            }
-            // pattern table debug end
-
-            // Let macroquad render
-            next_frame().await;
-
-            // Unset render ready flag
-            main_ppu.render_frame = false;
+            draw_texture_ex(
+                &plane_right,
+                WINDOW_WIDTH as f32 * 3.0 + (128 * 3) as f32,
+                0.0,
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(Vec2 {
+                        x: (128 * 3) as f32,
+                        y: (128 * 3) as f32,
+                    }),
+                    source: None,
+                    rotation: 0.0,
+                    flip_x: false,
+                    flip_y: false,
+                    pivot: None,
+                },
+            );
         }
+        // pattern table debug end
+
+        // Let macroquad render
+        next_frame().await;
     }
 }
@@ -0,0 +1,99 @@
+// Vim folding
+// vim:foldmethod=marker
+// Mapper 7 (AxROM)
+// Switchable 32KB prg bank, 8KB chr ram and single screen mirroring picked by the bank register
+#![allow(unused_variables)]
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000; // 32KB
+
+//: Axrom {{{
+pub struct Axrom {
+    /// Bank register
+    /// Layout:
+    /// 0-2 - 32KB prg bank at $8000
+    /// 4   - Nametable (0: lower 1KB of vram, 1: upper)
+    bank: u8,
+    /// AMROM boards AND the written value with rom, NES 2.0 submapper 2
+    bus_conflicts: bool,
+}
+
+impl Axrom {
+    pub fn new(bus_conflicts: bool) -> Self {
+        Self {
+            bank: 0,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        (self.bank & 0x07) as usize * PRG_BANK_SIZE + (addr & 0x7FFF) as usize
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        self.bank = value;
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        (addr & 0x1FFF) as usize
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        if self.bank & 0x10 != 0 {
+            Some(Mirroring::SingleScreenB)
+        } else {
+            Some(Mirroring::SingleScreenA)
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.bank = r.read_u8()?;
+        Ok(())
+    }
+}
+//}}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use crate::mapper::{marked_banks, test_bus};
+
+    #[test]
+    fn prg_bank_and_nametable_switch() {
+        let mut bus = test_bus(7, &marked_banks(4, 0x8000, 0x30), &[]);
+        for bank in 0..4 {
+            bus.write(0x8000, bank);
+            assert_eq!(bus.read(0x8000, false), 0x30 + bank);
+            assert_eq!(bus.read(0xFFFF, false), 0x30 + bank);
+        }
+
+        // Lower 1KB of vram for every nametable
+        bus.write(0x8000, 0x02);
+        bus.ppu_write(0x2000, 0x11);
+        assert_eq!(bus.ppu_read(0x2400), 0x11);
+        assert_eq!(bus.ppu_read(0x2C00), 0x11);
+
+        // Upper 1KB, prg bank from the same write
+        bus.write(0x8000, 0x12);
+        assert_eq!(bus.read(0x8000, false), 0x32);
+        bus.ppu_write(0x2800, 0x22);
+        assert_eq!(bus.ppu_read(0x2000), 0x22);
+        assert_eq!(bus.ppu_read(0x2400), 0x22);
+
+        bus.write(0x8000, 0x02);
+        assert_eq!(bus.ppu_read(0x2800), 0x11);
+    }
+}
+//}}}
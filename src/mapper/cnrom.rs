@@ -0,0 +1,84 @@
+// Vim folding
+// vim:foldmethod=marker
+// Mapper 3 (CNROM)
+// Fixed 16KB or 32KB prg like NROM, switchable 8KB chr bank
+#![allow(unused_variables)]
+use crate::mapper::{Mapper, CHR_BANK_SIZE};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+//: Cnrom {{{
+pub struct Cnrom {
+    /// Number of 16KB prg banks (1 or 2)
+    prg_banks: usize,
+    /// Chr bank at $0000
+    chr_bank: u8,
+    /// Writes are ANDed with rom, everything but NES 2.0 submapper 1 has them
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    pub fn new(prg_banks: usize, bus_conflicts: bool) -> Self {
+        Self {
+            prg_banks,
+            chr_bank: 0,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        // 16KB roms are mirrored into $C000-$FFFF
+        if self.prg_banks > 1 {
+            (addr & 0x7FFF) as usize
+        } else {
+            (addr & 0x3FFF) as usize
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        self.chr_bank = value;
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        self.chr_bank as usize * CHR_BANK_SIZE + (addr & 0x1FFF) as usize
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.chr_bank = r.read_u8()?;
+        Ok(())
+    }
+}
+//}}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use crate::mapper::{marked_banks, test_bus};
+
+    #[test]
+    fn chr_bank_switch() {
+        let mut prg = vec![0xFF; 0x8000];
+        prg[0x0100] = 0x01;
+        let mut bus = test_bus(3, &prg, &marked_banks(4, 0x2000, 0x20));
+        for bank in 0..4 {
+            bus.write(0x8000, bank);
+            assert_eq!(bus.ppu_fetch_pattern(0x0000), 0x20 + bank);
+            assert_eq!(bus.ppu_fetch_pattern(0x1FFF), 0x20 + bank);
+            assert_eq!(bus.read(0x8000, false), 0xFF);
+        }
+
+        // Bus conflict, the rom byte at $8100 is 1 so 3 selects bank 1
+        bus.write(0x8100, 3);
+        assert_eq!(bus.ppu_fetch_pattern(0x0000), 0x21);
+    }
+}
+//}}}
@@ -0,0 +1,84 @@
+// Vim folding
+// vim:foldmethod=marker
+// Mapper 66 (GxROM)
+// Switchable 32KB prg bank and 8KB chr bank from one register
+#![allow(unused_variables)]
+use crate::mapper::{Mapper, CHR_BANK_SIZE};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000; // 32KB
+
+//: Gxrom {{{
+pub struct Gxrom {
+    /// Bank register
+    /// Layout:
+    /// 0-1 - 8KB chr bank
+    /// 4-5 - 32KB prg bank
+    bank: u8,
+}
+
+impl Default for Gxrom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gxrom {
+    pub fn new() -> Self {
+        Self { bank: 0 }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        ((self.bank >> 4) & 0x03) as usize * PRG_BANK_SIZE + (addr & 0x7FFF) as usize
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        self.bank = value;
+    }
+
+    // Discrete logic boards always have bus conflicts
+    fn bus_conflicts(&self) -> bool {
+        true
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        (self.bank & 0x03) as usize * CHR_BANK_SIZE + (addr & 0x1FFF) as usize
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.bank = r.read_u8()?;
+        Ok(())
+    }
+}
+//}}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use crate::mapper::{marked_banks, test_bus};
+
+    #[test]
+    fn prg_and_chr_bank_switch() {
+        let mut prg = marked_banks(2, 0x8000, 0x50);
+        // Register writes go to a byte that is all 1s in both banks, no conflicts
+        prg[0x0100] = 0xFF;
+        prg[0x8100] = 0xFF;
+        let mut bus = test_bus(66, &prg, &marked_banks(4, 0x2000, 0x20));
+        for prg_bank in 0..2 {
+            for chr_bank in 0..4 {
+                bus.write(0x8100, prg_bank << 4 | chr_bank);
+                assert_eq!(bus.read(0x8000, false), 0x50 + prg_bank);
+                assert_eq!(bus.read(0xFFFF, false), 0x50 + prg_bank);
+                assert_eq!(bus.ppu_fetch_pattern(0x0000), 0x20 + chr_bank);
+                assert_eq!(bus.ppu_fetch_pattern(0x1FFF), 0x20 + chr_bank);
+            }
+        }
+    }
+}
+//}}}
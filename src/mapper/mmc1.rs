@@ -0,0 +1,214 @@
+// Vim folding
+// vim:foldmethod=marker
+// Mapper 1 (MMC1)
+// Registers are written one bit at a time through a serial shift register
+use crate::cartridge::Mirroring;
+use crate::mapper::{Mapper, PRG_BANK_SIZE};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Shift register value after a reset, the 1 marks when five bits have been written
+const SHIFT_RESET: u8 = 0x10;
+
+//: Mmc1 {{{
+pub struct Mmc1 {
+    /// Number of 16KB prg banks
+    prg_banks: usize,
+
+    /// Serial shift register
+    shift: u8,
+
+    /// Control register
+    /// Layout:
+    /// 0-1 - Mirroring (0: one-screen lower, 1: one-screen upper, 2: vertical, 3: horizontal)
+    /// 2-3 - Prg bank mode (0, 1: 32KB; 2: fix first bank at $8000; 3: fix last bank at $C000)
+    /// 4   - Chr bank mode (0: 8KB, 1: two 4KB banks)
+    control: u8,
+    /// Chr bank for $0000 (or both halves in 8KB mode)
+    chr_bank_zero: u8,
+    /// Chr bank for $1000
+    chr_bank_one: u8,
+    /// Prg bank, bit 4 is prg ram disable
+    prg_bank: u8,
+    /// Cpu cycles since the last write, saturating
+    /// A write on the cycle right after another is ignored, which is what the dummy write
+    /// of a read-modify-write instruction (INC $FFFF to reset) relies on
+    write_age: u8,
+}
+//}}}
+
+//: Mmc1 Functions {{{
+impl Mmc1 {
+    pub fn new(prg_banks: usize) -> Self {
+        Self {
+            prg_banks,
+            shift: SHIFT_RESET,
+            // Power on in prg mode 3 so the reset vector is in the fixed last bank
+            control: 0x0C,
+            chr_bank_zero: 0,
+            chr_bank_one: 0,
+            prg_bank: 0,
+            write_age: u8::MAX,
+        }
+    }
+
+    // 512KB boards (SUROM) use bit 4 of the chr bank to pick which 256KB half of prg is used
+    fn prg_outer_bank(&self) -> usize {
+        if self.prg_banks > 16 {
+            (self.chr_bank_zero & 0x10) as usize
+        } else {
+            0
+        }
+    }
+
+    // Write a full five bit value to the register picked by address bits 13-14
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match (addr >> 13) & 0x03 {
+            0 => self.control = value,
+            1 => self.chr_bank_zero = value,
+            2 => self.chr_bank_one = value,
+            _ => self.prg_bank = value,
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        let outer = self.prg_outer_bank();
+        let bank = outer | (self.prg_bank & 0x0F) as usize;
+        let offset = (addr & 0x3FFF) as usize;
+
+        match (self.control >> 2) & 0x03 {
+            // 32KB mode, low bit of bank number ignored
+            0 | 1 => (bank & !1) * PRG_BANK_SIZE + (addr & 0x7FFF) as usize,
+            // First bank fixed at $8000, switch $C000
+            2 => {
+                if addr < 0xC000 {
+                    outer * PRG_BANK_SIZE + offset
+                } else {
+                    bank * PRG_BANK_SIZE + offset
+                }
+            }
+            // Switch $8000, last bank fixed at $C000
+            _ => {
+                if addr < 0xC000 {
+                    bank * PRG_BANK_SIZE + offset
+                } else {
+                    let last = (outer | 0x0F).min(self.prg_banks.saturating_sub(1));
+                    last * PRG_BANK_SIZE + offset
+                }
+            }
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        let consecutive = self.write_age < 2;
+        self.write_age = 0;
+        if consecutive {
+            return;
+        }
+
+        if value & 0x80 != 0 {
+            // Reset shift register and lock the last prg bank at $C000
+            self.shift = SHIFT_RESET;
+            self.control |= 0x0C;
+            return;
+        }
+
+        // Fifth write when the marker bit reaches the bottom
+        let complete = self.shift & 1 != 0;
+        self.shift = (self.shift >> 1) | ((value & 1) << 4);
+
+        if complete {
+            self.write_register(addr, self.shift);
+            self.shift = SHIFT_RESET;
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.write_age = self.write_age.saturating_add(1);
+    }
+
+    fn prg_ram_map(&self, addr: u16, write: bool) -> Option<usize> {
+        if self.prg_bank & 0x10 != 0 {
+            None
+        } else {
+            Some((addr & 0x1FFF) as usize)
+        }
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        if self.control & 0x10 == 0 {
+            // 8KB mode, low bit of bank number ignored
+            (self.chr_bank_zero & 0x1E) as usize * 0x1000 + (addr & 0x1FFF) as usize
+        } else if addr < 0x1000 {
+            self.chr_bank_zero as usize * 0x1000 + (addr & 0x0FFF) as usize
+        } else {
+            self.chr_bank_one as usize * 0x1000 + (addr & 0x0FFF) as usize
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0x03 {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.shift);
+        w.write_u8(self.control);
+        w.write_u8(self.chr_bank_zero);
+        w.write_u8(self.chr_bank_one);
+        w.write_u8(self.prg_bank);
+        w.write_u8(self.write_age);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.shift = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.chr_bank_zero = r.read_u8()?;
+        self.chr_bank_one = r.read_u8()?;
+        self.prg_bank = r.read_u8()?;
+        self.write_age = r.read_u8()?;
+        Ok(())
+    }
+}
+//}}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::test_rom;
+    use crate::Nes;
+
+    #[test]
+    fn rmw_dummy_write_is_ignored() {
+        // Banks 0-2 are filled with their own marker, code lives in the fixed last bank
+        let mut prg = vec![0u8; 4 * 0x4000];
+        for (bank, marker) in [0xFF, 0x11, 0x22].into_iter().enumerate() {
+            prg[bank * 0x4000..(bank + 1) * 0x4000].fill(marker);
+        }
+        let code = [
+            0x78, // SEI
+            0xEE, 0x00, 0x80, // INC $8000, writes $FF (reset) then $00 on the next cycle
+            0xA9, 0x02, // LDA #$02
+            0x8D, 0x00, 0xE0, 0x4A, // STA $E000, LSR A
+            0x8D, 0x00, 0xE0, 0x4A,
+            0x8D, 0x00, 0xE0, 0x4A,
+            0x8D, 0x00, 0xE0, 0x4A,
+            0x8D, 0x00, 0xE0, // Fifth bit selects prg bank 2
+            0x4C, 0x19, 0xC0, // JMP *
+        ];
+        prg[0xC000..0xC000 + code.len()].copy_from_slice(&code);
+        prg[0xFFFA..].copy_from_slice(&[0x19, 0xC0, 0x00, 0xC0, 0x19, 0xC0]);
+
+        let mut nes = Nes::from_rom_bytes(&test_rom(1, &prg, &[])).unwrap();
+        for _ in 0..20 {
+            nes.step_instruction();
+        }
+        assert_eq!(nes.peek(0x8000), 0x22);
+    }
+}
+//: }}}
@@ -0,0 +1,247 @@
+// Vim folding
+// vim:foldmethod=marker
+// Mapper 4 (MMC3)
+// 8KB prg banks, 1KB/2KB chr banks and a scanline counter clocked by PPU A12
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000; // 8KB
+const CHR_BANK_SIZE: usize = 0x0400; // 1KB
+// M2 falling edges A12 has to stay low for before a rise clocks the scanline counter
+const A12_LOW_CYCLES: u8 = 3;
+
+//: Mmc3 {{{
+pub struct Mmc3 {
+    /// Number of 8KB prg banks
+    prg_banks: usize,
+
+    /// Bank select register
+    /// Layout:
+    /// 0-2 - Which bank register the next bank data write goes to
+    /// 6   - Prg bank mode (0: $8000 swappable, $C000 fixed; 1: the other way around)
+    /// 7   - Chr A12 inversion (0: 2KB banks at $0000; 1: 2KB banks at $1000)
+    bank_select: u8,
+    /// Bank registers R0-R7
+    /// R0, R1 - 2KB chr banks
+    /// R2-R5  - 1KB chr banks
+    /// R6, R7 - 8KB prg banks
+    banks: [u8; 8],
+    /// 0: vertical, 1: horizontal
+    horizontal_mirroring: bool,
+
+    // Scanline counter
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enable: bool,
+    irq_pending: bool,
+    // Last seen state of PPU A12, the counter clocks on a rising edge
+    last_a12: bool,
+    // Cpu cycles A12 has been low, saturating. A rise only counts after A12 has been low
+    // for a few cycles, which filters out the quick toggles between sprite fetches
+    a12_low_cycles: u8,
+}
+//}}}
+
+//: Mmc3 Functions {{{
+impl Mmc3 {
+    pub fn new(prg_banks: usize) -> Self {
+        Self {
+            prg_banks,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            horizontal_mirroring: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enable: false,
+            irq_pending: false,
+            last_a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    // Clocked once per scanline (when rendering) by the rise of A12
+    fn clock_scanline_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enable {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        let second_last = self.prg_banks.saturating_sub(2);
+        let last = self.prg_banks.saturating_sub(1);
+        let swap_mode = self.bank_select & 0x40 != 0;
+
+        let bank = match (addr >> 13) & 0x03 {
+            // $8000-$9FFF
+            0 => {
+                if swap_mode {
+                    second_last
+                } else {
+                    (self.banks[6] & 0x3F) as usize
+                }
+            }
+            // $A000-$BFFF
+            1 => (self.banks[7] & 0x3F) as usize,
+            // $C000-$DFFF
+            2 => {
+                if swap_mode {
+                    (self.banks[6] & 0x3F) as usize
+                } else {
+                    second_last
+                }
+            }
+            // $E000-$FFFF
+            _ => last,
+        };
+
+        bank * PRG_BANK_SIZE + (addr & 0x1FFF) as usize
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        let even = addr & 1 == 0;
+        match (addr & 0xE000, even) {
+            (0x8000, true) => self.bank_select = value,
+            (0x8000, false) => self.banks[(self.bank_select & 0x07) as usize] = value,
+            (0xA000, true) => self.horizontal_mirroring = value & 1 != 0,
+            (0xA000, false) => {} // Prg ram protect, ignored as MMC6 boards share mapper 4
+            (0xC000, true) => self.irq_latch = value,
+            (0xC000, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000, true) => {
+                // Disabling also acknowledges any pending interrupt
+                self.irq_enable = false;
+                self.irq_pending = false;
+            }
+            _ => self.irq_enable = true,
+        }
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        // Inversion swaps the 2KB and 1KB halves
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+
+        let bank = match (addr >> 10) & 0x07 {
+            0 => self.banks[0] & 0xFE,
+            1 => self.banks[0] | 0x01,
+            2 => self.banks[1] & 0xFE,
+            3 => self.banks[1] | 0x01,
+            n => self.banks[(n - 2) as usize],
+        };
+
+        bank as usize * CHR_BANK_SIZE + (addr & 0x03FF) as usize
+    }
+
+    fn ppu_fetch(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.last_a12 && self.a12_low_cycles >= A12_LOW_CYCLES {
+            self.clock_scanline_counter();
+        }
+        if !a12 && self.last_a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.last_a12 = a12;
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.last_a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        if self.horizontal_mirroring {
+            Some(Mirroring::Horizontal)
+        } else {
+            Some(Mirroring::Vertical)
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.banks);
+        w.write_bool(self.horizontal_mirroring);
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enable);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.last_a12);
+        w.write_u8(self.a12_low_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.bank_select = r.read_u8()?;
+        r.read_into(&mut self.banks)?;
+        self.horizontal_mirroring = r.read_bool()?;
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enable = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.last_a12 = r.read_bool()?;
+        self.a12_low_cycles = r.read_u8()?;
+        Ok(())
+    }
+}
+//}}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a12_rises_need_low_time() {
+        let mut mmc3 = Mmc3::new(4);
+        mmc3.cpu_write(0xC000, 10); // Latch
+        mmc3.cpu_write(0xC001, 0); // Reload on the next clock
+
+        // Background from $0000 for a while, then the first sprite fetch from $1000
+        mmc3.ppu_fetch(0x0000);
+        for _ in 0..20 {
+            mmc3.cpu_clock();
+        }
+        mmc3.ppu_fetch(0x1000);
+        assert_eq!(mmc3.irq_counter, 10);
+
+        // 8x16 sprites mixing tables, A12 is only low for a cycle at a time
+        for _ in 0..8 {
+            mmc3.ppu_fetch(0x0000);
+            mmc3.cpu_clock();
+            mmc3.ppu_fetch(0x1000);
+            mmc3.cpu_clock();
+        }
+        assert_eq!(mmc3.irq_counter, 10);
+
+        // Next scanline
+        mmc3.ppu_fetch(0x0000);
+        for _ in 0..A12_LOW_CYCLES {
+            mmc3.cpu_clock();
+        }
+        mmc3.ppu_fetch(0x1000);
+        assert_eq!(mmc3.irq_counter, 9);
+    }
+}
+//}}}
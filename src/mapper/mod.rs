@@ -0,0 +1,101 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(unused_variables)]
+pub mod axrom;
+pub mod cnrom;
+pub mod gxrom;
+pub mod mmc1;
+pub mod mmc3;
+pub mod nrom;
+pub mod uxrom;
+
+use crate::cartridge::{CartInfo, Mirroring};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use axrom::Axrom;
+use cnrom::Cnrom;
+use gxrom::Gxrom;
+use mmc1::Mmc1;
+use mmc3::Mmc3;
+use nrom::Nrom;
+use uxrom::Uxrom;
+
+pub const PRG_BANK_SIZE: usize = 0x4000; // 16KB
+pub const CHR_BANK_SIZE: usize = 0x2000; // 8KB
+
+//: Mapper {{{
+/// Mappers sit between the cartridge memory and the cpu/ppu busses.
+/// They decide which bank of prg/chr is visible at a given address, and usually have
+/// registers that are written through $8000-$FFFF.
+pub trait Mapper {
+    /// Map a cpu address ($8000-$FFFF) to an offset into prg rom
+    fn cpu_map_read(&self, addr: u16) -> usize;
+    /// Handle a cpu write to $8000-$FFFF (bank select registers and the like)
+    fn cpu_write(&mut self, addr: u16, value: u8);
+    /// Map a ppu address ($0000-$1FFF) to an offset into chr
+    fn ppu_map_read(&self, addr: u16) -> usize;
+    /// Discrete logic boards where the rom drives the data bus during register writes,
+    /// the mapper sees the written value ANDed with the rom byte at that address
+    fn bus_conflicts(&self) -> bool {
+        false
+    }
+    /// Map a cpu address ($6000-$7FFF) to an offset into prg ram
+    /// None while the mapper has the ram disabled
+    fn prg_ram_map(&self, addr: u16, write: bool) -> Option<usize> {
+        Some((addr & 0x1FFF) as usize)
+    }
+    /// Called on every pattern table fetch the ppu makes while rendering
+    /// Mappers that count scanlines (MMC3) watch address line A12 here
+    fn ppu_fetch(&mut self, addr: u16) {}
+    /// Called once per cpu cycle (falling edge of M2), for mappers that time things in
+    /// cpu cycles
+    fn cpu_clock(&mut self) {}
+    /// True while the mapper is pulling the cpu's IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+    /// Nametable mirroring picked by the mapper, None if the header decides
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+    /// Write the mapper's registers into a save state
+    fn save_state(&self, w: &mut StateWriter) {}
+    /// Restore the mapper's registers from a save state
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+//}}}
+
+//: new_mapper {{{
+/// Creates the mapper for the board described by the header
+pub fn new_mapper(info: &CartInfo) -> Option<Box<dyn Mapper>> {
+    let prg_banks = info.prg_rom_size / PRG_BANK_SIZE;
+    match info.mapper {
+        0 => Some(Box::new(Nrom::new(prg_banks))),
+        1 => Some(Box::new(Mmc1::new(prg_banks))),
+        // NES 2.0 submappers: 1 no bus conflicts, 2 bus conflicts
+        2 => Some(Box::new(Uxrom::new(prg_banks, info.submapper == 2))),
+        3 => Some(Box::new(Cnrom::new(prg_banks, info.submapper != 1))),
+        4 => Some(Box::new(Mmc3::new(info.prg_rom_size / 0x2000))),
+        7 => Some(Box::new(Axrom::new(info.submapper == 2))),
+        66 => Some(Box::new(Gxrom::new())),
+        _ => None,
+    }
+}
+//}}}
+
+//: Test helpers {{{
+/// Bus with a test cart plugged in, see cartridge::test_rom
+#[cfg(test)]
+pub fn test_bus(mapper: u8, prg: &[u8], chr: &[u8]) -> crate::bus::Bus {
+    use crate::cartridge::{test_rom, Cart};
+    let cart = Cart::from_bytes(&test_rom(mapper, prg, chr)).unwrap();
+    crate::bus::Bus::new(crate::ram::Ram::new(), cart, crate::input::Input::new())
+}
+
+/// count banks of size bytes, each filled with marker + its bank number
+#[cfg(test)]
+pub fn marked_banks(count: usize, size: usize, marker: u8) -> Vec<u8> {
+    (0..count).flat_map(|bank| vec![marker + bank as u8; size]).collect()
+}
+//}}}
@@ -0,0 +1,34 @@
+// Mapper 0
+// No bank switching, 16KB or 32KB of prg and 8KB of chr
+#![allow(unused_variables)]
+use crate::mapper::Mapper;
+
+pub struct Nrom {
+    /// Number of 16KB prg banks (1 or 2)
+    prg_banks: usize,
+}
+
+impl Nrom {
+    pub fn new(prg_banks: usize) -> Self {
+        Self { prg_banks }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        // 16KB roms are mirrored into $C000-$FFFF
+        if self.prg_banks > 1 {
+            (addr & 0x7FFF) as usize
+        } else {
+            (addr & 0x3FFF) as usize
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        // No registers
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        (addr & 0x1FFF) as usize
+    }
+}
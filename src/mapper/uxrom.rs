@@ -0,0 +1,84 @@
+// Vim folding
+// vim:foldmethod=marker
+// Mapper 2 (UxROM)
+// Switchable 16KB prg bank at $8000, last bank fixed at $C000, 8KB chr ram
+#![allow(unused_variables)]
+use crate::mapper::{Mapper, PRG_BANK_SIZE};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+//: Uxrom {{{
+pub struct Uxrom {
+    /// Number of 16KB prg banks
+    prg_banks: usize,
+    /// Prg bank at $8000
+    prg_bank: u8,
+    /// UNROM boards AND the written value with rom, NES 2.0 submapper 2
+    bus_conflicts: bool,
+}
+
+impl Uxrom {
+    pub fn new(prg_banks: usize, bus_conflicts: bool) -> Self {
+        Self {
+            prg_banks,
+            prg_bank: 0,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_map_read(&self, addr: u16) -> usize {
+        let bank = if addr < 0xC000 {
+            self.prg_bank as usize
+        } else {
+            self.prg_banks.saturating_sub(1)
+        };
+        bank * PRG_BANK_SIZE + (addr & 0x3FFF) as usize
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        self.prg_bank = value;
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize {
+        (addr & 0x1FFF) as usize
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = r.read_u8()?;
+        Ok(())
+    }
+}
+//}}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use crate::mapper::{marked_banks, test_bus};
+
+    #[test]
+    fn prg_bank_switch() {
+        let mut bus = test_bus(2, &marked_banks(4, 0x4000, 0x10), &[]);
+        for bank in 0..4 {
+            bus.write(0x8000, bank);
+            assert_eq!(bus.read(0x8000, false), 0x10 + bank);
+            assert_eq!(bus.read(0xBFFF, false), 0x10 + bank);
+            // Last bank stays at $C000
+            assert_eq!(bus.read(0xC000, false), 0x13);
+        }
+
+        // Chr ram isn't banked
+        bus.ppu_write(0x1234, 0x5A);
+        bus.write(0x8000, 1);
+        assert_eq!(bus.ppu_fetch_pattern(0x1234), 0x5A);
+    }
+}
+//}}}
@@ -0,0 +1,366 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+use crate::input::{
+    DeviceKind, FrameInput, InputSource, ZapperInput, COMMAND_POWER, COMMAND_RESET, PLAYER_COUNT,
+    PORT_COUNT,
+};
+use crate::utils;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Input movies, one FrameInput per frame from power on, stored as FCEUX .fm2 text
+//
+// An fm2 file is a header of "<key> <value>" lines followed by one line per frame:
+//   |<commands>|RLDUTSBA|RLDUTSBA||
+// Any character other than '.' or ' ' in a controller field means the button is held.
+// A zapper field is "<x> <y> <trigger> <bogo> <zaphit>", only the first three matter here.
+// With a four score there are four controller fields, one per player. The Famicom adapter
+// is stored the same way with an extra "famicom4p 1" header line.
+
+// fm2 port types
+const PORT_NONE: &str = "0";
+const PORT_GAMEPAD: &str = "1";
+const PORT_ZAPPER: &str = "2";
+
+// Button order in an fm2 controller field, highest bit first
+const FM2_BUTTONS: &str = "RLDUTSBA";
+
+//: MovieError {{{
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    /// Line can't be parsed
+    BadLine(usize),
+    /// Movie uses something this emulator can't play back
+    Unsupported(String),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::BadLine(line) => write!(f, "Movie line {line} is malformed"),
+            MovieError::Unsupported(what) => write!(f, "Movie uses unsupported {what}"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+//: }}}
+
+//: Movie {{{
+pub struct Movie {
+    // Header lines in file order, comments and unknown keys are kept
+    header: Vec<(String, String)>,
+    // Devices on each port, from the port0/port1 header lines
+    devices: [DeviceKind; PORT_COUNT],
+    pub frames: Vec<FrameInput>,
+}
+
+impl Movie {
+    /// Empty movie for a rom, ready to record into
+    pub fn new(rom_name: &str, rom_md5: [u8; 16], pal: bool, devices: [DeviceKind; PORT_COUNT]) -> Self {
+        // Any unique value will do for the guid
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let id = utils::md5(&[rom_md5.as_slice(), &nanos.to_le_bytes()].concat());
+        let hex: String = id.iter().map(|b| format!("{b:02X}")).collect();
+        let guid = format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        );
+
+        let header = [
+            ("version", "3".to_string()),
+            ("emuVersion", "22020".to_string()),
+            ("rerecordCount", "0".to_string()),
+            ("palFlag", (pal as u8).to_string()),
+            ("romFilename", rom_name.to_string()),
+            ("romChecksum", format!("base64:{}", utils::base64_encode(&rom_md5))),
+            ("guid", guid),
+            ("fourscore", (devices[0].is_multitap() as u8).to_string()),
+            ("microphone", "0".to_string()),
+            ("port0", port_type(devices[0]).to_string()),
+            ("port1", port_type(devices[1]).to_string()),
+            ("port2", "0".to_string()),
+            ("FDS", "0".to_string()),
+            ("NewPPU", "0".to_string()),
+        ];
+        let mut movie = Self {
+            header: header.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            devices,
+            frames: Vec::new(),
+        };
+        if devices[0] == DeviceKind::FamicomAdapter {
+            movie.set_header("famicom4p", "1");
+        }
+        movie
+    }
+
+    /// First header value for a key
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.header
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        match self.header.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.header.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// md5 of the rom the movie was made with, None if missing or not base64
+    pub fn rom_md5(&self) -> Option<[u8; 16]> {
+        let checksum = self.header("romChecksum")?.strip_prefix("base64:")?;
+        utils::base64_decode(checksum)?.try_into().ok()
+    }
+
+    pub fn push(&mut self, input: FrameInput) {
+        self.frames.push(input);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Devices the movie was recorded with, they need to be plugged in before playing
+    pub fn devices(&self) -> [DeviceKind; PORT_COUNT] {
+        self.devices
+    }
+
+    // Devices from the header
+    fn parse_devices(&self) -> Result<[DeviceKind; PORT_COUNT], MovieError> {
+        if self.header("fourscore").is_some_and(|v| v != "0") {
+            if self.header("famicom4p").is_some_and(|v| v != "0") {
+                return Ok([DeviceKind::FamicomAdapter; PORT_COUNT]);
+            }
+            return Ok([DeviceKind::FourScore; PORT_COUNT]);
+        }
+        let mut devices = [DeviceKind::None; PORT_COUNT];
+        for (port, kind) in devices.iter_mut().enumerate() {
+            *kind = match self.header(&format!("port{port}")).unwrap_or(PORT_NONE) {
+                PORT_NONE => DeviceKind::None,
+                PORT_GAMEPAD => DeviceKind::Controller,
+                PORT_ZAPPER => DeviceKind::Zapper,
+                other => return Err(MovieError::Unsupported(format!("port{port} device {other}"))),
+            };
+        }
+        Ok(devices)
+    }
+
+    /// Read an fm2 movie
+    pub fn parse_fm2(text: &str) -> Result<Self, MovieError> {
+        let mut movie = Self {
+            header: Vec::new(),
+            devices: [DeviceKind::None; PORT_COUNT],
+            frames: Vec::new(),
+        };
+        let mut header_done = false;
+
+        for (number, line) in text.lines().enumerate() {
+            let number = number + 1;
+            let line = line.trim_end_matches('\r');
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // Header
+            if !line.starts_with('|') && !header_done {
+                let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                if key == "binary" && value != "0" && value != "false" {
+                    return Err(MovieError::Unsupported("binary input".to_string()));
+                }
+                // Movies have to start from power on
+                if key == "savestate" {
+                    return Err(MovieError::Unsupported("savestate start".to_string()));
+                }
+                movie.header.push((key.to_string(), value.to_string()));
+                continue;
+            }
+
+            // Input, the header is complete by the first frame
+            if !header_done {
+                movie.devices = movie.parse_devices()?;
+                header_done = true;
+            }
+            let layout = movie.layout();
+            let fields: Vec<&str> = line.split('|').collect();
+            if fields.len() < 2 + layout.len() {
+                return Err(MovieError::BadLine(number));
+            }
+            let commands = fields[1].trim().parse::<u8>().map_err(|_| MovieError::BadLine(number))?;
+            if commands & !(COMMAND_RESET | COMMAND_POWER) != 0 {
+                return Err(MovieError::Unsupported(format!("command {commands}")));
+            }
+
+            let mut input = FrameInput {
+                commands,
+                ..Default::default()
+            };
+            for (field, kind) in fields[2..].iter().zip(layout) {
+                match kind {
+                    Field::Gamepad(player) => {
+                        if field.len() != FM2_BUTTONS.len() {
+                            return Err(MovieError::BadLine(number));
+                        }
+                        for (i, c) in field.chars().enumerate() {
+                            if c != '.' && c != ' ' {
+                                input.buttons[player] |= 0x80 >> i;
+                            }
+                        }
+                    }
+                    Field::Zapper => {
+                        let values: Vec<&str> = field.split_whitespace().collect();
+                        let [x, y, trigger, ref counters @ ..] = values[..] else {
+                            return Err(MovieError::BadLine(number));
+                        };
+                        // bogo and zaphit are 64 bit counters, checked but otherwise unused
+                        let bad = |_| MovieError::BadLine(number);
+                        for counter in counters {
+                            counter.parse::<u64>().map_err(bad)?;
+                        }
+                        let x = x.parse::<u8>().map_err(bad)?;
+                        let y = y.parse::<u8>().map_err(bad)?;
+                        let trigger = trigger.parse::<u8>().map_err(bad)?;
+                        input.zapper = ZapperInput {
+                            x,
+                            y,
+                            trigger: trigger != 0,
+                        };
+                    }
+                    Field::Empty => {}
+                }
+            }
+            movie.frames.push(input);
+        }
+        if !header_done {
+            movie.devices = movie.parse_devices()?;
+        }
+        Ok(movie)
+    }
+
+    // What each controller field of an input line holds
+    fn layout(&self) -> Vec<Field> {
+        if self.devices[0].is_multitap() {
+            return (0..PLAYER_COUNT).map(Field::Gamepad).collect();
+        }
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(port, kind)| match kind {
+                DeviceKind::Controller => Field::Gamepad(port),
+                DeviceKind::Zapper => Field::Zapper,
+                _ => Field::Empty,
+            })
+            .collect()
+    }
+
+    /// Write as fm2 text
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        for (key, value) in &self.header {
+            text.push_str(&format!("{key} {value}\n"));
+        }
+
+        for input in &self.frames {
+            text.push_str(&format!("|{}|", input.commands));
+            for field in self.layout() {
+                match field {
+                    Field::Gamepad(player) => {
+                        for (i, c) in FM2_BUTTONS.chars().enumerate() {
+                            let held = input.buttons[player] & (0x80 >> i) != 0;
+                            text.push(if held { c } else { '.' });
+                        }
+                    }
+                    Field::Zapper => {
+                        let zapper = input.zapper;
+                        text.push_str(&format!("{} {} {} 0 0", zapper.x, zapper.y, zapper.trigger as u8));
+                    }
+                    Field::Empty => {}
+                }
+                text.push('|');
+            }
+            // Expansion port, unused
+            text.push_str("|\n");
+        }
+        text
+    }
+}
+//: }}}
+
+// Controller field of an input line
+#[derive(Clone, Copy)]
+enum Field {
+    Gamepad(usize),
+    Zapper,
+    Empty,
+}
+
+// fm2 port type for a device, four player adapters are all gamepads
+fn port_type(kind: DeviceKind) -> &'static str {
+    match kind {
+        DeviceKind::None => PORT_NONE,
+        DeviceKind::Controller | DeviceKind::FourScore | DeviceKind::FamicomAdapter => PORT_GAMEPAD,
+        DeviceKind::Zapper => PORT_ZAPPER,
+    }
+}
+
+//: MoviePlayer {{{
+/// Plays a movie back one frame at a time
+pub struct MoviePlayer {
+    movie: Movie,
+    position: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, position: 0 }
+    }
+
+    /// Frames played so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+impl InputSource for MoviePlayer {
+    fn next_frame(&mut self) -> Option<FrameInput> {
+        let input = self.movie.frames.get(self.position).copied();
+        if input.is_some() {
+            self.position += 1;
+        }
+        input
+    }
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zapper_counters_past_255() {
+        let text = "version 3\nport0 2\nport1 0\n|0|120 80 1 4000000000 300|||\n";
+        let movie = Movie::parse_fm2(text).unwrap();
+        let zapper = movie.frames[0].zapper;
+        assert_eq!((zapper.x, zapper.y, zapper.trigger), (120, 80, true));
+    }
+}
+//: }}}
@@ -3,6 +3,7 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 use crate::bus::*;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -33,7 +34,7 @@ use std::rc::Rc;
 //: }}}
 
 //: Ppu {{{
-pub struct Ppu<'a> {
+pub struct Ppu {
     // Data for next tile
     // Nametable index
     pub background_next_nametable: u8,
@@ -82,15 +83,16 @@ pub struct Ppu<'a> {
 
     // Flag when frame is ready to render
     pub render_frame: bool,
-    // RGBA representation of the screen
-    pub screen: [u8; 4 * 256 * 240], // screen pixel buffer
+    // RGBA representation of the screen, the buffers are boxed so moving a Ppu doesn't copy
+    // them across the stack
+    pub screen: Box<[u8; 4 * 256 * 240]>, // screen pixel buffer
 
     // Debug Stuff, Representation of left and right pattern tables
-    pub pattern_table_left: [u8; 4 * 128 * 128],
-    pub pattern_table_right: [u8; 4 * 128 * 128],
+    pub pattern_table_left: Box<[u8; 4 * 128 * 128]>,
+    pub pattern_table_right: Box<[u8; 4 * 128 * 128]>,
 
     // Reference to main bus 
-    pub bus: Rc<RefCell<Bus<'a>>>, 
+    pub bus: Rc<RefCell<Bus>>, 
 }
 //: }}}
 
@@ -180,7 +182,7 @@ impl PpuData {
     /* CPU Registers */
     // PPU_CTRL
     // 0: disable, 1: enable
-    fn get_nmi_enable(&self) -> bool { (self.ctrl & (1 << 7)) != 0 }
+    pub fn get_nmi_enable(&self) -> bool { (self.ctrl & (1 << 7)) != 0 }
     // 0: slave, 1: master
     fn get_master_slave(&self) -> bool { (self.ctrl & (1 << 6)) != 0 }
     // 0: 8x8, 1: 8x16
@@ -211,6 +213,8 @@ impl PpuData {
     fn get_emphasize_green(&self) -> bool { (self.mask & (1 << 6)) != 0 }
     // 0: $2000, 1: $2400, 2: $2800, 3:$2C00
     fn get_emphasize_blue(&self) -> bool { (self.mask & (1 << 7)) != 0 }
+    // Either background or sprites are being drawn
+    pub fn get_rendering_enabled(&self) -> bool { self.get_background_enable() || self.get_sprite_enable() }
 
     // PPU_STATUS
     // Open bus is weird, TODO: make sure to come back to this
@@ -233,9 +237,114 @@ impl PpuData {
 }
 // }}}
 
+//: PpuData SaveState {{{
+impl SaveState for PpuData {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.nmi_occurred);
+        w.write_u8(self.ctrl);
+        w.write_u8(self.mask);
+        w.write_u8(self.status);
+        w.write_u8(self.oam_addr);
+        w.write_bool(self.scroll_latch);
+        w.write_bool(self.addr_latch);
+        w.write_u8(self.data);
+        w.write_u8(self.data_buffer);
+        w.write_u8(self.fine_x_scroll);
+        w.write_u16(self.vram_addr);
+        w.write_u16(self.temp_vram_addr);
+        w.write_bytes(&self.oam);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.nmi_occurred = r.read_bool()?;
+        self.ctrl = r.read_u8()?;
+        self.mask = r.read_u8()?;
+        self.status = r.read_u8()?;
+        self.oam_addr = r.read_u8()?;
+        self.scroll_latch = r.read_bool()?;
+        self.addr_latch = r.read_bool()?;
+        self.data = r.read_u8()?;
+        self.data_buffer = r.read_u8()?;
+        self.fine_x_scroll = r.read_u8()?;
+        self.vram_addr = r.read_u16()?;
+        self.temp_vram_addr = r.read_u16()?;
+        r.read_into(&mut self.oam)
+    }
+}
+// }}}
+
+//: Ppu SaveState {{{
+// The screen and pattern table buffers are outputs, so they are not saved
+impl SaveState for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.background_next_nametable);
+        w.write_u8(self.background_next_attrib);
+        w.write_u8(self.background_next_pattern_low);
+        w.write_u8(self.background_next_pattern_high);
+        w.write_u16(self.background_shift_attrib_low);
+        w.write_u16(self.background_shift_attrib_high);
+        w.write_u16(self.background_shift_pattern_low);
+        w.write_u16(self.background_shift_pattern_high);
+
+        w.write_u8(self.num_next_sprites_found);
+        w.write_u16(self.search_oam_index);
+        w.write_i8(self.search_current_sprite_byte);
+        w.write_bool(self.sprite_zero_on_scanline);
+        for sprite in self.next_scanline_sprites.iter() {
+            w.write_bytes(sprite);
+        }
+        for sprite in self.current_scanline_sprites.iter() {
+            w.write_bytes(sprite);
+        }
+        w.write_bytes(&self.scanline_sprite_patterns_low);
+        w.write_bytes(&self.scanline_sprite_patterns_high);
+        w.write_bytes(&self.scanline_sprite_shift_counters);
+
+        w.write_i16(self.scanline);
+        w.write_i16(self.cycle);
+        w.write_bool(self.even);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.background_next_nametable = r.read_u8()?;
+        self.background_next_attrib = r.read_u8()?;
+        self.background_next_pattern_low = r.read_u8()?;
+        self.background_next_pattern_high = r.read_u8()?;
+        self.background_shift_attrib_low = r.read_u16()?;
+        self.background_shift_attrib_high = r.read_u16()?;
+        self.background_shift_pattern_low = r.read_u16()?;
+        self.background_shift_pattern_high = r.read_u16()?;
+
+        self.num_next_sprites_found = r.read_u8()?;
+        self.search_oam_index = r.read_u16()?;
+        self.search_current_sprite_byte = r.read_i8()?;
+        self.sprite_zero_on_scanline = r.read_bool()?;
+        for sprite in self.next_scanline_sprites.iter_mut() {
+            r.read_into(sprite)?;
+        }
+        for sprite in self.current_scanline_sprites.iter_mut() {
+            r.read_into(sprite)?;
+        }
+        r.read_into(&mut self.scanline_sprite_patterns_low)?;
+        r.read_into(&mut self.scanline_sprite_patterns_high)?;
+        r.read_into(&mut self.scanline_sprite_shift_counters)?;
+
+        self.scanline = r.read_i16()?;
+        self.cycle = r.read_i16()?;
+        self.even = r.read_bool()?;
+        Ok(())
+    }
+}
+// }}}
+
 //: Ppu Functions {{{
-impl<'a> Ppu<'a> {
-    pub fn new(bus: Rc<RefCell<Bus<'a>>>) -> Self {
+// Built on the heap, a [0; N] literal would go through the stack first
+fn zeroed_box<const N: usize>() -> Box<[u8; N]> {
+    vec![0; N].try_into().unwrap()
+}
+
+impl Ppu {
+    pub fn new(bus: Rc<RefCell<Bus>>) -> Self {
         Self {
             background_next_nametable: 0,
             background_next_attrib: 0,
@@ -265,10 +374,10 @@ impl<'a> Ppu<'a> {
             even: true,
 
             render_frame: false,
-            screen: [0x00; 4 * 256 * 240],
+            screen: zeroed_box(),
 
-            pattern_table_left: [0x00; 4 * 128 * 128],
-            pattern_table_right: [0x00; 4 * 128 * 128],
+            pattern_table_left: zeroed_box(),
+            pattern_table_right: zeroed_box(),
 
             bus,
         }
@@ -361,12 +470,9 @@ impl<'a> Ppu<'a> {
     // Start vblank period
     pub fn set_vblank(&mut self) {
         let mut bus = self.bus.borrow_mut();
-        // Set nmi
+        // Set nmi, the cpu sees it through Bus::nmi_line
         bus.ppu_data.set_vblank(true);
         bus.ppu_data.nmi_occurred = true;
-        if bus.ppu_data.get_nmi_enable() {
-            bus.nmi_signal = true;
-        }
     }
 
     // Shift current background patterns
@@ -426,7 +532,7 @@ impl<'a> Ppu<'a> {
 
     // Get the next background pattern low order bits
     pub fn set_background_next_pattern_low(&mut self) {
-        let bus = self.bus.borrow();
+        let mut bus = self.bus.borrow_mut();
         let mut pattern_addr: u16 = bus.ppu_data.get_fine_y_scroll_v() as u16;
 
         pattern_addr += (self.background_next_nametable as u16) << 4;
@@ -435,12 +541,12 @@ impl<'a> Ppu<'a> {
             pattern_addr += 0x1000;
         }
 
-        self.background_next_pattern_low = bus.ppu_read(pattern_addr);
+        self.background_next_pattern_low = bus.ppu_fetch_pattern(pattern_addr);
     }
 
     // Get the next background pattern high order bits
     pub fn set_background_next_pattern_high(&mut self) {
-        let bus = self.bus.borrow();
+        let mut bus = self.bus.borrow_mut();
         let mut pattern_addr: u16 = bus.ppu_data.get_fine_y_scroll_v() as u16;
 
         pattern_addr += (self.background_next_nametable as u16) << 4;
@@ -451,18 +557,27 @@ impl<'a> Ppu<'a> {
 
         pattern_addr += 8;
 
-        self.background_next_pattern_high = bus.ppu_read(pattern_addr);
+        self.background_next_pattern_high = bus.ppu_fetch_pattern(pattern_addr);
     }
 
     // Get the next sprite patterns
     // low : low vs high sprite pattern bits
     pub fn set_sprite_next_pattern(&mut self, low: bool) {
-        let bus = self.bus.borrow();
+        let mut bus = self.bus.borrow_mut();
         let current_sprite: usize = ((self.cycle - 256) / 8) as usize;
 
         // Checks for bad sprite data
         if current_sprite >= self.num_next_sprites_found as usize
         {
+            // Empty slots still fetch tile $FF, which mappers watching A12 can see
+            let mut dummy_addr: u16 = 0x0FF0;
+            if bus.ppu_data.get_sprite_size() || bus.ppu_data.get_sprite_table_select() {
+                dummy_addr += 0x1000;
+            }
+            if !low {
+                dummy_addr += 8;
+            }
+            bus.ppu_fetch_pattern(dummy_addr);
             return;
         }
 
@@ -542,7 +657,7 @@ impl<'a> Ppu<'a> {
             pattern_addr += 8;
         }
 
-        let mut tmp_pattern = bus.ppu_read(pattern_addr);
+        let mut tmp_pattern = bus.ppu_fetch_pattern(pattern_addr);
 
         // Horizontal Flip
         if self.next_scanline_sprites[current_sprite][2] & 0x40 == 0 {
@@ -755,22 +870,19 @@ impl<'a> Ppu<'a> {
             self.screen[(offset + 1) as usize] = true_pixel.g;
             self.screen[(offset + 2) as usize] = true_pixel.b;
             self.screen[(offset + 3) as usize] = true_pixel.a;
+
+            // Let a zapper see the pixel
+            if bus.input_mut().senses_light() {
+                let rgb = [true_pixel.r, true_pixel.g, true_pixel.b];
+                bus.input_mut().pixel((self.cycle - 1) as u8, self.scanline as u8, rgb);
+            }
         }
     }
 
     // The big clock function, drives the ppu
     pub fn clock(&mut self) {
         {
-            let mut bus = self.bus.borrow_mut();
-            // Check for oam dma
-            if bus.oam_dma_ppu {
-                for i in 0x0..0x100 {
-                    let addr = bus.oam_dma_addr + (i as u16);
-                    bus.ppu_data.oam[i] = bus.read(addr, false);
-                }
-                bus.oam_dma_ppu = false;
-            }
-
+            let bus = self.bus.borrow();
             if self.scanline == 0
                 && self.cycle == 0
                 && !self.even
@@ -904,7 +1016,8 @@ impl<'a> Ppu<'a> {
                 }
             }
 
-            if self.cycle > 256 && self.cycle <= 320 && self.scanline != 261 {
+            // Pre-render line fetches too, but never finds any sprites
+            if self.cycle > 256 && self.cycle <= 320 {
                 match self.cycle % 8 {
                     // Low bits
                     5 => {
@@ -943,6 +1056,7 @@ impl<'a> Ppu<'a> {
             self.cycle += 1;
         } else {
             self.cycle = 0;
+            self.bus.borrow_mut().input_mut().end_scanline();
             if self.scanline < 262 {
                 self.scanline += 1;
             } else {
@@ -1,17 +1,24 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
+use crate::cartridge::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 pub struct Ram {
     pub cpu_memory: [u8; 0x800], // 2KB internal RAM
     pub ppu_memory: [u8; 0x2000], // 8KB, pattern tables on cart 
-    pub vertical_mirroring: bool, // Nametable mirroring
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ram {
-    pub fn new(vertical_mirroring: bool) -> Self {
+    pub fn new() -> Self {
         Self {
             cpu_memory: [0xFF; 0x800],
             ppu_memory: [0xFF; 0x2000],
-            vertical_mirroring,
         }
     }
 
@@ -25,21 +32,24 @@ impl Ram {
         self.cpu_memory[actual_addr as usize] = value;
     }
 
-    fn ppu_address_mapping(&self, addr: u16) -> usize {
+    // Mirroring is the cart's current mode, it can change between any two accesses
+    fn ppu_address_mapping(&self, addr: u16, mirroring: Mirroring) -> usize {
         let mut actual_addr = addr;
         // Nametable
-        if actual_addr >= 0x2000 && actual_addr < 0x3F00 {
-            if self.vertical_mirroring {
-                if actual_addr >= 0x2800 {
-                    actual_addr -= 0x800;
-                }
-            } else {
-                if (actual_addr >= 0x2400 && actual_addr < 0x2800)
-                    || (actual_addr >= 0x2C00 && actual_addr < 0x3000)
-                {
-                    actual_addr -= 0x400;
-                }
-            }
+        if (0x2000..0x3F00).contains(&actual_addr) {
+            // Which of the four logical nametables, $3000-$3EFF mirrors $2000-$2EFF
+            let table = ((actual_addr - 0x2000) / 0x400) % 4;
+            // Which of the two physical 1KB nametables it lands in
+            let physical = match mirroring {
+                Mirroring::Horizontal => table / 2,
+                Mirroring::Vertical => table % 2,
+                Mirroring::SingleScreenA => 0,
+                Mirroring::SingleScreenB => 1,
+                // Four screen nametables live on the cart (see Bus::ppu_read) and the cart
+                // resolves MapperControlled, so neither normally gets here
+                Mirroring::FourScreen | Mirroring::MapperControlled => table % 2,
+            };
+            actual_addr = 0x2000 + physical * 0x400 + (actual_addr & 0x03FF);
         } else if actual_addr >= 0x3F00 && actual_addr < 0x3F20 {
             // Pallet
             if actual_addr == 0x3F10
@@ -55,11 +65,23 @@ impl Ram {
         actual_addr as usize
     }
 
-    pub fn get_ppu_memory(&self, addr: u16) -> u8 {
-        self.ppu_memory[self.ppu_address_mapping(addr)]
+    pub fn get_ppu_memory(&self, addr: u16, mirroring: Mirroring) -> u8 {
+        self.ppu_memory[self.ppu_address_mapping(addr, mirroring)]
+    }
+
+    pub fn set_ppu_memory(&mut self, addr: u16, value: u8, mirroring: Mirroring) {
+        self.ppu_memory[self.ppu_address_mapping(addr, mirroring)] = value;
+    }
+}
+
+impl SaveState for Ram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.cpu_memory);
+        w.write_bytes(&self.ppu_memory);
     }
 
-    pub fn set_ppu_memory(&mut self, addr: u16, value: u8) {
-        self.ppu_memory[self.ppu_address_mapping(addr)] = value;
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into(&mut self.cpu_memory)?;
+        r.read_into(&mut self.ppu_memory)
     }
 }
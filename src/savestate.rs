@@ -0,0 +1,288 @@
+// Vim folding
+// vim:foldmethod=marker
+#![allow(dead_code)]
+use crate::cpu::Cpu;
+use crate::ppu::Ppu;
+use std::fmt;
+
+//: Overview Comment {{{
+/* Save state layout (all values little endian):
+ *
+ * Header
+ *   0  4 bytes  magic "NESS"
+ *   4  u16      format version
+ *   6  u32      crc32 of the rom's prg + chr rom, a state only loads into the same game
+ *   10 u32      length of the body in bytes
+ * Body
+ *   Cpu, Ppu, then the Bus (ppu registers, oam, apu, ram, input and cartridge ram/mapper),
+ *   each written by its SaveState implementation in a fixed order.
+ *   Cheats are left out, they belong to the player rather than the machine, so states
+ *   compare the same with cheats on or off and loading one keeps the active cheats.
+ *
+ * The header and length are checked before anything is touched. The body can still turn out
+ * bad partway through, so the machine is snapshot first and put back if it does, a bad state
+ * never leaves it half loaded.
+ * */
+//: }}}
+
+const MAGIC: &[u8; 4] = b"NESS";
+const VERSION: u16 = 12;
+const HEADER_SIZE: usize = 14;
+
+//: StateError {{{
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// Data does not start with the save state magic bytes
+    BadMagic,
+    /// State was made by a different format version
+    UnsupportedVersion(u16),
+    /// State was made with a different rom
+    RomMismatch { expected: u32, found: u32 },
+    /// State ends early
+    Truncated,
+    /// A value in the state is out of range
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => {
+                write!(f, "Save state version {v} is not supported (expected {VERSION})")
+            }
+            StateError::RomMismatch { expected, found } => write!(
+                f,
+                "Save state is for a different rom (crc {found:08X}, loaded rom is {expected:08X})"
+            ),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "Save state is corrupt: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+//: }}}
+
+//: StateWriter {{{
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn write_i8(&mut self, value: i8) {
+        self.data.push(value as u8);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+    // Length prefixed bytes, for buffers whose size depends on the cartridge
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+//: }}}
+
+//: StateReader {{{
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.pos + len > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn read_i8(&mut self) -> Result<i8, StateError> {
+        Ok(self.take(1)?[0] as i8)
+    }
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.take(1)?[0] != 0)
+    }
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn read_i16(&mut self) -> Result<i16, StateError> {
+        Ok(i16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    // Fill a fixed size buffer
+    pub fn read_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+    // Fill a cartridge sized buffer, the stored length has to match
+    pub fn read_vec_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        if self.read_u32()? as usize != out.len() {
+            return Err(StateError::Corrupt("buffer size does not match the cartridge"));
+        }
+        self.read_into(out)
+    }
+}
+//: }}}
+
+//: SaveState {{{
+/// Anything whose state goes into a save state
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+//: }}}
+
+//: save_state / load_state {{{
+/// Snapshot the whole machine
+pub fn save_state(cpu: &Cpu, ppu: &Ppu) -> Vec<u8> {
+    let mut body = StateWriter::new();
+    cpu.save_state(&mut body);
+    ppu.save_state(&mut body);
+    cpu.bus.borrow().save_state(&mut body);
+    let body = body.into_inner();
+
+    let mut w = StateWriter::new();
+    w.write_bytes(MAGIC);
+    w.write_u16(VERSION);
+    w.write_u32(cpu.bus.borrow().rom_crc());
+    w.write_u32(body.len() as u32);
+    w.write_bytes(&body);
+    w.into_inner()
+}
+
+/// Restore a snapshot made by save_state
+pub fn load_state(cpu: &mut Cpu, ppu: &mut Ppu, data: &[u8]) -> Result<(), StateError> {
+    let mut r = StateReader::new(data);
+
+    let mut magic = [0u8; 4];
+    r.read_into(&mut magic).map_err(|_| StateError::BadMagic)?;
+    if &magic != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+
+    let version = r.read_u16()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    let found = r.read_u32()?;
+    let expected = cpu.bus.borrow().rom_crc();
+    if found != expected {
+        return Err(StateError::RomMismatch { expected, found });
+    }
+
+    let body_len = r.read_u32()? as usize;
+    if data.len() != HEADER_SIZE + body_len {
+        return Err(StateError::Truncated);
+    }
+
+    let backup = save_state(cpu, ppu);
+    if let Err(err) = load_body(cpu, ppu, &mut r) {
+        // Our own snapshot always loads
+        load_body(cpu, ppu, &mut StateReader::new(&backup[HEADER_SIZE..]))
+            .expect("Failed to restore the machine");
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn load_body(cpu: &mut Cpu, ppu: &mut Ppu, r: &mut StateReader) -> Result<(), StateError> {
+    cpu.load_state(r)?;
+    ppu.load_state(r)?;
+    cpu.bus.borrow_mut().load_state(r)?;
+    if r.pos != r.data.len() {
+        return Err(StateError::Corrupt("body is longer than expected"));
+    }
+    Ok(())
+}
+//: }}}
+
+//: Tests {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+    use crate::Nes;
+
+    #[test]
+    fn bad_body_leaves_machine_alone() {
+        // INX, JMP $8000
+        let mut prg = vec![0xEA; 0x8000];
+        prg[..4].copy_from_slice(&[0xE8, 0x4C, 0x00, 0x80]);
+        prg[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        let mut nes = Nes::from_rom_bytes(&test_rom(0, &prg, &[0; 0x2000])).unwrap();
+        nes.run_frame();
+        let state = nes.save_state();
+        nes.run_frame();
+        let before = nes.save_state();
+        assert_ne!(state, before);
+
+        // Cut the end off the body and fix up the length, the cpu and ppu load but the bus
+        // runs out
+        let mut cut = state[..state.len() - 16].to_vec();
+        let body_len = (cut.len() - HEADER_SIZE) as u32;
+        cut[10..14].copy_from_slice(&body_len.to_le_bytes());
+        assert_eq!(nes.load_state(&cut), Err(StateError::Truncated));
+        assert_eq!(nes.save_state(), before);
+
+        // Too long
+        let mut long = state.clone();
+        long.push(0);
+        let body_len = (long.len() - HEADER_SIZE) as u32;
+        long[10..14].copy_from_slice(&body_len.to_le_bytes());
+        assert!(matches!(nes.load_state(&long), Err(StateError::Corrupt(_))));
+        assert_eq!(nes.save_state(), before);
+
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_state(), state);
+    }
+}
+//: }}}
@@ -22,11 +22,11 @@ static ASM_LOOKUP: [&str; 0x100] = [
     " RTS", " ADC", "*KIL", "*RRA", "*IGN", " ADC", " ROR", "*RRA", " PLA", " ADC", " ROR", "*ARR",
     " JMP", " ADC", " ROR", "*RRA", " BVS", " ADC", "*KIL", "*RRA", "*IGN", " ADC", " ROR", "*RRA",
     " SEI", " ADC", "*NOP", "*RRA", "*IGN", " ADC", " ROR", "*RRA", "*SKB", " STA", "*SKB", "*SAX",
-    " STY", " STA", " STX", "*SAX", " DEY", "*SKB", " TXA", " NUL", " STY", " STA", " STX", "*SAX",
-    " BCC", " STA", "*KIL", " NUL", " STY", " STA", " STX", "*SAX", " TYA", " STA", " TXS", " NUL",
-    " NUL", " STA", " NUL", " NUL", " LDY", " LDA", " LDX", "*LAX", " LDY", " LDA", " LDX", "*LAX",
-    " TAY", " LDA", " TAX", " NUL", " LDY", " LDA", " LDX", "*LAX", " BCS", " LDA", "*KIL", "*LAX",
-    " LDY", " LDA", " LDX", "*LAX", " CLV", " LDA", " TSX", " NUL", " LDY", " LDA", " LDX", "*LAX",
+    " STY", " STA", " STX", "*SAX", " DEY", "*SKB", " TXA", "*XAA", " STY", " STA", " STX", "*SAX",
+    " BCC", " STA", "*KIL", "*SHA", " STY", " STA", " STX", "*SAX", " TYA", " STA", " TXS", "*TAS",
+    "*SHY", " STA", "*SHX", "*SHA", " LDY", " LDA", " LDX", "*LAX", " LDY", " LDA", " LDX", "*LAX",
+    " TAY", " LDA", " TAX", "*LXA", " LDY", " LDA", " LDX", "*LAX", " BCS", " LDA", "*KIL", "*LAX",
+    " LDY", " LDA", " LDX", "*LAX", " CLV", " LDA", " TSX", "*LAS", " LDY", " LDA", " LDX", "*LAX",
     " CPY", " CMP", "*SKB", "*DCP", " CPY", " CMP", " DEC", "*DCP", " INY", " CMP", " DEX", "*AXS",
     " CPY", " CMP", " DEC", "*DCP", " BNE", " CMP", "*KIL", "*DCP", "*IGN", " CMP", " DEC", "*DCP",
     " CLD", " CMP", "*NOP", "*DCP", "*IGN", " CMP", " DEC", "*DCP", " CPX", " SBC", "*SKB", "*ISB",
@@ -37,7 +37,7 @@ static ASM_LOOKUP: [&str; 0x100] = [
 //: }}}
 
 //: get_asm {{{
-pub fn get_asm(cpu: &Cpu, mut bus: RefMut<Bus<'_>>) -> String {
+pub fn get_asm(cpu: &Cpu, mut bus: RefMut<Bus>) -> String {
     let mut asm_string: String;
     let opcode: u8 = bus.read(cpu.pc, true);
 
@@ -233,10 +233,118 @@ pub fn output_debug_info(cpu: &Cpu) {
 
 //: readbuf_vec {{{
 // Vec copy utility function
-pub fn readbuf_vec(to: &mut Vec<u8>, from: &mut Vec<u8>, start: &mut usize, size: usize) {
+pub fn readbuf_vec(to: &mut [u8], from: &[u8], start: &mut usize, size: usize) {
     for i in 0..size {
         to[i] = from[i + *start];
     }
     *start += size;
 }
 //: }}}
+
+//: crc32 {{{
+// CRC-32 (IEEE), used to identify roms
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Continue a crc32 over more data
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+//: }}}
+
+//: md5 {{{
+// MD5, FCEUX identifies roms in movie files with it
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    // Pad to a multiple of 64 bytes with the bit length at the end
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[(i / 16) * 4 + i % 4]));
+        }
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut digest = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+//: }}}
+
+//: base64 {{{
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// None if the text has characters outside the base64 alphabet
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE64_CHARS.iter().position(|b| *b == c)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+//: }}}